@@ -0,0 +1,368 @@
+//! Benchmarks of the CPU arithmetic and of every device backend.
+//!
+//! `cargo bench --bench device_bench` runs Criterion and then writes all
+//! results with the commit and device metadata to `BENCH_JSON`, by default
+//! `target/criterion/device_bench.json`. With `BENCH_BASELINE` pointing to a
+//! file written by an earlier run, the results are compared against it and
+//! the run fails if any benchmark is slower by more than
+//! `BENCH_REGRESSION_THRESHOLD`, by default 0.1.
+//!
+//! The sizes are `2^BENCH_MIN_K..=2^BENCH_MAX_K`, by default 10 to 16. With
+//! `BENCH_MSM_WINDOWS=1` the CPU MSM also runs with the windows next to the
+//! one `multiexp_window_size` picks, for tuning its table.
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{BenchmarkId, Criterion};
+use group::{
+    ff::{Field, PrimeField},
+    Curve, Group,
+};
+use halo2_proofs::arithmetic::*;
+use halo2_proofs::device_manager::*;
+use halo2curves::bn256::{Fr, G1Affine, G1};
+use rand_core::OsRng;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `small_multiexp` is quadratic in the bits, so it stops early.
+const SMALL_MULTIEXP_MAX_K: u32 = 12;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn k_range() -> std::ops::RangeInclusive<u32> {
+    env_or("BENCH_MIN_K", 10)..=env_or("BENCH_MAX_K", 16)
+}
+
+fn root_of_unity(k: u32) -> Fr {
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in k..Fr::S {
+        omega = omega.square();
+    }
+    omega
+}
+
+fn gen_scalars(k: u32) -> Vec<Fr> {
+    (0..1 << k).map(|_| Fr::random(OsRng)).collect()
+}
+
+fn gen_bases(k: u32) -> Vec<G1Affine> {
+    (0..1 << k).map(|_| G1::random(OsRng).to_affine()).collect()
+}
+
+/// The device backends to benchmark, by name.
+fn device_backends() -> Vec<(&'static str, Arc<dyn DeviceBackend>)> {
+    let mut backends: Vec<(&'static str, Arc<dyn DeviceBackend>)> =
+        vec![("mock", Arc::new(MockDeviceBackend::new(1)))];
+    #[cfg(any(feature = "msm_cuda", feature = "fft_cuda"))]
+    backends.push(("panda", Arc::new(PandaBackend::new())));
+    backends
+}
+
+fn bench_msm(c: &mut Criterion) {
+    let mut group = c.benchmark_group("msm");
+    group.sample_size(10);
+
+    for k in k_range() {
+        let scalars = gen_scalars(k);
+        let bases = gen_bases(k);
+
+        group.bench_with_input(BenchmarkId::new("best_multiexp_cpu", k), &k, |b, _| {
+            b.iter(|| best_multiexp_cpu(&scalars, &bases))
+        });
+        if env_or("BENCH_MSM_WINDOWS", 0) == 1 {
+            let window = multiexp_window_size(scalars.len());
+            for c in window.saturating_sub(2).max(1)..=window + 2 {
+                group.bench_with_input(
+                    BenchmarkId::new(format!("best_multiexp_cpu_window_{}", c), k),
+                    &k,
+                    |b, _| b.iter(|| best_multiexp_cpu_with_window(&scalars, &bases, c)),
+                );
+            }
+        }
+        if k <= SMALL_MULTIEXP_MAX_K {
+            group.bench_with_input(BenchmarkId::new("small_multiexp", k), &k, |b, _| {
+                b.iter(|| small_multiexp(&scalars, &bases))
+            });
+        }
+
+        for (name, backend) in device_backends() {
+            let mut device_manager = DeviceManager::with_backend(backend);
+            let handle = device_manager.get_handle_mut();
+            if handle
+                .init(
+                    DeviceInitUnitType::DeviceInitUnitTypeMSM,
+                    Some(0),
+                    Some(&[transmute_values(&bases)]),
+                    None,
+                )
+                .is_err()
+            {
+                continue;
+            }
+            group.bench_with_input(
+                BenchmarkId::new(format!("device_{}", name), k),
+                &k,
+                |b, _| b.iter(|| handle.execute_msm::<G1Affine>(0, 0, &scalars).unwrap()),
+            );
+            handle.deinit().unwrap();
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_ntt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ntt");
+    group.sample_size(10);
+
+    for k in k_range() {
+        let scalars = gen_scalars(k);
+        let omega = root_of_unity(k);
+        let twiddles: Vec<Fr> = (0..1 << (k - 1))
+            .scan(Fr::ONE, |w, _| {
+                let tw = *w;
+                *w *= omega;
+                Some(tw)
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("best_fft_cpu", k), &k, |b, _| {
+            b.iter_batched_ref(
+                || scalars.clone(),
+                |a| best_fft_cpu(a, omega, k),
+                criterion::BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("best_fft_cpu_radix4", k), &k, |b, _| {
+            b.iter_batched_ref(
+                || scalars.clone(),
+                |a| best_fft_cpu_radix4(a, omega, k),
+                criterion::BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(
+            BenchmarkId::new("recursive_butterfly_arithmetic", k),
+            &k,
+            |b, _| {
+                b.iter_batched_ref(
+                    || scalars.clone(),
+                    |a| recursive_butterfly_arithmetic(a, 1 << k, 1, &twiddles),
+                    criterion::BatchSize::LargeInput,
+                )
+            },
+        );
+
+        for (name, backend) in device_backends() {
+            let mut device_manager = DeviceManager::with_backend(backend);
+            let handle = device_manager.get_handle_mut();
+            if handle
+                .init(
+                    DeviceInitUnitType::DeviceInitUnitTypeNTT,
+                    None,
+                    None,
+                    Some(transmute_values(&[omega])),
+                )
+                .is_err()
+            {
+                continue;
+            }
+            group.bench_with_input(
+                BenchmarkId::new(format!("device_{}", name), k),
+                &k,
+                |b, _| {
+                    b.iter_batched_ref(
+                        || scalars.clone(),
+                        |a| handle.execute_ntt::<Fr, Fr>(a, k).unwrap(),
+                        criterion::BatchSize::LargeInput,
+                    )
+                },
+            );
+            handle.deinit().unwrap();
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_g_to_lagrange(c: &mut Criterion) {
+    let mut group = c.benchmark_group("g_to_lagrange");
+    group.sample_size(10);
+
+    for k in k_range() {
+        let g: Vec<G1> = (0..1 << k).map(|_| G1::random(OsRng)).collect();
+        group.bench_with_input(BenchmarkId::new("g_to_lagrange", k), &k, |b, _| {
+            b.iter_batched(
+                || g.clone(),
+                |g| g_to_lagrange::<G1Affine>(g, k),
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_msm, bench_ntt, bench_g_to_lagrange);
+
+fn criterion_dir() -> PathBuf {
+    let target = std::env::var("CARGO_TARGET_DIR").unwrap_or_else(|_| "target".to_string());
+    Path::new(&target).join("criterion")
+}
+
+/// The mean time in nanoseconds of every benchmark Criterion wrote since
+/// `since`, by full id such as `msm/best_multiexp_cpu/16`.
+fn collect_results(dir: &Path, since: SystemTime, results: &mut BTreeMap<String, f64>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        // Criterion keeps the latest run of each benchmark in `new`.
+        if path.file_name().map_or(false, |name| name == "new") {
+            // Skip the results of earlier runs with other sizes or filters.
+            let modified = std::fs::metadata(path.join("estimates.json"))
+                .and_then(|metadata| metadata.modified());
+            if modified.map_or(true, |modified| modified < since) {
+                continue;
+            }
+            let read = |name: &str| -> Option<Value> {
+                let text = std::fs::read_to_string(path.join(name)).ok()?;
+                serde_json::from_str(&text).ok()
+            };
+            if let (Some(benchmark), Some(estimates)) =
+                (read("benchmark.json"), read("estimates.json"))
+            {
+                if let (Some(id), Some(mean)) = (
+                    benchmark["full_id"].as_str(),
+                    estimates["mean"]["point_estimate"].as_f64(),
+                ) {
+                    results.insert(id.to_string(), mean);
+                }
+            }
+        } else {
+            collect_results(&path, since, results);
+        }
+    }
+}
+
+fn git_commit() -> Option<String> {
+    if let Ok(commit) = std::env::var("GIT_COMMIT") {
+        return Some(commit);
+    }
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn devices_metadata() -> Value {
+    device_backends()
+        .into_iter()
+        .map(|(name, backend)| {
+            json!({
+                "backend": name,
+                "device_num": backend.device_number().ok(),
+            })
+        })
+        .collect()
+}
+
+/// Compare against the baseline and return the ids of the regressions.
+fn compare(results: &BTreeMap<String, f64>, baseline: &Value, threshold: f64) -> Vec<String> {
+    let mut regressions = vec![];
+    println!(
+        "{:<50} {:>14} {:>14} {:>9}",
+        "benchmark", "baseline ns", "current ns", "change"
+    );
+    for (id, mean) in results.iter() {
+        let base = match baseline["results"][id.as_str()]["mean_ns"].as_f64() {
+            Some(base) => base,
+            None => {
+                println!("{:<50} {:>14} {:>14.0} {:>9}", id, "-", mean, "new");
+                continue;
+            }
+        };
+        let change = mean / base - 1f64;
+        let flag = if change > threshold {
+            regressions.push(id.clone());
+            " REGRESSION"
+        } else {
+            ""
+        };
+        println!(
+            "{:<50} {:>14.0} {:>14.0} {:>8.1}%{}",
+            id,
+            base,
+            mean,
+            change * 100f64,
+            flag
+        );
+    }
+    regressions
+}
+
+fn main() {
+    let start = SystemTime::now();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+
+    let mut results = BTreeMap::new();
+    collect_results(&criterion_dir(), start, &mut results);
+
+    let report = json!({
+        "commit": git_commit(),
+        "timestamp": start
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        "threads": std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        "devices": devices_metadata(),
+        "results": results
+            .iter()
+            .map(|(id, mean)| (id.clone(), json!({ "mean_ns": mean })))
+            .collect::<serde_json::Map<_, _>>(),
+    });
+
+    let json_path = std::env::var("BENCH_JSON")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| criterion_dir().join("device_bench.json"));
+    if let Some(parent) = json_path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    std::fs::write(&json_path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+    println!("Wrote {}", json_path.display());
+
+    if let Ok(baseline_path) = std::env::var("BENCH_BASELINE") {
+        let baseline: Value =
+            serde_json::from_str(&std::fs::read_to_string(&baseline_path).unwrap()).unwrap();
+        let threshold = env_or("BENCH_REGRESSION_THRESHOLD", 0.1f64);
+        let regressions = compare(&results, &baseline, threshold);
+        if !regressions.is_empty() {
+            eprintln!(
+                "{} benchmarks regressed by more than {:.0}% against {}",
+                regressions.len(),
+                threshold * 100f64,
+                baseline_path
+            );
+            std::process::exit(1);
+        }
+    }
+}
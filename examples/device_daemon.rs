@@ -0,0 +1,43 @@
+//! Serve the devices of this host to the local prover processes.
+//!
+//! `cargo run --example device_daemon --features msm_cuda,fft_cuda -- [socket]`
+//! serves the GPUs, and `--mock <devices>` serves simulated devices computing
+//! on the CPU. The provers use `DaemonClientBackend` on the same socket.
+
+#[cfg(unix)]
+fn main() -> std::io::Result<()> {
+    use halo2_proofs::device_manager::*;
+    use std::sync::Arc;
+
+    let mut args = std::env::args().skip(1);
+    let mut path = "/tmp/halo2-device.sock".to_string();
+    let mut mock_device_num = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mock" => {
+                mock_device_num = Some(
+                    args.next()
+                        .and_then(|value| value.parse().ok())
+                        .expect("--mock takes the number of devices"),
+                )
+            }
+            _ => path = arg,
+        }
+    }
+
+    let device_manager = match mock_device_num {
+        Some(device_num) => {
+            DeviceManager::with_backend(Arc::new(MockDeviceBackend::new(device_num)))
+        }
+        None => DeviceManager::new(),
+    };
+
+    let daemon = DeviceDaemon::bind(&path, device_manager)?;
+    println!("Serving devices on {}", path);
+    daemon.serve()
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The device daemon needs Unix domain sockets");
+}
@@ -7,112 +7,224 @@ use group::{
     ff::{BatchInvert, PrimeField},
     Curve, Group, GroupOpsOwned, ScalarMulOwned,
 };
+use halo2curves::Coordinates;
+use lazy_static::lazy_static;
+use std::any::{Any, TypeId};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 pub use halo2curves::{CurveAffine, CurveExt};
 
 /// This represents an element of a group with basic operations that can be
 /// performed. This allows an FFT implementation (for example) to operate
-/// generically over either a field or elliptic curve group.
+/// generically over either a field or elliptic curve group. The elements are
+/// compared by value, as `device::FftGroup` requires to verify device results.
 pub trait FftGroup<Scalar: Field>:
-    Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar>
+    Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar> + PartialEq
 {
 }
 
 impl<T, Scalar> FftGroup<Scalar> for T
 where
     Scalar: Field,
-    T: Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar>,
+    T: Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar> + PartialEq,
 {
 }
 
-fn multiexp_serial<C: CurveAffine>(coeffs: &[C::Scalar], bases: &[C], acc: &mut C::Curve) {
-    let coeffs: Vec<_> = coeffs.iter().map(|a| a.to_repr()).collect();
+/// The `segment`-th window of `c` bits of the little-endian `bytes`.
+fn window_at(bytes: &[u8], segment: usize, c: usize) -> u64 {
+    let skip_bits = segment * c;
+    let skip_bytes = skip_bits / 8;
+
+    if skip_bytes >= bytes.len() {
+        return 0;
+    }
+
+    let mut v = [0; 8];
+    for (v, o) in v.iter_mut().zip(bytes[skip_bytes..].iter()) {
+        *v = *o;
+    }
 
-    let c = if bases.len() < 4 {
-        1
-    } else if bases.len() < 32 {
-        3
+    let mut tmp = u64::from_le_bytes(v);
+    tmp >>= skip_bits - (skip_bytes * 8);
+    tmp % (1 << c)
+}
+
+/// The number of signed digits of `c` bits of a scalar of `bits` bits.
+pub fn signed_digit_num(bits: usize, c: usize) -> usize {
+    bits / c + 1
+}
+
+/// The signed digit of a `window` of `c` bits, given the carry of the window
+/// below, and the carry for the window above.
+///
+/// A window with its top bit set becomes the negative digit `window - 2^c`
+/// and carries one, as in Booth recoding, so the digits are in
+/// `[-2^(c-1), 2^(c-1)]` and `2^(c-1)` buckets are enough, a negative digit
+/// adding the negated base. The carry only depends on the window below, see
+/// `signed_digit_at`.
+pub fn signed_window_digit(window: u64, carry: &mut bool, c: usize) -> i64 {
+    let digit = window as i64 + *carry as i64;
+    *carry = window >> (c - 1) == 1;
+    if *carry {
+        digit - (1 << c)
     } else {
-        (f64::from(bases.len() as u32)).ln().ceil() as usize
-    };
+        digit
+    }
+}
 
-    fn get_at<F: PrimeField>(segment: usize, c: usize, bytes: &F::Repr) -> usize {
-        let skip_bits = segment * c;
-        let skip_bytes = skip_bits / 8;
+/// The `segment`-th digit of `recode_signed_digits`, without the digits
+/// below it.
+pub fn signed_digit_at(bytes: &[u8], segment: usize, c: usize) -> i64 {
+    let mut carry = segment > 0 && window_at(bytes, segment - 1, c) >> (c - 1) == 1;
+    signed_window_digit(window_at(bytes, segment, c), &mut carry, c)
+}
 
-        if skip_bytes >= 32 {
-            return 0;
-        }
+/// Signed-digit recoding of the little-endian `bytes` of a scalar in windows
+/// of `c` bits, lowest first, such that `sum(digits[i] * 2^(c * i))` is the
+/// scalar. There are `signed_digit_num(bytes.len() * 8, c)` digits, the last
+/// absorbing the final carry.
+pub fn recode_signed_digits(bytes: &[u8], c: usize) -> Vec<i64> {
+    let mut carry = false;
+    (0..signed_digit_num(bytes.len() * 8, c))
+        .map(|segment| signed_window_digit(window_at(bytes, segment, c), &mut carry, c))
+        .collect()
+}
 
-        let mut v = [0; 8];
-        for (v, o) in v.iter_mut().zip(bytes.as_ref()[skip_bytes..].iter()) {
-            *v = *o;
-        }
+/// The window of `best_multiexp_cpu` for `2^k` bases, by `k`, and the last
+/// one for larger MSMs. It minimises the estimated cost of the batched
+/// additions and the bucket sums, capped where the buckets outgrow the cache.
+/// `BENCH_MSM_WINDOWS=1 cargo bench --bench device_bench` measures the
+/// neighbouring windows to re-tune it.
+const MULTIEXP_WINDOW_TABLE: [usize; 21] = [
+    1, 2, 2, 3, 3, 4, 4, 5, 6, 7, 7, 8, 9, 10, 11, 12, 13, 13, 14, 15, 16,
+];
+
+/// The additions of a window share one inversion per this many points.
+const MULTIEXP_BATCH_SIZE: usize = 1 << 11;
+
+/// The window size of `best_multiexp_cpu` for `n` bases.
+pub fn multiexp_window_size(n: usize) -> usize {
+    let k = (usize::BITS - n.max(1).leading_zeros() - 1) as usize;
+    MULTIEXP_WINDOW_TABLE[k.min(MULTIEXP_WINDOW_TABLE.len() - 1)]
+}
 
-        let mut tmp = u64::from_le_bytes(v);
-        tmp >>= skip_bits - (skip_bytes * 8);
-        tmp = tmp % (1 << c);
+/// Add each point of the batch to its bucket, at most one point per bucket,
+/// in affine coordinates with one inversion for all of them.
+fn batch_add_affine<C: CurveAffine>(buckets: &mut [C], batch: &[(usize, C)]) {
+    let mut additions = Vec::with_capacity(batch.len());
+    let mut denominators = Vec::with_capacity(batch.len());
+    for (bucket, point) in batch.iter() {
+        let a: Option<Coordinates<C>> = buckets[*bucket].coordinates().into();
+        let b: Option<Coordinates<C>> = point.coordinates().into();
+        match (a, b) {
+            (None, _) => buckets[*bucket] = *point,
+            (_, None) => {}
+            // Doubling or cancelling, which the slope below does not cover.
+            (Some(a), Some(b)) if a.x() == b.x() => {
+                buckets[*bucket] = (buckets[*bucket] + *point).to_affine();
+            }
+            (Some(a), Some(b)) => {
+                denominators.push(*b.x() - a.x());
+                additions.push((*bucket, *a.x(), *a.y(), *b.x(), *b.y()));
+            }
+        }
+    }
 
-        tmp as usize
+    denominators.iter_mut().batch_invert();
+    for ((bucket, ax, ay, bx, by), inverse) in additions.into_iter().zip(denominators) {
+        let lambda = (by - ay) * inverse;
+        let x = lambda.square() - ax - bx;
+        let y = lambda * (ax - x) - ay;
+        buckets[bucket] = C::from_xy(x, y).unwrap();
     }
+}
 
-    let segments = (256 / c) + 1;
+/// The sum of the points times their signed digits of `c` bits.
+///
+/// The buckets are affine and filled in batches of `MULTIEXP_BATCH_SIZE`
+/// points, see `batch_add_affine`. A point whose bucket is already in the
+/// batch waits for the next batch, and if it is taken again goes to a
+/// projective overflow of the bucket, which keeps MSMs with many equal
+/// scalars from running a batch per point.
+fn bucket_sum<C: CurveAffine>(points: impl Iterator<Item = (i64, C)>, c: usize) -> C::Curve {
+    struct Window<C: CurveAffine> {
+        buckets: Vec<C>,
+        overflow: Vec<Option<C::Curve>>,
+        scheduled: Vec<bool>,
+        batch: Vec<(usize, C)>,
+        deferred: Vec<(usize, C)>,
+    }
 
-    for current_segment in (0..segments).rev() {
-        for _ in 0..c {
-            *acc = acc.double();
+    impl<C: CurveAffine> Window<C> {
+        fn schedule(&mut self, bucket: usize, point: C) -> bool {
+            if self.scheduled[bucket] {
+                return false;
+            }
+            self.scheduled[bucket] = true;
+            self.batch.push((bucket, point));
+            true
         }
 
-        #[derive(Clone, Copy)]
-        enum Bucket<C: CurveAffine> {
-            None,
-            Affine(C),
-            Projective(C::Curve),
-        }
-
-        impl<C: CurveAffine> Bucket<C> {
-            fn add_assign(&mut self, other: &C) {
-                *self = match *self {
-                    Bucket::None => Bucket::Affine(*other),
-                    Bucket::Affine(a) => Bucket::Projective(a + *other),
-                    Bucket::Projective(mut a) => {
-                        a += *other;
-                        Bucket::Projective(a)
-                    }
-                }
+        /// Add the batch, then move the deferred points to the next batch or
+        /// to the overflow.
+        fn flush(&mut self) {
+            batch_add_affine(&mut self.buckets, &self.batch);
+            for (bucket, _) in self.batch.drain(..) {
+                self.scheduled[bucket] = false;
             }
-
-            fn add(self, mut other: C::Curve) -> C::Curve {
-                match self {
-                    Bucket::None => other,
-                    Bucket::Affine(a) => {
-                        other += a;
-                        other
+            for (bucket, point) in std::mem::take(&mut self.deferred) {
+                if !self.schedule(bucket, point) {
+                    match &mut self.overflow[bucket] {
+                        Some(sum) => *sum += point,
+                        overflow => *overflow = Some(point.to_curve()),
                     }
-                    Bucket::Projective(a) => other + &a,
                 }
             }
         }
+    }
 
-        let mut buckets: Vec<Bucket<C>> = vec![Bucket::None; (1 << c) - 1];
+    let bucket_num = 1 << (c - 1);
+    let mut window = Window {
+        buckets: vec![C::identity(); bucket_num],
+        overflow: vec![None; bucket_num],
+        scheduled: vec![false; bucket_num],
+        batch: Vec::with_capacity(MULTIEXP_BATCH_SIZE),
+        deferred: vec![],
+    };
 
-        for (coeff, base) in coeffs.iter().zip(bases.iter()) {
-            let coeff = get_at::<C::Scalar>(current_segment, c, coeff);
-            if coeff != 0 {
-                buckets[coeff - 1].add_assign(base);
-            }
+    for (digit, base) in points {
+        if digit == 0 {
+            continue;
+        }
+        let bucket = digit.unsigned_abs() as usize - 1;
+        let point = if digit < 0 { -base } else { base };
+        if !window.schedule(bucket, point) {
+            window.deferred.push((bucket, point));
         }
+        // A flush may leave more deferred points in the batch than its size.
+        if window.batch.len() >= MULTIEXP_BATCH_SIZE {
+            window.flush();
+        }
+    }
+    while !window.batch.is_empty() {
+        window.flush();
+    }
 
-        // Summation by parts
-        // e.g. 3a + 2b + 1c = a +
-        //                    (a) + b +
-        //                    ((a) + b) + c
-        let mut running_sum = C::Curve::identity();
-        for exp in buckets.into_iter().rev() {
-            running_sum = exp.add(running_sum);
-            *acc = *acc + &running_sum;
+    // Summation by parts
+    // e.g. 3a + 2b + 1c = a +
+    //                    (a) + b +
+    //                    ((a) + b) + c
+    let mut running_sum = C::Curve::identity();
+    let mut window_sum = C::Curve::identity();
+    for (bucket, overflow) in window.buckets.iter().zip(window.overflow.iter()).rev() {
+        running_sum += *bucket;
+        if let Some(overflow) = overflow {
+            running_sum += overflow;
         }
+        window_sum += &running_sum;
     }
+    window_sum
 }
 
 /// Performs a small multi-exponentiation operation.
@@ -144,33 +256,597 @@ pub fn small_multiexp<C: CurveAffine>(coeffs: &[C::Scalar], bases: &[C]) -> C::C
 /// This function will panic if coeffs and bases have a different length.
 ///
 /// This will use multithreading if beneficial.
+///
+/// Zero scalars are dropped, the bases of the scalars equal to one are
+/// summed, and the scalars below `2^64` run with the windows of 64 bits only,
+/// see `multiexp_scalar_kinds`. This only pays off for the copies of the bases
+/// when at least `MULTIEXP_SPLIT_MIN_FRACTION` of the scalars are such.
 pub fn best_multiexp_cpu<C: CurveAffine>(coeffs: &[C::Scalar], bases: &[C]) -> C::Curve {
+    best_multiexp_cpu_with_endo_bases(coeffs, bases, None)
+}
+
+/// `best_multiexp_cpu` with the endomorphisms of the bases from
+/// `glv_endo_bases`, which the curves with GLV need to run it, see
+/// `glv_lambda`. They take the memory of the bases again, so they are only
+/// worth it cached for bases used again.
+pub fn best_multiexp_cpu_with_endo_bases<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    bases: &[C],
+    endo_bases: Option<&[C]>,
+) -> C::Curve {
     assert_eq!(coeffs.len(), bases.len());
+    if let Some(endo_bases) = endo_bases {
+        assert_eq!(endo_bases.len(), bases.len());
+    }
 
-    let num_threads = multicore::current_num_threads();
-    if coeffs.len() > num_threads {
-        let chunk = coeffs.len() / num_threads;
-        let num_chunks = coeffs.chunks(chunk).len();
-        let mut results = vec![C::Curve::identity(); num_chunks];
-        multicore::scope(|scope| {
-            let chunk = coeffs.len() / num_threads;
+    let kinds = multiexp_scalar_kinds(coeffs);
+    let special = kinds
+        .iter()
+        .filter(|kind| !matches!(kind, MultiexpScalarKind::Large))
+        .count();
+    if special == 0 || (special as f64) < MULTIEXP_SPLIT_MIN_FRACTION * coeffs.len() as f64 {
+        return best_multiexp_cpu_dense(coeffs, bases, endo_bases);
+    }
 
-            for ((coeffs, bases), acc) in coeffs
-                .chunks(chunk)
-                .zip(bases.chunks(chunk))
-                .zip(results.iter_mut())
-            {
-                scope.spawn(move |_| {
-                    multiexp_serial(coeffs, bases, acc);
-                });
+    let mut ones = vec![];
+    let (mut small_coeffs, mut small_bases) = (vec![], vec![]);
+    let (mut large_coeffs, mut large_bases, mut large_endo_bases) = (vec![], vec![], vec![]);
+    for (i, ((kind, coeff), base)) in kinds
+        .iter()
+        .zip(coeffs.iter())
+        .zip(bases.iter())
+        .enumerate()
+    {
+        match kind {
+            MultiexpScalarKind::Zero => {}
+            MultiexpScalarKind::One => ones.push(*base),
+            MultiexpScalarKind::Small(value) => {
+                small_coeffs.push(*value);
+                small_bases.push(*base);
             }
+            MultiexpScalarKind::Large => {
+                large_coeffs.push(*coeff);
+                large_bases.push(*base);
+                if let Some(endo_bases) = endo_bases {
+                    large_endo_bases.push(endo_bases[i]);
+                }
+            }
+        }
+    }
+
+    let mut acc = sum_bases(&ones);
+    if !small_coeffs.is_empty() {
+        let c = multiexp_window_size(small_coeffs.len());
+        acc += multiexp_rounds(
+            small_coeffs.len(),
+            signed_digit_num(MULTIEXP_SMALL_BITS, c),
+            c,
+            |segment, t| {
+                let digit = signed_digit_at(&small_coeffs[t].to_le_bytes(), segment, c);
+                Some((digit, small_bases[t]))
+            },
+        );
+    }
+    if !large_coeffs.is_empty() {
+        let large_endo_bases = endo_bases.map(|_| &large_endo_bases[..]);
+        acc += best_multiexp_cpu_dense(&large_coeffs, &large_bases, large_endo_bases);
+    }
+    acc
+}
+
+/// The bits of the small scalars of `MultiexpScalarKind`.
+pub const MULTIEXP_SMALL_BITS: usize = 64;
+
+/// The fraction of zero, one or small scalars from which `best_multiexp_cpu`
+/// splits the scalars by kind. Below it the few of them run with the large
+/// ones.
+pub const MULTIEXP_SPLIT_MIN_FRACTION: f64 = 0.125;
+
+/// The paths of `best_multiexp_cpu` by scalar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MultiexpScalarKind {
+    ///
+    Zero,
+    ///
+    One,
+    /// Below `2^MULTIEXP_SMALL_BITS`, other than zero and one.
+    Small(u64),
+    ///
+    Large,
+}
+
+impl MultiexpScalarKind {
+    /// The kind of the scalar.
+    pub fn of<F: PrimeField>(coeff: &F) -> Self {
+        let repr = coeff.to_repr();
+        let (low, high) = repr.as_ref().split_at(MULTIEXP_SMALL_BITS / 8);
+        if high.iter().any(|byte| *byte != 0) {
+            return MultiexpScalarKind::Large;
+        }
+        match u64::from_le_bytes(low.try_into().unwrap()) {
+            0 => MultiexpScalarKind::Zero,
+            1 => MultiexpScalarKind::One,
+            value => MultiexpScalarKind::Small(value),
+        }
+    }
+}
+
+/// `MultiexpScalarKind::of` each scalar.
+pub fn multiexp_scalar_kinds<F: PrimeField>(coeffs: &[F]) -> Vec<MultiexpScalarKind> {
+    let mut kinds = vec![MultiexpScalarKind::Large; coeffs.len()];
+    parallelize(&mut kinds, |kinds, start| {
+        for (kind, coeff) in kinds.iter_mut().zip(coeffs[start..].iter()) {
+            *kind = MultiexpScalarKind::of(coeff);
+        }
+    });
+    kinds
+}
+
+/// The sum of the bases.
+fn sum_bases<C: CurveAffine>(bases: &[C]) -> C::Curve {
+    let num_threads = multicore::current_num_threads();
+    let chunk = ((bases.len() + num_threads - 1) / num_threads).max(1);
+    let mut sums = vec![C::Curve::identity(); bases.chunks(chunk).len()];
+    multicore::scope(|scope| {
+        for (bases, sum) in bases.chunks(chunk).zip(sums.iter_mut()) {
+            scope.spawn(move |_| {
+                for base in bases {
+                    *sum += *base;
+                }
+            });
+        }
+    });
+    sums.iter().fold(C::Curve::identity(), |a, b| a + b)
+}
+
+/// `best_multiexp_cpu` treating every scalar as large.
+fn best_multiexp_cpu_dense<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    bases: &[C],
+    endo_bases: Option<&[C]>,
+) -> C::Curve {
+    match (glv_constants::<C>(), endo_bases) {
+        // GLV doubles the points of the windows.
+        (Some(glv), Some(endo_bases)) => {
+            let c = multiexp_window_size(coeffs.len() * 2);
+            multiexp_glv(coeffs, bases, endo_bases, glv, c)
+        }
+        _ => best_multiexp_cpu_with_window(coeffs, bases, multiexp_window_size(coeffs.len())),
+    }
+}
+
+/// `best_multiexp_cpu` with signed digits of `c` bits, without GLV.
+pub fn best_multiexp_cpu_with_window<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    bases: &[C],
+    c: usize,
+) -> C::Curve {
+    assert_eq!(coeffs.len(), bases.len());
+    multiexp_shifted(coeffs, bases, 1, c)
+}
+
+/// How much of the bases `fixed_base_precompute` keeps, a trade of memory
+/// for speed.
+///
+/// The table holds `tables` copies of the bases, the copy `j` shifted by
+/// `2^(window * rounds * j)`, where `rounds` is the number of signed digits of
+/// `window` bits per copy. An MSM then runs `rounds` windows over
+/// `tables * n` points with `window * rounds` doublings, instead of every
+/// window over the `n` bases. With one copy per digit it has no doublings,
+/// with one copy it is `best_multiexp_cpu_with_window`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedBaseConfig {
+    ///
+    pub window: usize,
+    /// The copies of the bases, at most `signed_digit_num(256, window)`.
+    pub tables: usize,
+}
+
+impl FixedBaseConfig {
+    /// The signed digits each copy of the bases covers.
+    pub fn rounds(&self) -> usize {
+        let digits = signed_digit_num(256, self.window);
+        (digits + self.tables - 1) / self.tables
+    }
+
+    /// The points of the table of `n` bases.
+    pub fn table_len(&self, n: usize) -> usize {
+        n * self.tables
+    }
+}
+
+/// The table of `config.tables` shifted copies of the bases, one after the
+/// other, see `FixedBaseConfig`.
+pub fn fixed_base_precompute<C: CurveAffine>(bases: &[C], config: &FixedBaseConfig) -> Vec<C> {
+    assert!(config.window > 0);
+    assert!(config.tables > 0 && config.tables <= signed_digit_num(256, config.window));
+
+    let n = bases.len();
+    let shift = config.window * config.rounds();
+    let mut table = vec![C::identity(); config.table_len(n)];
+    table[..n].copy_from_slice(bases);
+    for j in 1..config.tables {
+        let (previous, current) = table.split_at_mut(j * n);
+        let previous = &previous[(j - 1) * n..];
+        parallelize(&mut current[..n], |current, start| {
+            let shifted: Vec<C::Curve> = previous[start..start + current.len()]
+                .iter()
+                .map(|base| {
+                    let mut base = base.to_curve();
+                    for _ in 0..shift {
+                        base = base.double();
+                    }
+                    base
+                })
+                .collect();
+            C::Curve::batch_normalize(&shifted, current);
         });
-        results.iter().fold(C::Curve::identity(), |a, b| a + b)
-    } else {
-        let mut acc = C::Curve::identity();
-        multiexp_serial(coeffs, bases, &mut acc);
-        acc
     }
+    table
+}
+
+/// The MSM of the scalars with the first of the bases of the table of
+/// `fixed_base_precompute`, which may hold more bases than there are scalars.
+pub fn fixed_base_multiexp<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    table: &[C],
+    config: &FixedBaseConfig,
+) -> C::Curve {
+    assert_eq!(table.len() % config.tables, 0);
+    assert!(coeffs.len() <= table.len() / config.tables);
+    multiexp_shifted(coeffs, table, config.tables, config.window)
+}
+
+/// The MSM of the scalars with `tables` copies of the bases, each shifted by
+/// the digits of the copies before it.
+fn multiexp_shifted<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    shifted: &[C],
+    tables: usize,
+    c: usize,
+) -> C::Curve {
+    assert!(c > 0);
+
+    let mut reprs = vec![<C::Scalar as PrimeField>::Repr::default(); coeffs.len()];
+    parallelize(&mut reprs, |reprs, start| {
+        for (repr, coeff) in reprs.iter_mut().zip(coeffs[start..].iter()) {
+            *repr = coeff.to_repr();
+        }
+    });
+
+    let (n, stride) = (coeffs.len(), shifted.len() / tables);
+    let segments = signed_digit_num(256, c);
+    let rounds = (segments + tables - 1) / tables;
+    multiexp_rounds(n * tables, rounds, c, |round, t| {
+        let (j, i) = (t / n, t % n);
+        let segment = j * rounds + round;
+        (segment < segments).then(|| {
+            let digit = signed_digit_at(reprs[i].as_ref(), segment, c);
+            (digit, shifted[j * stride + i])
+        })
+    })
+}
+
+/// The sum over the `rounds` windows of `c` bits, from the top, of the
+/// `points` points with their digits of the window, which `point(round, t)`
+/// gives for the point `t`.
+///
+/// The threads split the work by windows, and by ranges of the points within
+/// a window when there are more threads than windows, so the doublings
+/// between the windows run once.
+fn multiexp_rounds<C: CurveAffine>(
+    points: usize,
+    rounds: usize,
+    c: usize,
+    point: impl Fn(usize, usize) -> Option<(i64, C)> + Sync,
+) -> C::Curve {
+    let parts = (multicore::current_num_threads() + rounds - 1) / rounds;
+    let part_len = ((points + parts - 1) / parts).max(1);
+
+    let mut window_sums = vec![C::Curve::identity(); rounds * parts];
+    multicore::scope(|scope| {
+        let point = &point;
+        for (i, window_sum) in window_sums.iter_mut().enumerate() {
+            let (round, part) = (i / parts, i % parts);
+            let start = (part * part_len).min(points);
+            let end = (start + part_len).min(points);
+            scope.spawn(move |_| {
+                *window_sum = bucket_sum((start..end).filter_map(|t| point(round, t)), c);
+            });
+        }
+    });
+
+    let mut acc = C::Curve::identity();
+    for window_sums in window_sums.chunks(parts).rev() {
+        for _ in 0..c {
+            acc = acc.double();
+        }
+        for window_sum in window_sums {
+            acc += window_sum;
+        }
+    }
+    acc
+}
+
+/// The GLV endomorphism `(x, y) -> (beta * x, y)` of a curve, which
+/// multiplies the points by `lambda`, and the lattice to split its scalars
+/// with, see `glv_decompose`.
+struct GlvConstants {
+    /// `CurveExt::CURVE_ID` of the curve.
+    curve_id: &'static str,
+    /// A cube root of unity of the base field, in little-endian limbs.
+    beta: [u64; 4],
+    /// The cube root of unity of the scalar field that goes with `beta`.
+    lambda: [u64; 4],
+    /// The short basis `(a1, b1), (a2, b2)` of the lattice of the `(a, b)`
+    /// with `a + b * lambda = 0`, as magnitudes with `true` for negative.
+    basis: [(u128, bool); 4],
+    /// `round(2^256 * b2 / r)` and `round(-2^256 * b1 / r)` for the scalar
+    /// field modulus `r`.
+    g: [[u64; 4]; 2],
+}
+
+/// The curves `best_multiexp_cpu_with_endo_bases` runs with GLV.
+static GLV_CONSTANTS: [GlvConstants; 3] = [
+    GlvConstants {
+        curve_id: "bn256_g1",
+        beta: [
+            0xe4bd44e5607cfd48,
+            0xc28f069fbb966e3d,
+            0x5e6dd9e7e0acccb0,
+            0x30644e72e131a029,
+        ],
+        lambda: [
+            0xb8ca0b2d36636f23,
+            0xcc37a73fec2bc5e9,
+            0x048b6e193fd84104,
+            0x30644e72e131a029,
+        ],
+        basis: [
+            (0x6f4d8248eeb859fc8211bbeb7d4f1128, false),
+            (0x89d3256894d213e3, true),
+            (0x89d3256894d213e3, false),
+            (0x6f4d8248eeb859fd0be4e1541221250b, false),
+        ],
+        g: [
+            [
+                0x5398fd0300ff6565,
+                0x4ccef014a773d2d2,
+                0x0000000000000002,
+                0x0000000000000000,
+            ],
+            [
+                0xd91d232ec7e0b3d7,
+                0x0000000000000002,
+                0x0000000000000000,
+                0x0000000000000000,
+            ],
+        ],
+    },
+    GlvConstants {
+        curve_id: "pallas",
+        beta: [
+            0x7b7fd22f0201b547,
+            0x05270d29d19fc7d2,
+            0xd3552a23a8554e50,
+            0x2d33357cb532458e,
+        ],
+        lambda: [
+            0x619d1840af55f1b1,
+            0x1259527ec1d4752e,
+            0xaee24b27e308f0a6,
+            0x397e65a7d7c1ad71,
+        ],
+        basis: [
+            (0x49e69d1640a899538cb1279300000000, false),
+            (0x49e69d1640f049157fcae1c700000001, true),
+            (0x93cd3a2c8198e2690c7c095a00000001, false),
+            (0x49e69d1640a899538cb1279300000000, false),
+        ],
+        g: [
+            [
+                0x32c49e4bffffffff,
+                0x279a745902a2654e,
+                0x0000000000000001,
+                0x0000000000000000,
+            ],
+            [
+                0xff2b871c00000003,
+                0x279a745903c12455,
+                0x0000000000000001,
+                0x0000000000000000,
+            ],
+        ],
+    },
+    GlvConstants {
+        curve_id: "vesta",
+        beta: [
+            0x2aa9d2e050aa0e4f,
+            0x0fed467d47c033af,
+            0x511db4d81cf70f5a,
+            0x06819a58283e528e,
+        ],
+        lambda: [
+            0x1dad5ebdfdfe4ab9,
+            0x1d1f8bd237ad3149,
+            0x2caad5dc57aab1b0,
+            0x12ccca834acdba71,
+        ],
+        basis: [
+            (0x49e69d1640f049157fcae1c700000000, false),
+            (0x49e69d1640a899538cb1279300000001, true),
+            (0x49e69d1640a899538cb1279300000001, false),
+            (0x93cd3a2c8198e2690c7c095a00000001, false),
+        ],
+        g: [
+            [
+                0x31f0256800000003,
+                0x4f34e8b2066389a4,
+                0x0000000000000002,
+                0x0000000000000000,
+            ],
+            [
+                0x32c49e4c00000003,
+                0x279a745902a2654e,
+                0x0000000000000001,
+                0x0000000000000000,
+            ],
+        ],
+    },
+];
+
+/// The bytes `glv_encode` takes per scalar.
+pub const GLV_SCALAR_BYTES: usize = 34;
+
+fn glv_constants<C: CurveAffine>() -> Option<&'static GlvConstants> {
+    let curve_id = <C::CurveExt as CurveExt>::CURVE_ID;
+    GLV_CONSTANTS.iter().find(|glv| glv.curve_id == curve_id)
+}
+
+/// The field element of the little-endian limbs.
+fn field_from_limbs<F: PrimeField>(limbs: &[u64; 4]) -> F {
+    let mut repr = F::Repr::default();
+    for (bytes, limb) in repr.as_mut().chunks_mut(8).zip(limbs.iter()) {
+        bytes.copy_from_slice(&limb.to_le_bytes());
+    }
+    F::from_repr(repr).unwrap()
+}
+
+/// The multiplier of the endomorphism of the curve on its points, if
+/// `best_multiexp_cpu` runs it with GLV.
+pub fn glv_lambda<C: CurveAffine>() -> Option<C::Scalar> {
+    glv_constants::<C>().map(|glv| field_from_limbs(&glv.lambda))
+}
+
+/// The endomorphism of the curve, `lambda` times the point.
+fn glv_endo<C: CurveAffine>(point: &C, beta: &C::Base) -> C {
+    let coordinates: Option<Coordinates<C>> = point.coordinates().into();
+    match coordinates {
+        Some(coordinates) => C::from_xy(*coordinates.x() * beta, *coordinates.y()).unwrap(),
+        None => *point,
+    }
+}
+
+/// `glv_endo` of each base.
+fn endo_bases_of<C: CurveAffine>(bases: &[C], glv: &GlvConstants) -> Vec<C> {
+    let beta: C::Base = field_from_limbs(&glv.beta);
+    let mut endo_bases = vec![C::identity(); bases.len()];
+    parallelize(&mut endo_bases, |endo_bases, start| {
+        for (endo_base, base) in endo_bases.iter_mut().zip(bases[start..].iter()) {
+            *endo_base = glv_endo(base, &beta);
+        }
+    });
+    endo_bases
+}
+
+/// The endomorphism of each base for `best_multiexp_cpu_with_endo_bases`,
+/// `None` if `best_multiexp_cpu` runs the curve without GLV.
+pub fn glv_endo_bases<C: CurveAffine>(bases: &[C]) -> Option<Vec<C>> {
+    glv_constants::<C>().map(|glv| endo_bases_of(bases, glv))
+}
+
+/// `(a * b) >> 256`, which is below `2^128` for the scalars and `g`.
+fn mul_shr_256(a: &[u64; 4], b: &[u64; 4]) -> u128 {
+    let mut product = [0u64; 8];
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let t = product[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+            product[i + j] = t as u64;
+            carry = t >> 64;
+        }
+        product[i + 4] = carry as u64;
+    }
+    debug_assert!(product[6] == 0 && product[7] == 0);
+    product[4] as u128 | (product[5] as u128) << 64
+}
+
+/// The scalar `k = k1 + k2 * lambda` as the magnitudes and signs, `true` for
+/// negative, of `k1` and `k2`, which are below `2^128`.
+fn glv_decompose<F: PrimeField>(k: &F, glv: &GlvConstants) -> [(u128, bool); 2] {
+    let repr = k.to_repr();
+    let mut limbs = [0u64; 4];
+    for (limb, bytes) in limbs.iter_mut().zip(repr.as_ref().chunks(8)) {
+        *limb = u64::from_le_bytes(bytes.try_into().unwrap());
+    }
+    let c1 = F::from_u128(mul_shr_256(&limbs, &glv.g[0]));
+    let c2 = F::from_u128(mul_shr_256(&limbs, &glv.g[1]));
+    let [a1, b1, a2, b2] = glv.basis.map(|(magnitude, negative)| {
+        let value = F::from_u128(magnitude);
+        if negative {
+            -value
+        } else {
+            value
+        }
+    });
+
+    // k - c1 * (a1 + b1 * lambda) - c2 * (a2 + b2 * lambda), both of which
+    // are 0, so only the size of the halves depends on the rounding.
+    let k1 = *k - c1 * a1 - c2 * a2;
+    let k2 = -(c1 * b1) - c2 * b2;
+    [k1, k2].map(|half| {
+        [(half, false), (-half, true)]
+            .into_iter()
+            .find_map(|(value, negative)| {
+                let repr = value.to_repr();
+                let (low, high) = repr.as_ref().split_at(16);
+                high.iter()
+                    .all(|byte| *byte == 0)
+                    .then(|| (u128::from_le_bytes(low.try_into().unwrap()), negative))
+            })
+            .expect("the GLV halves are below 2^128")
+    })
+}
+
+/// `glv_decompose` of each scalar.
+fn glv_halves<F: PrimeField>(coeffs: &[F], glv: &GlvConstants) -> Vec<[(u128, bool); 2]> {
+    let mut halves = vec![[(0u128, false); 2]; coeffs.len()];
+    parallelize(&mut halves, |halves, start| {
+        for (half, coeff) in halves.iter_mut().zip(coeffs[start..].iter()) {
+            *half = glv_decompose(coeff, glv);
+        }
+    });
+    halves
+}
+
+/// The scalars decomposed by GLV, `GLV_SCALAR_BYTES` each: the little-endian
+/// magnitudes of `k1` and `k2` in 16 bytes each, then their signs in a byte
+/// each, 1 for negative. `None` if the curve has no GLV constants.
+pub fn glv_encode<C: CurveAffine>(coeffs: &[C::Scalar]) -> Option<Vec<u8>> {
+    let glv = glv_constants::<C>()?;
+    let bytes = glv_halves(coeffs, glv)
+        .into_iter()
+        .flat_map(|[(k1, s1), (k2, s2)]| {
+            k1.to_le_bytes()
+                .into_iter()
+                .chain(k2.to_le_bytes())
+                .chain([s1 as u8, s2 as u8])
+        })
+        .collect();
+    Some(bytes)
+}
+
+/// The MSM with each scalar split by GLV into two halves of 128 bits, the
+/// second with `endo_bases`, the endomorphisms of the bases, so half of the
+/// windows of `multiexp_shifted` over twice the points.
+fn multiexp_glv<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    bases: &[C],
+    endo_bases: &[C],
+    glv: &GlvConstants,
+    c: usize,
+) -> C::Curve {
+    assert!(c > 0);
+
+    let halves = glv_halves(coeffs, glv);
+    let segments = signed_digit_num(128, c);
+    multiexp_rounds(coeffs.len() * 2, segments, c, |segment, t| {
+        let (i, half) = (t / 2, t % 2);
+        let (magnitude, negative) = halves[i][half];
+        let digit = signed_digit_at(&magnitude.to_le_bytes(), segment, c);
+        let base = if half == 0 { bases[i] } else { endo_bases[i] };
+        Some((if negative { -digit } else { digit }, base))
+    })
 }
 
 /// gpu/cpu msm
@@ -197,107 +873,792 @@ pub fn best_init_gpu<C: CurveAffine>(param_id: usize, bases: &[&[C]]) {
     let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
     let device_manager_handle = binding.get_handle_mut();
 
-    let mut bases_bytes_vec: Vec<&[u8]> = Vec::new();
-    for base in bases.iter() {
-        let bases_bytes = device_manager_handle.transmute_values(base);
-        bases_bytes_vec.push(bases_bytes);
-    }
+    let mut bases_bytes_vec: Vec<&[u8]> = Vec::new();
+    for base in bases.iter() {
+        let bases_bytes = device_manager_handle.transmute_values(base);
+        bases_bytes_vec.push(bases_bytes);
+    }
+
+    let bases_bytes_slice: &[&[u8]] = &bases_bytes_vec;
+
+    device_manager_handle
+        .init(
+            common::DeviceInitUnitType::DeviceInitUnitTypeMSM,
+            Some(param_id),
+            Some(&bases_bytes_slice),
+            None,
+        )
+        .unwrap();
+
+    // Optional, see `DeviceManagerContext::set_fixed_base_config`.
+    device_manager_handle
+        .init_fixed_base(param_id, bases)
+        .unwrap();
+    device_manager_handle.init_glv_bases(param_id, bases);
+}
+
+/// The CPU MSM of a registered param, with the fixed-base table of its bases
+/// if it has one, else with the endomorphisms of its bases if they are cached.
+#[cfg(any(feature = "msm_cuda"))]
+fn best_multiexp_cpu_fixed_base<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    bases: &[C],
+    fixed_base: Option<(FixedBaseConfig, std::sync::Arc<Vec<C>>)>,
+    endo_bases: Option<std::sync::Arc<Vec<C>>>,
+) -> C::Curve {
+    match (fixed_base, endo_bases) {
+        (Some((config, table)), _) if coeffs.len() <= table.len() / config.tables => {
+            fixed_base_multiexp(coeffs, &table, &config)
+        }
+        (_, Some(endo_bases)) if coeffs.len() <= endo_bases.len() => {
+            best_multiexp_cpu_with_endo_bases(coeffs, bases, Some(&endo_bases[..coeffs.len()]))
+        }
+        _ => best_multiexp_cpu(coeffs, bases),
+    }
+}
+
+/// Performs a multi-exponentiation operation GPU.
+///
+/// This function will panic if coeffs and bases have a different length.
+///
+/// This will use multithreading if beneficial.
+#[cfg(any(feature = "msm_cuda"))]
+pub fn best_multiexp_gpu<C: CurveAffine>(
+    coeffs: &[C::Scalar],
+    bases: &[C],
+    param_id: usize,
+    bases_index: usize,
+) -> C::Curve {
+    let wait_start = std::time::Instant::now();
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+    let fixed_base = device_manager_handle
+        .fixed_base_table(param_id, bases_index)
+        .and_then(|table| table.points::<C>().map(|points| (table.config, points)));
+    let endo_bases = device_manager_handle
+        .glv_bases(param_id, bases_index)
+        .and_then(|glv_bases| glv_bases.points::<C>());
+
+    // Below the calibrated crossover size the CPU is faster, and the job does
+    // not wait in the queue of the devices.
+    if device_manager_handle.prefer_cpu(DeviceUnitType::DeviceUnitTypeMSM, coeffs.len()) {
+        device_manager_handle
+            .metrics
+            .record_fallback(DeviceUnitType::DeviceUnitTypeMSM);
+        tracing::debug!(
+            n = coeffs.len(),
+            "msm below the device crossover, running on the cpu"
+        );
+        drop(binding);
+        return best_multiexp_cpu_fixed_base(coeffs, bases, fixed_base, endo_bases);
+    }
+    let options = DeviceJobOptions {
+        timeout: device_manager_handle.job_timeout,
+        cancel: None,
+    };
+    drop(binding);
+
+    // Wait for the turn of this job on the devices, outside of the manager
+    // lock, no longer than the job timeout of the manager.
+    let (priority, tenant) = device_job_class();
+    let permit = GLOBAL_DEVICE_JOB_QUEUE.acquire_with_options(
+        DeviceJob {
+            priority,
+            tenant: tenant.unwrap_or(param_id as u64),
+            device_unit_type: DeviceUnitType::DeviceUnitTypeMSM,
+            n: coeffs.len(),
+        },
+        &options,
+    );
+
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+    device_manager_handle
+        .metrics
+        .record_job_wait(DeviceUnitType::DeviceUnitTypeMSM, wait_start.elapsed());
+    if let Err(error) = &permit {
+        device_manager_handle.metrics.record_error(error);
+        device_manager_handle
+            .metrics
+            .record_fallback(DeviceUnitType::DeviceUnitTypeMSM);
+        drop(binding);
+        return best_multiexp_cpu_fixed_base(coeffs, bases, fixed_base, endo_bases);
+    }
+
+    let result =
+        device_manager_handle.execute_msm_verified::<C>(param_id, bases_index, coeffs, bases);
+    let result = match result.and_then(|result_datas| curve_from_bytes::<C>(&result_datas)) {
+        Ok(result) => result,
+        // Every device failed or is quarantined, or the result was wrong.
+        Err(_) => {
+            device_manager_handle
+                .metrics
+                .record_fallback(DeviceUnitType::DeviceUnitTypeMSM);
+            drop(binding);
+            drop(permit);
+            return best_multiexp_cpu_fixed_base(coeffs, bases, fixed_base, endo_bases);
+        }
+    };
+    drop(binding);
+    drop(permit);
+
+    result
+}
+
+/// Time MSM and NTT on the CPU and every device for `2^min_k..=2^max_k` and
+/// keep the fitted cost model in the device manager, persisting it to `path`.
+///
+// Note: `bases` must be the bases registered at `bases_index` of `param_id`.
+#[cfg(any(feature = "msm_cuda"))]
+pub fn best_calibrate_gpu<C: CurveAffine>(
+    param_id: usize,
+    bases_index: usize,
+    bases: &[C],
+    omega: C::Scalar,
+    min_k: u32,
+    max_k: u32,
+    path: Option<&std::path::Path>,
+) -> DeviceCostModel {
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+
+    let model = device_manager_handle
+        .calibrate(param_id, bases_index, bases, omega, min_k, max_k)
+        .unwrap();
+    if let Some(path) = path {
+        model.save(path).unwrap();
+    }
+
+    model
+}
+
+///
+#[cfg(any(feature = "fft_cuda"))]
+pub fn best_fft_init_gpu<Scalar: Field>(omega: Scalar) {
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+
+    let omega_slice = [omega.clone()];
+    let omega_bytes = device_manager_handle.transmute_values(&omega_slice);
+
+    device_manager_handle.init(
+        DeviceInitUnitType::DeviceInitUnitTypeNTT,
+        None,
+        None,
+        Some(omega_bytes),
+    );
+}
+
+/// Performs a radix-$2$ Fast-Fourier Transformation (FFT) on a vector of size
+/// $n = 2^k$, when provided `log_n` = $k$ and an element of multiplicative
+/// order $n$ called `omega` ($\omega$). The result is that the vector `a`, when
+/// interpreted as the coefficients of a polynomial of degree $n - 1$, is
+/// transformed into the evaluations of this polynomial at each of the $n$
+/// distinct powers of $\omega$. This transformation is invertible by providing
+/// $\omega^{-1}$ in place of $\omega$ and dividing each resulting field element
+/// by $n$.
+///
+/// This will use multithreading if beneficial.
+pub fn best_fft<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scalar, log_n: u32) {
+    #[cfg(any(feature = "fft_cuda"))]
+    best_fft_gpu(a, omega, log_n);
+    #[cfg(not(any(feature = "fft_cuda")))]
+    best_fft_cpu(a, omega, log_n);
+}
+
+///
+#[cfg(any(feature = "fft_cuda"))]
+pub fn best_fft_gpu<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scalar, log_n: u32) {
+    let wait_start = std::time::Instant::now();
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+
+    // Below the calibrated crossover size the CPU is faster, and the job does
+    // not wait in the queue of the devices.
+    if device_manager_handle.prefer_cpu(DeviceUnitType::DeviceUnitTypeNTT, a.len()) {
+        device_manager_handle
+            .metrics
+            .record_fallback(DeviceUnitType::DeviceUnitTypeNTT);
+        tracing::debug!(
+            n = a.len(),
+            "ntt below the device crossover, running on the cpu"
+        );
+        drop(binding);
+        return best_fft_cpu(a, omega, log_n);
+    }
+    let options = DeviceJobOptions {
+        timeout: device_manager_handle.job_timeout,
+        cancel: None,
+    };
+    drop(binding);
+
+    // Wait for the turn of this job on the devices, outside of the manager
+    // lock, no longer than the job timeout of the manager.
+    let (priority, tenant) = device_job_class();
+    let permit = GLOBAL_DEVICE_JOB_QUEUE.acquire_with_options(
+        DeviceJob {
+            priority,
+            tenant: tenant.unwrap_or(0),
+            device_unit_type: DeviceUnitType::DeviceUnitTypeNTT,
+            n: a.len(),
+        },
+        &options,
+    );
+
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+    device_manager_handle
+        .metrics
+        .record_job_wait(DeviceUnitType::DeviceUnitTypeNTT, wait_start.elapsed());
+    if let Err(error) = &permit {
+        device_manager_handle.metrics.record_error(error);
+        device_manager_handle
+            .metrics
+            .record_fallback(DeviceUnitType::DeviceUnitTypeNTT);
+        drop(binding);
+        return best_fft_cpu(a, omega, log_n);
+    }
+
+    // Every device failed or is quarantined, or the result was wrong.
+    // The input is left untouched.
+    if device_manager_handle
+        .execute_ntt_verified::<Scalar, G>(a, omega, log_n)
+        .is_err()
+    {
+        device_manager_handle
+            .metrics
+            .record_fallback(DeviceUnitType::DeviceUnitTypeNTT);
+        drop(binding);
+        drop(permit);
+        best_fft_cpu(a, omega, log_n);
+    }
+}
+
+/// `poly` padded with zeros to `2^extended_log_n`, its coefficient `i`
+/// multiplied by `zeta^i`, so that its NTT evaluates it on the coset `zeta`
+/// of the extended domain.
+pub fn coset_scale_padded<Scalar: Field, G: FftGroup<Scalar>>(
+    poly: &[G],
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    assert!(poly.len() <= 1 << extended_log_n);
+    let mut values = poly.to_vec();
+    parallelize(&mut values, |values, start| {
+        let mut power = zeta.pow_vartime([start as u64]);
+        for value in values.iter_mut() {
+            *value *= power;
+            power *= zeta;
+        }
+    });
+    // `FftGroup` has no zero of its own.
+    let zero = poly[0] * Scalar::ZERO;
+    values.resize(1 << extended_log_n, zero);
+    values
+}
+
+/// The `2^log_n` coefficients of a polynomial from the forward NTT of its
+/// evaluations on the coset `zeta` of the extended domain, undoing
+/// `coset_scale_padded`. The inverse NTT is the forward one with the outputs
+/// but the first reversed, divided by the size.
+pub fn coset_unscale_truncated<Scalar: Field, G: FftGroup<Scalar>>(
+    transformed: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    let extended_n = 1 << extended_log_n;
+    assert_eq!(transformed.len(), extended_n);
+    assert!(log_n <= extended_log_n);
+    let n_inv = Scalar::TWO_INV.pow_vartime([extended_log_n as u64]);
+    let zeta_inv = zeta.invert().unwrap();
+
+    let mut coeffs = transformed[..1 << log_n].to_vec();
+    parallelize(&mut coeffs, |coeffs, start| {
+        let mut power = zeta_inv.pow_vartime([start as u64]) * n_inv;
+        for (i, coeff) in coeffs.iter_mut().enumerate() {
+            *coeff = transformed[(extended_n - start - i) % extended_n];
+            *coeff *= power;
+            power *= zeta_inv;
+        }
+    });
+    coeffs
+}
+
+/// The evaluations of the `2^log_n` coefficients `poly` on the coset `zeta`
+/// of the domain of `2^extended_log_n` elements with the standard root of
+/// unity, on the CPU.
+pub fn coset_extend_cpu<Scalar: PrimeField, G: FftGroup<Scalar>>(
+    poly: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    assert_eq!(poly.len(), 1 << log_n);
+    let mut omega = Scalar::ROOT_OF_UNITY;
+    for _ in extended_log_n..Scalar::S {
+        omega = omega.square();
+    }
+
+    let mut values = coset_scale_padded(poly, extended_log_n, zeta);
+    best_fft_cpu(&mut values, omega, extended_log_n);
+    values
+}
+
+/// The inverse of `coset_extend_cpu`, the `2^log_n` coefficients of a
+/// polynomial from its evaluations on the extended coset.
+pub fn extended_to_coeff_cpu<Scalar: PrimeField, G: FftGroup<Scalar>>(
+    values: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    let mut omega = Scalar::ROOT_OF_UNITY;
+    for _ in extended_log_n..Scalar::S {
+        omega = omega.square();
+    }
+
+    let mut transformed = values.to_vec();
+    best_fft_cpu(&mut transformed, omega, extended_log_n);
+    coset_unscale_truncated(&transformed, log_n, extended_log_n, zeta)
+}
+
+/// The evaluations of the coefficients `poly` on the coset `zeta` of the
+/// extended domain, padding, scaling and transforming in one device call
+/// when the devices support it.
+pub fn best_coset_extend<Scalar: PrimeField, G: FftGroup<Scalar>>(
+    poly: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    #[cfg(any(feature = "fft_cuda"))]
+    return best_coset_extend_gpu(poly, log_n, extended_log_n, zeta);
+    #[cfg(not(any(feature = "fft_cuda")))]
+    return coset_extend_cpu(poly, log_n, extended_log_n, zeta);
+}
+
+/// The inverse of `best_coset_extend`.
+pub fn best_extended_to_coeff<Scalar: PrimeField, G: FftGroup<Scalar>>(
+    values: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    #[cfg(any(feature = "fft_cuda"))]
+    return best_extended_to_coeff_gpu(values, log_n, extended_log_n, zeta);
+    #[cfg(not(any(feature = "fft_cuda")))]
+    return extended_to_coeff_cpu(values, log_n, extended_log_n, zeta);
+}
+
+///
+#[cfg(any(feature = "fft_cuda"))]
+pub fn best_coset_extend_gpu<Scalar: PrimeField, G: FftGroup<Scalar>>(
+    poly: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    let n = 1 << extended_log_n;
+    with_device(DeviceUnitType::DeviceUnitTypeNTT, n, |handle| {
+        handle.execute_coset_extend::<Scalar, G>(poly, log_n, extended_log_n, zeta)
+    })
+    .unwrap_or_else(|| coset_extend_cpu(poly, log_n, extended_log_n, zeta))
+}
+
+///
+#[cfg(any(feature = "fft_cuda"))]
+pub fn best_extended_to_coeff_gpu<Scalar: PrimeField, G: FftGroup<Scalar>>(
+    values: &[G],
+    log_n: u32,
+    extended_log_n: u32,
+    zeta: Scalar,
+) -> Vec<G> {
+    with_device(DeviceUnitType::DeviceUnitTypeNTT, values.len(), |handle| {
+        handle.execute_extended_to_coeff::<Scalar, G>(values, log_n, extended_log_n, zeta)
+    })
+    .unwrap_or_else(|| extended_to_coeff_cpu(values, log_n, extended_log_n, zeta))
+}
+
+/// Run the job of `n` elements of the unit on the global device manager in
+/// the turn of the job queue, or `None` when it should run on the CPU: below
+/// the crossover size, without waiting in the queue, or after every device
+/// failed.
+#[cfg(any(feature = "fft_cuda"))]
+fn with_device<T>(
+    device_unit_type: DeviceUnitType,
+    n: usize,
+    job: impl FnOnce(&mut DeviceManagerContext) -> Result<T, DeviceManagerError>,
+) -> Option<T> {
+    let wait_start = std::time::Instant::now();
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+    if device_manager_handle.prefer_cpu(device_unit_type, n) {
+        device_manager_handle
+            .metrics
+            .record_fallback(device_unit_type);
+        return None;
+    }
+    let options = DeviceJobOptions {
+        timeout: device_manager_handle.job_timeout,
+        cancel: None,
+    };
+    drop(binding);
+
+    let (priority, tenant) = device_job_class();
+    let permit = GLOBAL_DEVICE_JOB_QUEUE.acquire_with_options(
+        DeviceJob {
+            priority,
+            tenant: tenant.unwrap_or(0),
+            device_unit_type,
+            n,
+        },
+        &options,
+    );
+
+    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    let device_manager_handle = binding.get_handle_mut();
+    device_manager_handle
+        .metrics
+        .record_job_wait(device_unit_type, wait_start.elapsed());
+    match &permit {
+        Ok(_) => {
+            if let Ok(result) = job(device_manager_handle) {
+                return Some(result);
+            }
+        }
+        Err(error) => device_manager_handle.metrics.record_error(error),
+    }
+    device_manager_handle
+        .metrics
+        .record_fallback(device_unit_type);
+    None
+}
+
+/// An elementwise operation on a vector `a`, with a vector `b` of the same
+/// length for the binary ones, see `pointwise_cpu`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PointwiseOp<F> {
+    /// `a[i] + b[i]`
+    Add,
+    /// `a[i] - b[i]`
+    Sub,
+    /// `a[i] * b[i]`
+    Mul,
+    /// `a[i] * c`
+    Scale(F),
+    /// `a[i] + c`
+    AddConstant(F),
+    /// `a[i] - c`
+    SubConstant(F),
+    /// `a[i] * start * ratio^i`
+    MulPowers {
+        ///
+        start: F,
+        ///
+        ratio: F,
+    },
+}
+
+impl<F> PointwiseOp<F> {
+    /// Whether the operation takes a second vector.
+    pub fn is_binary(&self) -> bool {
+        matches!(self, PointwiseOp::Add | PointwiseOp::Sub | PointwiseOp::Mul)
+    }
+
+    /// The same operation with the constants mapped, such as to their bytes.
+    pub fn map<T>(&self, f: impl Fn(&F) -> T) -> PointwiseOp<T> {
+        match self {
+            PointwiseOp::Add => PointwiseOp::Add,
+            PointwiseOp::Sub => PointwiseOp::Sub,
+            PointwiseOp::Mul => PointwiseOp::Mul,
+            PointwiseOp::Scale(c) => PointwiseOp::Scale(f(c)),
+            PointwiseOp::AddConstant(c) => PointwiseOp::AddConstant(f(c)),
+            PointwiseOp::SubConstant(c) => PointwiseOp::SubConstant(f(c)),
+            PointwiseOp::MulPowers { start, ratio } => PointwiseOp::MulPowers {
+                start: f(start),
+                ratio: f(ratio),
+            },
+        }
+    }
+
+    /// `map` with a conversion that may fail, such as from bytes.
+    pub fn try_map<T, E>(&self, f: impl Fn(&F) -> Result<T, E>) -> Result<PointwiseOp<T>, E> {
+        Ok(match self {
+            PointwiseOp::Add => PointwiseOp::Add,
+            PointwiseOp::Sub => PointwiseOp::Sub,
+            PointwiseOp::Mul => PointwiseOp::Mul,
+            PointwiseOp::Scale(c) => PointwiseOp::Scale(f(c)?),
+            PointwiseOp::AddConstant(c) => PointwiseOp::AddConstant(f(c)?),
+            PointwiseOp::SubConstant(c) => PointwiseOp::SubConstant(f(c)?),
+            PointwiseOp::MulPowers { start, ratio } => PointwiseOp::MulPowers {
+                start: f(start)?,
+                ratio: f(ratio)?,
+            },
+        })
+    }
+}
+
+/// The elementwise operation on `values` in place on the devices when they
+/// support it, see `pointwise_cpu`.
+pub fn best_pointwise<F: Field>(values: &mut [F], other: &[F], op: &PointwiseOp<F>) {
+    #[cfg(any(feature = "fft_cuda"))]
+    return best_pointwise_gpu(values, other, op);
+    #[cfg(not(any(feature = "fft_cuda")))]
+    return pointwise_cpu(values, other, op);
+}
+
+///
+#[cfg(any(feature = "fft_cuda"))]
+pub fn best_pointwise_gpu<F: Field>(values: &mut [F], other: &[F], op: &PointwiseOp<F>) {
+    // Without the backend support this is no fallback, so it neither queues
+    // nor counts in the metrics.
+    let supported = GLOBAL_DEVICE_MANAGER
+        .lock()
+        .unwrap()
+        .get_handle()
+        .backend
+        .supports_pointwise();
+    if !supported {
+        return pointwise_cpu(values, other, op);
+    }
+    let done = with_device(
+        DeviceUnitType::DeviceUnitTypePointwise,
+        values.len(),
+        |handle| handle.execute_pointwise(values, other, op),
+    );
+    if done.is_none() {
+        pointwise_cpu(values, other, op);
+    }
+}
+
+/// The elementwise operation on `values` in place, with `other` for the
+/// binary operations. `other` is ignored by the others.
+pub fn pointwise_cpu<F: Field>(values: &mut [F], other: &[F], op: &PointwiseOp<F>) {
+    if op.is_binary() {
+        assert_eq!(values.len(), other.len());
+    }
+    parallelize(values, |values, start| match op {
+        PointwiseOp::Add => {
+            for (a, b) in values.iter_mut().zip(other[start..].iter()) {
+                *a += b;
+            }
+        }
+        PointwiseOp::Sub => {
+            for (a, b) in values.iter_mut().zip(other[start..].iter()) {
+                *a -= b;
+            }
+        }
+        PointwiseOp::Mul => {
+            for (a, b) in values.iter_mut().zip(other[start..].iter()) {
+                *a *= b;
+            }
+        }
+        PointwiseOp::Scale(c) => {
+            for a in values.iter_mut() {
+                *a *= c;
+            }
+        }
+        PointwiseOp::AddConstant(c) => {
+            for a in values.iter_mut() {
+                *a += c;
+            }
+        }
+        PointwiseOp::SubConstant(c) => {
+            for a in values.iter_mut() {
+                *a -= c;
+            }
+        }
+        PointwiseOp::MulPowers {
+            start: first,
+            ratio,
+        } => {
+            let mut power = *first * ratio.pow_vartime([start as u64]);
+            for a in values.iter_mut() {
+                *a *= power;
+                power *= ratio;
+            }
+        }
+    });
+}
+
+/// The default memory limit of the twiddle tables `best_fft_cpu` keeps.
+pub const FFT_TWIDDLE_CACHE_DEFAULT_BYTES: usize = 1 << 30;
 
-    let bases_bytes_slice: &[&[u8]] = &bases_bytes_vec;
+/// The smallest `log_n` whose twiddles are cached, smaller ones are cheaper
+/// to compute than to look up.
+const FFT_TWIDDLE_CACHE_MIN_LOG_N: u32 = 4;
 
-    device_manager_handle
-        .init(
-            common::DeviceInitUnitType::DeviceInitUnitTypeMSM,
-            Some(param_id),
-            Some(&bases_bytes_slice),
-            None,
-        )
-        .unwrap();
+/// The twiddle factors of an FFT, `table[i * stride]` being `omega^i`.
+#[derive(Clone, Debug)]
+pub struct FftTwiddles<Scalar> {
+    ///
+    pub table: Arc<Vec<Scalar>>,
+    ///
+    pub stride: usize,
 }
 
-/// Performs a multi-exponentiation operation GPU.
-///
-/// This function will panic if coeffs and bases have a different length.
-///
-/// This will use multithreading if beneficial.
-#[cfg(any(feature = "msm_cuda"))]
-pub fn best_multiexp_gpu<C: CurveAffine>(
-    coeffs: &[C::Scalar],
-    _bases: &[C],
-    param_id: usize,
-    bases_index: usize,
-) -> C::Curve {
-    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
-    let device_manager_handle = binding.get_handle_mut();
+struct FftTwiddleEntry {
+    type_id: TypeId,
+    bytes: usize,
+    /// A `Vec` of the powers of the `omega` of the table.
+    table: Arc<dyn Any + Send + Sync>,
+}
 
-    let mut result_datas = device_manager_handle
-        .execute_msm::<C>(param_id, bases_index, coeffs)
-        .unwrap();
+/// The twiddle tables by field, the most recently used last.
+struct FftTwiddleCache {
+    limit: usize,
+    bytes: usize,
+    entries: Vec<FftTwiddleEntry>,
+}
 
-    let result_datas_ptr = result_datas.as_mut_ptr();
+impl FftTwiddleCache {
+    /// The index of the table of the entry, if it is of `Scalar` and its
+    /// powers include those of `omega` of size `n / 2`, with the stride.
+    fn find<Scalar: Field>(&self, omega: Scalar, n: usize) -> Option<(usize, FftTwiddles<Scalar>)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, entry)| {
+                if entry.type_id != TypeId::of::<Scalar>() {
+                    return None;
+                }
+                let table = entry.table.clone().downcast::<Vec<Scalar>>().ok()?;
+                if table.len() < n / 2 {
+                    return None;
+                }
+                let stride = table.len() / (n / 2);
+                if table[stride] != omega {
+                    return None;
+                }
+                Some((index, FftTwiddles { table, stride }))
+            })
+    }
 
-    let mut curve_value = Vec::<C::Curve>::with_capacity(1);
-    let curve_value_ptr = curve_value.as_mut_ptr() as *mut u8;
+    /// Keep the table, dropping the least recently used ones over the limit.
+    fn insert<Scalar: Field>(&mut self, table: Arc<Vec<Scalar>>) {
+        let bytes = std::mem::size_of_val(&table[..]);
+        if bytes > self.limit {
+            return;
+        }
+        // The smaller tables of the same root are strides of this one.
+        self.entries.retain(|entry| {
+            let subsumed = entry.type_id == TypeId::of::<Scalar>()
+                && entry
+                    .table
+                    .downcast_ref::<Vec<Scalar>>()
+                    .map_or(false, |smaller| {
+                        smaller.len() < table.len()
+                            && smaller[1] == table[table.len() / smaller.len()]
+                    });
+            !subsumed
+        });
+        self.entries.push(FftTwiddleEntry {
+            type_id: TypeId::of::<Scalar>(),
+            bytes,
+            table,
+        });
+        self.evict();
+    }
 
-    let size = std::mem::size_of::<u8>() * result_datas.len();
-    unsafe {
-        std::ptr::copy_nonoverlapping(result_datas_ptr, curve_value_ptr, size);
+    fn evict(&mut self) {
+        self.bytes = self.entries.iter().map(|entry| entry.bytes).sum();
+        while self.bytes > self.limit {
+            let entry = self.entries.remove(0);
+            self.bytes -= entry.bytes;
+        }
     }
-    std::mem::forget(result_datas_ptr);
-    unsafe { curve_value.set_len(1) };
+}
 
-    let result = curve_value[0].clone();
+lazy_static! {
+    static ref FFT_TWIDDLE_CACHE: Mutex<FftTwiddleCache> = Mutex::new(FftTwiddleCache {
+        limit: FFT_TWIDDLE_CACHE_DEFAULT_BYTES,
+        bytes: 0,
+        entries: vec![],
+    });
+}
 
-    result
+/// Limit the memory of the twiddle tables kept by `best_fft_cpu`, `0` to
+/// keep none.
+pub fn set_fft_twiddle_cache_limit(bytes: usize) {
+    let mut cache = FFT_TWIDDLE_CACHE.lock().unwrap();
+    cache.limit = bytes;
+    cache.evict();
 }
 
-///
-#[cfg(any(feature = "fft_cuda"))]
-pub fn best_fft_init_gpu<Scalar: Field>(omega: Scalar) {
-    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
-    let device_manager_handle = binding.get_handle_mut();
+/// The memory of the twiddle tables kept by `best_fft_cpu`.
+pub fn fft_twiddle_cache_bytes() -> usize {
+    FFT_TWIDDLE_CACHE.lock().unwrap().bytes
+}
 
-    let omega_slice = [omega.clone()];
-    let omega_bytes = device_manager_handle.transmute_values(&omega_slice);
+/// The powers `omega^i` for `i < n / 2`, computed in parallel.
+fn fft_twiddle_table<Scalar: Field>(omega: Scalar, n: usize) -> Vec<Scalar> {
+    let mut table = vec![Scalar::ONE; n / 2];
+    parallelize(&mut table, |table, start| {
+        let mut w = omega.pow_vartime([start as u64]);
+        for tw in table.iter_mut() {
+            *tw = w;
+            w *= &omega;
+        }
+    });
+    table
+}
 
-    device_manager_handle.init(
-        DeviceInitUnitType::DeviceInitUnitTypeNTT,
-        None,
-        None,
-        Some(omega_bytes),
-    );
+/// The twiddle factors of the FFT of size `2^log_n` with `omega`. The tables
+/// are kept across calls, and the table of a larger domain serves the
+/// smaller ones with a stride.
+pub fn fft_twiddles<Scalar: Field>(omega: Scalar, log_n: u32) -> FftTwiddles<Scalar> {
+    let n = 1 << log_n;
+    if log_n < FFT_TWIDDLE_CACHE_MIN_LOG_N {
+        return FftTwiddles {
+            table: Arc::new(fft_twiddle_table(omega, n)),
+            stride: 1,
+        };
+    }
+
+    {
+        let mut cache = FFT_TWIDDLE_CACHE.lock().unwrap();
+        if let Some((index, twiddles)) = cache.find(omega, n) {
+            let entry = cache.entries.remove(index);
+            cache.entries.push(entry);
+            return twiddles;
+        }
+    }
+
+    // Computed without the lock, a race only computes the table twice.
+    let table = Arc::new(fft_twiddle_table(omega, n));
+    FFT_TWIDDLE_CACHE.lock().unwrap().insert(table.clone());
+    FftTwiddles { table, stride: 1 }
 }
 
-/// Performs a radix-$2$ Fast-Fourier Transformation (FFT) on a vector of size
-/// $n = 2^k$, when provided `log_n` = $k$ and an element of multiplicative
-/// order $n$ called `omega` ($\omega$). The result is that the vector `a`, when
-/// interpreted as the coefficients of a polynomial of degree $n - 1$, is
-/// transformed into the evaluations of this polynomial at each of the $n$
-/// distinct powers of $\omega$. This transformation is invertible by providing
-/// $\omega^{-1}$ in place of $\omega$ and dividing each resulting field element
-/// by $n$.
-///
-/// This will use multithreading if beneficial.
-pub fn best_fft<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scalar, log_n: u32) {
-    #[cfg(any(feature = "fft_cuda"))]
-    best_fft_gpu(a, omega, log_n);
-    #[cfg(not(any(feature = "fft_cuda")))]
-    best_fft_cpu(a, omega, log_n);
+/// The CPU NTT algorithms `best_fft_cpu` chooses from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FftCpuAlgorithm {
+    /// `best_fft_cpu_radix2`, the default.
+    Radix2,
+    /// `best_fft_cpu_radix4`.
+    Radix4,
 }
 
-///
-#[cfg(any(feature = "fft_cuda"))]
-pub fn best_fft_gpu<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scalar, log_n: u32) {
-    let mut binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
-    let device_manager_handle = binding.get_handle_mut();
+static FFT_CPU_ALGORITHM: AtomicBool = AtomicBool::new(false);
 
-    let mut result_datas = device_manager_handle
-        .execute_ntt::<Scalar, G>(a, log_n)
-        .unwrap();
+/// Choose the algorithm of `best_fft_cpu` for the whole process.
+pub fn set_fft_cpu_algorithm(algorithm: FftCpuAlgorithm) {
+    FFT_CPU_ALGORITHM.store(algorithm == FftCpuAlgorithm::Radix4, Ordering::Relaxed);
 }
 
-/// raw best_fft
-pub fn best_fft_cpu<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scalar, log_n: u32) {
+/// The algorithm of `best_fft_cpu`.
+pub fn fft_cpu_algorithm() -> FftCpuAlgorithm {
+    if FFT_CPU_ALGORITHM.load(Ordering::Relaxed) {
+        FftCpuAlgorithm::Radix4
+    } else {
+        FftCpuAlgorithm::Radix2
+    }
+}
+
+/// Permute `a` of size `2^log_n` into bit-reversed order.
+fn bitreverse_permute<T>(a: &mut [T], log_n: u32) {
     fn bitreverse(mut n: usize, l: usize) -> usize {
         let mut r = 0;
         for _ in 0..l {
@@ -307,30 +1668,44 @@ pub fn best_fft_cpu<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scal
         r
     }
 
-    let threads = multicore::current_num_threads();
-    let log_threads = log2_floor(threads);
-    let n = a.len() as usize;
-    assert_eq!(n, 1 << log_n);
-
-    for k in 0..n {
+    for k in 0..a.len() {
         let rk = bitreverse(k, log_n as usize);
         if k < rk {
             a.swap(rk, k);
         }
     }
+}
 
-    // precompute twiddle factors
-    let twiddles: Vec<_> = (0..(n / 2) as usize)
-        .scan(Scalar::ONE, |w, _| {
-            let tw = *w;
-            *w *= &omega;
-            Some(tw)
-        })
-        .collect();
+/// raw best_fft, with the algorithm of `set_fft_cpu_algorithm`.
+pub fn best_fft_cpu<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scalar, log_n: u32) {
+    match fft_cpu_algorithm() {
+        FftCpuAlgorithm::Radix2 => best_fft_cpu_radix2(a, omega, log_n),
+        FftCpuAlgorithm::Radix4 => best_fft_cpu_radix4(a, omega, log_n),
+    }
+}
+
+/// The radix-2 NTT of `best_fft_cpu`.
+pub fn best_fft_cpu_radix2<Scalar: Field, G: FftGroup<Scalar>>(
+    a: &mut [G],
+    omega: Scalar,
+    log_n: u32,
+) {
+    let threads = multicore::current_num_threads();
+    let log_threads = log2_floor(threads);
+    let n = a.len() as usize;
+    assert_eq!(n, 1 << log_n);
+
+    bitreverse_permute(a, log_n);
+
+    // The twiddle factors are cached across calls.
+    let FftTwiddles {
+        table: twiddles,
+        stride,
+    } = fft_twiddles(omega, log_n);
 
     if log_n <= log_threads {
         let mut chunk = 2_usize;
-        let mut twiddle_chunk = (n / 2) as usize;
+        let mut twiddle_chunk = (n / 2) as usize * stride;
         for _ in 0..log_n {
             a.chunks_mut(chunk).for_each(|coeffs| {
                 let (left, right) = coeffs.split_at_mut(chunk / 2);
@@ -358,7 +1733,158 @@ pub fn best_fft_cpu<Scalar: Field, G: FftGroup<Scalar>>(a: &mut [G], omega: Scal
             twiddle_chunk /= 2;
         }
     } else {
-        recursive_butterfly_arithmetic(a, n, 1, &twiddles)
+        recursive_butterfly_arithmetic(a, n, stride, &twiddles)
+    }
+}
+
+/// The size of the blocks `best_fft_cpu_radix4` runs its first stages in,
+/// small enough to stay in the cache.
+pub const FFT_RADIX4_LOG_BLOCK: u32 = 10;
+
+/// An NTT with the result of `best_fft_cpu_radix2`, running two radix-2
+/// stages per pass with radix-4 butterflies. The stages whose butterflies
+/// fit in a block of `2^FFT_RADIX4_LOG_BLOCK` elements run block by block,
+/// the others pass over the whole input split across the threads.
+pub fn best_fft_cpu_radix4<Scalar: Field, G: FftGroup<Scalar>>(
+    a: &mut [G],
+    omega: Scalar,
+    log_n: u32,
+) {
+    let n = a.len();
+    assert_eq!(n, 1 << log_n);
+    if n == 1 {
+        return;
+    }
+
+    bitreverse_permute(a, log_n);
+
+    let FftTwiddles { table, stride } = fft_twiddles(omega, log_n);
+    // `omega^e`, with `omega^(n / 2) = -1` for the exponents past the table.
+    let twiddle = |e: usize| {
+        if e < n / 2 {
+            table[e * stride]
+        } else {
+            -table[(e - n / 2) * stride]
+        }
+    };
+    let twiddle = &twiddle;
+
+    // An odd number of stages starts with a radix-2 one, whose twiddles are
+    // all one.
+    let mut log_m = log_n % 2;
+    let log_block = log_n.min(FFT_RADIX4_LOG_BLOCK);
+    let block_stages = (log_block - log_m) / 2;
+    multicore::scope(|scope| {
+        for block in a.chunks_mut(1 << log_block) {
+            scope.spawn(move |_| {
+                if log_m == 1 {
+                    for pair in block.chunks_mut(2) {
+                        let t = pair[1];
+                        pair[1] = pair[0];
+                        pair[0] += &t;
+                        pair[1] -= &t;
+                    }
+                }
+                for stage in 0..block_stages {
+                    let m = 1 << (log_m + 2 * stage);
+                    for group in block.chunks_mut(4 * m) {
+                        radix4_butterflies(group, 0, m, n, twiddle);
+                    }
+                }
+            });
+        }
+    });
+    log_m += 2 * block_stages;
+
+    let threads = multicore::current_num_threads();
+    while log_m < log_n {
+        let m = 1 << log_m;
+        let chunk = (n / 4 / threads).max(1).min(m);
+        multicore::scope(|scope| {
+            for group in a.chunks_mut(4 * m) {
+                let (q01, q23) = group.split_at_mut(2 * m);
+                let (q0, q1) = q01.split_at_mut(m);
+                let (q2, q3) = q23.split_at_mut(m);
+                for (index, (((q0, q1), q2), q3)) in q0
+                    .chunks_mut(chunk)
+                    .zip(q1.chunks_mut(chunk))
+                    .zip(q2.chunks_mut(chunk))
+                    .zip(q3.chunks_mut(chunk))
+                    .enumerate()
+                {
+                    scope.spawn(move |_| {
+                        radix4_quarters([q0, q1, q2, q3], index * chunk, m, n, twiddle)
+                    });
+                }
+            }
+        });
+        log_m += 2;
+    }
+}
+
+/// The radix-4 butterflies of a group of `4 * m` elements, from `j0` in its
+/// quarters.
+fn radix4_butterflies<Scalar: Field, G: FftGroup<Scalar>>(
+    group: &mut [G],
+    j0: usize,
+    m: usize,
+    n: usize,
+    twiddle: &(impl Fn(usize) -> Scalar + Sync),
+) {
+    let (q01, q23) = group.split_at_mut(2 * m);
+    let (q0, q1) = q01.split_at_mut(m);
+    let (q2, q3) = q23.split_at_mut(m);
+    radix4_quarters([q0, q1, q2, q3], j0, m, n, twiddle)
+}
+
+/// Two radix-2 stages, of sizes `2 * m` and `4 * m`, over the elements of the
+/// quarters of a group from `j0` on. With `w = omega_(4m)^j`, the first
+/// stage pairs the quarters 0, 1 and 2, 3 with `w^2`, and the second pairs
+/// 0, 2 with `w` and 1, 3 with `w * omega_4`.
+fn radix4_quarters<Scalar: Field, G: FftGroup<Scalar>>(
+    [q0, q1, q2, q3]: [&mut [G]; 4],
+    j0: usize,
+    m: usize,
+    n: usize,
+    twiddle: &(impl Fn(usize) -> Scalar + Sync),
+) {
+    let step = n / (4 * m);
+    let imag = twiddle(n / 4);
+    for (j, (((a0, a1), a2), a3)) in q0
+        .iter_mut()
+        .zip(q1.iter_mut())
+        .zip(q2.iter_mut())
+        .zip(q3.iter_mut())
+        .enumerate()
+    {
+        let e = (j0 + j) * step;
+        let mut b1 = *a1;
+        let mut b2 = *a2;
+        let mut b3 = *a3;
+        if e != 0 {
+            b1 *= twiddle(2 * e);
+            b2 *= twiddle(e);
+            b3 *= twiddle(3 * e);
+        }
+
+        let mut x1 = *a0;
+        x1 -= &b1;
+        let mut x0 = *a0;
+        x0 += &b1;
+        let mut y3 = b2;
+        y3 -= &b3;
+        y3 *= imag;
+        let mut y2 = b2;
+        y2 += &b3;
+
+        *a0 = x0;
+        *a0 += &y2;
+        *a2 = x0;
+        *a2 -= &y2;
+        *a1 = x1;
+        *a1 += &y3;
+        *a3 = x1;
+        *a3 -= &y3;
     }
 }
 
@@ -645,3 +2171,381 @@ fn test_lagrange_interpolate() {
         }
     }
 }
+
+#[test]
+fn test_recode_signed_digits() {
+    let rng = OsRng;
+
+    for c in 1..20 {
+        let scalar = Fp::random(rng);
+        let digits = recode_signed_digits(scalar.to_repr().as_ref(), c);
+        assert_eq!(digits.len(), signed_digit_num(256, c));
+
+        let radix = Fp::from(1 << c);
+        let mut recoded = Fp::ZERO;
+        for digit in digits.iter().rev() {
+            assert!(digit.unsigned_abs() <= 1 << (c - 1));
+            let digit = if *digit < 0 {
+                -Fp::from(digit.unsigned_abs())
+            } else {
+                Fp::from(*digit as u64)
+            };
+            recoded = recoded * radix + digit;
+        }
+        assert_eq!(recoded, scalar);
+    }
+}
+
+#[test]
+fn test_multiexp_signed_digits() {
+    use crate::halo2curves::pasta::{Eq, EqAffine};
+
+    let rng = OsRng;
+
+    for n in [1, 3, 17, 100, 1000] {
+        let mut coeffs = (0..n).map(|_| Fp::random(rng)).collect::<Vec<_>>();
+        coeffs[0] = -Fp::ONE;
+        let bases = (0..n)
+            .map(|_| Eq::random(rng).to_affine())
+            .collect::<Vec<EqAffine>>();
+
+        let expected = small_multiexp(&coeffs, &bases);
+        assert_eq!(best_multiexp_cpu(&coeffs, &bases), expected);
+        for c in 1..10 {
+            assert_eq!(best_multiexp_cpu_with_window(&coeffs, &bases, c), expected);
+        }
+    }
+}
+
+#[test]
+fn test_multiexp_batch_affine_edge_cases() {
+    use crate::halo2curves::pasta::{Eq, EqAffine};
+
+    let rng = OsRng;
+    let base = Eq::random(rng).to_affine();
+    let scalar = Fp::random(rng);
+
+    // Every point goes to the same buckets, so the batches double the bucket
+    // and the rest of the points overflow.
+    let bases = vec![base; 100];
+    let coeffs = vec![scalar; 100];
+    assert_eq!(
+        best_multiexp_cpu(&coeffs, &bases),
+        small_multiexp(&coeffs, &bases)
+    );
+
+    // `2^c - 1` has the digits `-1, 1`, so the point cancels in the first
+    // bucket of the lowest window, which is added to again.
+    let bases: Vec<EqAffine> = vec![base; 3];
+    for c in 1..10 {
+        let coeffs = [Fp::ONE, Fp::from((1 << c) - 1), Fp::from(3)];
+        assert_eq!(
+            best_multiexp_cpu_with_window(&coeffs, &bases, c),
+            Eq::from(base) * Fp::from((1 << c) + 3)
+        );
+    }
+    assert_eq!(best_multiexp_cpu::<EqAffine>(&[], &[]), Eq::identity());
+
+    // Every digit comes twice in a row, so the batch fills up with as many
+    // points deferred, which refill it to its size on the flush.
+    let distinct: Vec<EqAffine> = (0..MULTIEXP_BATCH_SIZE)
+        .map(|_| Eq::random(rng).to_affine())
+        .collect();
+    let (mut coeffs, mut bases) = (vec![], vec![]);
+    for round in 0..3 {
+        for (k, base) in distinct.iter().enumerate() {
+            for _ in 0..(2 - round / 2) {
+                coeffs.push(Fp::from(k as u64 + 1));
+                bases.push(*base);
+            }
+        }
+    }
+    let expected = distinct
+        .iter()
+        .enumerate()
+        .map(|(k, base)| Eq::from(*base) * Fp::from(5 * (k as u64 + 1)))
+        .fold(Eq::identity(), |acc, point| acc + point);
+    assert_eq!(best_multiexp_cpu_with_window(&coeffs, &bases, 13), expected);
+}
+
+#[test]
+fn test_fixed_base_multiexp() {
+    use crate::halo2curves::pasta::{Eq, EqAffine};
+
+    let rng = OsRng;
+    let coeffs = (0..50).map(|_| Fp::random(rng)).collect::<Vec<_>>();
+    let bases = (0..60)
+        .map(|_| Eq::random(rng).to_affine())
+        .collect::<Vec<EqAffine>>();
+    let expected = small_multiexp(&coeffs, &bases[..50]);
+
+    for window in [1, 4, 7] {
+        for tables in [1, 2, 5, signed_digit_num(256, window)] {
+            let config = FixedBaseConfig { window, tables };
+            let table = fixed_base_precompute(&bases, &config);
+            assert_eq!(table.len(), config.table_len(bases.len()));
+            assert_eq!(fixed_base_multiexp(&coeffs, &table, &config), expected);
+        }
+    }
+}
+
+#[test]
+fn test_glv() {
+    use crate::halo2curves::bn256::{Fr, G1Affine, G1};
+    use crate::halo2curves::pasta::{Ep, EpAffine, Eq, EqAffine, Fq};
+
+    fn check<C: CurveAffine>(coeffs: &[C::Scalar], bases: &[C]) {
+        let glv = glv_constants::<C>().unwrap();
+        let lambda = glv_lambda::<C>().unwrap();
+        let beta: C::Base = field_from_limbs(&glv.beta);
+        assert_eq!(glv_endo(&bases[0], &beta), (bases[0] * lambda).to_affine());
+
+        for coeff in coeffs.iter() {
+            let [(k1, s1), (k2, s2)] = glv_decompose(coeff, glv);
+            let signed = |k: u128, negative: bool| {
+                let k = C::Scalar::from_u128(k);
+                if negative {
+                    -k
+                } else {
+                    k
+                }
+            };
+            assert_eq!(signed(k1, s1) + signed(k2, s2) * lambda, *coeff);
+        }
+        assert_eq!(
+            glv_encode::<C>(coeffs).unwrap().len(),
+            coeffs.len() * GLV_SCALAR_BYTES
+        );
+
+        let expected = multiexp_shifted(coeffs, bases, 1, 4);
+        assert_eq!(best_multiexp_cpu(coeffs, bases), expected);
+
+        // Also with the endomorphisms cached, on the mixed and the dense path.
+        let endo_bases = glv_endo_bases(bases).unwrap();
+        assert_eq!(
+            best_multiexp_cpu_with_endo_bases(coeffs, bases, Some(&endo_bases)),
+            expected
+        );
+        assert_eq!(
+            best_multiexp_cpu_with_endo_bases(&coeffs[3..], &bases[3..], Some(&endo_bases[3..])),
+            multiexp_shifted(&coeffs[3..], &bases[3..], 1, 4)
+        );
+    }
+
+    let rng = OsRng;
+    let mut coeffs = (0..100).map(|_| Fr::random(rng)).collect::<Vec<_>>();
+    coeffs[..3].copy_from_slice(&[Fr::ZERO, Fr::ONE, -Fr::ONE]);
+    let bases = (0..100)
+        .map(|_| G1::random(rng).to_affine())
+        .collect::<Vec<G1Affine>>();
+    check(&coeffs, &bases);
+
+    let mut coeffs = (0..100).map(|_| Fp::random(rng)).collect::<Vec<_>>();
+    coeffs[..3].copy_from_slice(&[Fp::ZERO, Fp::ONE, -Fp::ONE]);
+    let bases = (0..100)
+        .map(|_| Eq::random(rng).to_affine())
+        .collect::<Vec<EqAffine>>();
+    check(&coeffs, &bases);
+
+    let mut coeffs = (0..100).map(|_| Fq::random(rng)).collect::<Vec<_>>();
+    coeffs[..3].copy_from_slice(&[Fq::ZERO, Fq::ONE, -Fq::ONE]);
+    let bases = (0..100)
+        .map(|_| Ep::random(rng).to_affine())
+        .collect::<Vec<EpAffine>>();
+    check(&coeffs, &bases);
+}
+
+#[test]
+fn test_multiexp_sparse_and_small() {
+    use crate::halo2curves::bn256::{Fr, G1Affine, G1};
+
+    let rng = OsRng;
+    let bases = (0..200)
+        .map(|_| G1::random(rng).to_affine())
+        .collect::<Vec<G1Affine>>();
+    let coeffs = (0..200u64)
+        .map(|i| match i % 5 {
+            0 => Fr::ZERO,
+            1 => Fr::ONE,
+            2 => Fr::from(u64::MAX - i),
+            3 => Fr::from(i),
+            _ => Fr::random(rng),
+        })
+        .collect::<Vec<_>>();
+
+    let kinds = multiexp_scalar_kinds(&coeffs);
+    assert_eq!(kinds[0], MultiexpScalarKind::Zero);
+    assert_eq!(kinds[1], MultiexpScalarKind::One);
+    assert_eq!(kinds[2], MultiexpScalarKind::Small(u64::MAX - 2));
+    assert_eq!(kinds[4], MultiexpScalarKind::Large);
+    assert_eq!(MultiexpScalarKind::of(&-Fr::ONE), MultiexpScalarKind::Large);
+
+    assert_eq!(
+        best_multiexp_cpu(&coeffs, &bases),
+        small_multiexp(&coeffs, &bases)
+    );
+    for kind in 0..5 {
+        let coeffs: Vec<Fr> = coeffs
+            .iter()
+            .enumerate()
+            .map(|(i, coeff)| if i % 5 == kind { *coeff } else { Fr::ZERO })
+            .collect();
+        assert_eq!(
+            best_multiexp_cpu(&coeffs, &bases),
+            small_multiexp(&coeffs, &bases)
+        );
+    }
+
+    // Below `MULTIEXP_SPLIT_MIN_FRACTION` the few special scalars run with
+    // the large ones.
+    let coeffs: Vec<Fr> = coeffs
+        .iter()
+        .enumerate()
+        .map(|(i, coeff)| if i % 20 == 0 { *coeff } else { Fr::random(rng) })
+        .collect();
+    assert_eq!(
+        best_multiexp_cpu(&coeffs, &bases),
+        small_multiexp(&coeffs, &bases)
+    );
+}
+
+#[test]
+fn test_fft_twiddle_cache() {
+    use crate::halo2curves::bn256::Fr;
+
+    let log_n = 10;
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in log_n..Fr::S {
+        omega = omega.square();
+    }
+
+    let twiddles = fft_twiddles(omega, log_n);
+    assert!(fft_twiddle_cache_bytes() > 0);
+    // The sub-domain of a quarter the size strides the same table.
+    let sub_omega = omega.pow_vartime([4]);
+    let sub_twiddles = fft_twiddles(sub_omega, log_n - 2);
+    assert_eq!(sub_twiddles.stride % 4, 0);
+    for i in 0..1 << (log_n - 3) {
+        assert_eq!(
+            sub_twiddles.table[i * sub_twiddles.stride],
+            sub_omega.pow_vartime([i as u64])
+        );
+        assert_eq!(
+            twiddles.table[i * twiddles.stride],
+            omega.pow_vartime([i as u64])
+        );
+    }
+
+    // The FFT of the sub-domain matches a direct evaluation.
+    let a = (0..1 << (log_n - 2))
+        .map(|_| Fr::random(OsRng))
+        .collect::<Vec<_>>();
+    let mut b = a.clone();
+    best_fft_cpu(&mut b, sub_omega, log_n - 2);
+    for (i, b) in b.iter().enumerate() {
+        assert_eq!(*b, eval_polynomial(&a, sub_omega.pow_vartime([i as u64])));
+    }
+}
+
+#[test]
+fn test_fft_radix4() {
+    use crate::halo2curves::bn256::Fr;
+    use crate::halo2curves::pasta::Eq;
+
+    for log_n in 0..=13 {
+        let mut omega = Fr::ROOT_OF_UNITY;
+        for _ in log_n..Fr::S {
+            omega = omega.square();
+        }
+        let a = (0..1 << log_n)
+            .map(|_| Fr::random(OsRng))
+            .collect::<Vec<_>>();
+        let mut radix2 = a.clone();
+        best_fft_cpu_radix2(&mut radix2, omega, log_n);
+        let mut radix4 = a;
+        best_fft_cpu_radix4(&mut radix4, omega, log_n);
+        assert_eq!(radix2, radix4, "log_n {}", log_n);
+    }
+
+    // Group elements take the same path.
+    let log_n = 5;
+    let mut omega = Fp::ROOT_OF_UNITY;
+    for _ in log_n..Fp::S {
+        omega = omega.square();
+    }
+    let a = (0..1 << log_n)
+        .map(|_| Eq::random(OsRng))
+        .collect::<Vec<_>>();
+    let mut radix2 = a.clone();
+    best_fft_cpu_radix2(&mut radix2, omega, log_n);
+    let mut radix4 = a;
+    best_fft_cpu_radix4(&mut radix4, omega, log_n);
+    assert_eq!(radix2, radix4);
+}
+
+#[test]
+fn test_coset_extend() {
+    let (log_n, extended_log_n) = (4, 6);
+    let zeta = Fp::MULTIPLICATIVE_GENERATOR;
+    let mut omega = Fp::ROOT_OF_UNITY;
+    for _ in extended_log_n..Fp::S {
+        omega = omega.square();
+    }
+
+    let poly = (0..1 << log_n)
+        .map(|_| Fp::random(OsRng))
+        .collect::<Vec<_>>();
+    let values = coset_extend_cpu(&poly, log_n, extended_log_n, zeta);
+    for (i, value) in values.iter().enumerate() {
+        let point = zeta * omega.pow_vartime([i as u64]);
+        assert_eq!(*value, eval_polynomial(&poly, point));
+    }
+    assert_eq!(
+        extended_to_coeff_cpu(&values, log_n, extended_log_n, zeta),
+        poly
+    );
+    // A degree below the extended size also comes back from a smaller size.
+    assert_eq!(
+        extended_to_coeff_cpu(&values, log_n + 1, extended_log_n, zeta)[..1 << log_n],
+        poly[..]
+    );
+}
+
+#[test]
+fn test_pointwise_cpu() {
+    let rng = OsRng;
+    let a = (0..1000).map(|_| Fp::random(rng)).collect::<Vec<_>>();
+    let b = (0..1000).map(|_| Fp::random(rng)).collect::<Vec<_>>();
+    let (c, ratio) = (Fp::random(rng), Fp::random(rng));
+
+    let ops = [
+        PointwiseOp::Add,
+        PointwiseOp::Sub,
+        PointwiseOp::Mul,
+        PointwiseOp::Scale(c),
+        PointwiseOp::AddConstant(c),
+        PointwiseOp::SubConstant(c),
+        PointwiseOp::MulPowers { start: c, ratio },
+    ];
+    for op in ops.iter() {
+        let mut values = a.clone();
+        pointwise_cpu(&mut values, &b, op);
+        let mut power = c;
+        for (i, value) in values.iter().enumerate() {
+            let expected = match op {
+                PointwiseOp::Add => a[i] + b[i],
+                PointwiseOp::Sub => a[i] - b[i],
+                PointwiseOp::Mul => a[i] * b[i],
+                PointwiseOp::Scale(c) => a[i] * c,
+                PointwiseOp::AddConstant(c) => a[i] + c,
+                PointwiseOp::SubConstant(c) => a[i] - c,
+                PointwiseOp::MulPowers { .. } => {
+                    let expected = a[i] * power;
+                    power *= ratio;
+                    expected
+                }
+            };
+            assert_eq!(*value, expected, "{:?}", op);
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use super::*;
+
+use crate::arithmetic::{FixedBaseConfig, PointwiseOp};
+use panda::gpu_manager::unit::*;
+use panda::gpu_manager::wrapper::*;
+use panda::gpu_manager::*;
+use std::sync::Mutex;
+use tracing::info_span;
+
+/// The handle of the params set up on one device by `DeviceBackend::init`.
+#[derive(Clone, Debug)]
+pub struct DeviceHandle {
+    /// The GPU id.
+    pub device_id: usize,
+    /// Backend specific id of the set up params.
+    pub handle_id: usize,
+}
+
+/// The hardware interface used by `DeviceManagerContext`.
+///
+/// All data crosses the interface as byte streams, see `transmute_values`.
+pub trait DeviceBackend: std::fmt::Debug + Send + Sync {
+    /// The number of devices.
+    fn device_number(&self) -> Result<usize, DeviceManagerError>;
+
+    /// Set up the computation units on the device and copy the bases and omega.
+    fn init(
+        &self,
+        device_id: usize,
+        init_device_unit_type: DeviceInitUnitType,
+        bases: Option<&[&[u8]]>,
+        omega: Option<&[u8]>,
+    ) -> Result<DeviceHandle, DeviceManagerError>;
+
+    /// Release the resources of the handle.
+    fn deinit(&self, handle: &DeviceHandle);
+
+    /// MSM of the scalars with the bases at `bases_index`, returned as the
+    /// bytes of a projective point.
+    fn msm(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError>;
+
+    /// Whether the backend implements `msm_offset`, which sharding an MSM
+    /// across the devices needs.
+    fn supports_msm_offset(&self) -> bool {
+        false
+    }
+
+    /// MSM of the scalars with the bases at `bases_index`, starting from the
+    /// `bases_offset`-th base, for the parts of a sharded MSM.
+    fn msm_offset(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+        bases_offset: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        if bases_offset != 0 {
+            return Err(DeviceManagerError::DeviceManagerErrorBasesIndex);
+        }
+        self.msm(handle, scalars, bases_index)
+    }
+
+    /// Whether the backend implements `write_bases`, which streaming the
+    /// bases of an MSM through a device needs.
+    fn supports_write_bases(&self) -> bool {
+        false
+    }
+
+    /// Replace the bases at `bases_index` of the handle.
+    fn write_bases(
+        &self,
+        _handle: &DeviceHandle,
+        _bases_index: usize,
+        _bases: &[u8],
+    ) -> Result<(), DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// The window of the signed digits `msm_signed_digits` takes for an MSM
+    /// of `n` elements, or `None` when the backend takes raw scalars.
+    fn msm_signed_digit_window(&self, _n: usize) -> Option<usize> {
+        None
+    }
+
+    /// MSM of scalars recoded by `recode_signed_digits` in windows of `window`
+    /// bits, `signed_digit_num(256, window)` little-endian `i32` digits per
+    /// scalar, with the bases at `bases_index`.
+    fn msm_signed_digits(
+        &self,
+        _handle: &DeviceHandle,
+        _digits: &[u8],
+        _window: usize,
+        _bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Whether the backend implements `msm_small`. It is used for the MSMs
+    /// whose scalars all fit in `MULTIEXP_SMALL_BITS` bits.
+    fn supports_msm_small(&self) -> bool {
+        false
+    }
+
+    /// MSM of scalars below `2^64`, little-endian `u64` per scalar, with the
+    /// bases at `bases_index`.
+    fn msm_small(
+        &self,
+        _handle: &DeviceHandle,
+        _scalars: &[u8],
+        _bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Whether the backend implements `msm_sparse`. It is used for the MSMs
+    /// with few nonzero scalars, see `DEVICE_MSM_SPARSE_DENSITY`.
+    fn supports_msm_sparse(&self) -> bool {
+        false
+    }
+
+    /// MSM of the nonzero scalars only, with the bases at `bases_index` at
+    /// their indices, a little-endian `u32` each.
+    fn msm_sparse(
+        &self,
+        _handle: &DeviceHandle,
+        _indices: &[u8],
+        _scalars: &[u8],
+        _bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Whether the backend implements `msm_glv`. It is used for the curves
+    /// `glv_encode` decomposes.
+    fn supports_msm_glv(&self) -> bool {
+        false
+    }
+
+    /// MSM of the scalars decomposed by `glv_encode` into `k1 + k2 * lambda`,
+    /// `GLV_SCALAR_BYTES` per scalar, with the bases at `bases_index`.
+    fn msm_glv(
+        &self,
+        _handle: &DeviceHandle,
+        _scalars: &[u8],
+        _bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Offer the backend the fixed-base table of the bases at `bases_index`,
+    /// see `fixed_base_precompute`. Returns whether the backend keeps it for
+    /// the following MSMs of the handle.
+    fn init_fixed_base(
+        &self,
+        _handle: &DeviceHandle,
+        _bases_index: usize,
+        _table: &[u8],
+        _config: &FixedBaseConfig,
+    ) -> Result<bool, DeviceManagerError> {
+        Ok(false)
+    }
+
+    /// NTT of the scalars in place.
+    fn ntt(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError>;
+
+    /// The NTTs of size `2^log_n` of `batch` consecutive parts of the scalars
+    /// in place, with one launch. By default one `ntt` per part.
+    ///
+    /// The scalars must be exactly `batch` parts of `2^log_n` BN254 scalars.
+    fn ntt_batch(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+        batch: usize,
+    ) -> Result<(), DeviceManagerError> {
+        let part_len = std::mem::size_of::<halo2curves::bn256::Fr>() << log_n;
+        if scalars.len() != batch * part_len {
+            return Err(DeviceManagerError::DeviceManagerErrorExecute);
+        }
+        for part in scalars.chunks_mut(part_len) {
+            self.ntt(handle, part, log_n)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the backend implements `coset_extend` and `extended_to_coeff`.
+    fn supports_coset_extend(&self) -> bool {
+        false
+    }
+
+    /// The NTT of size `2^extended_log_n` of the `2^log_n` coefficients padded
+    /// with zeros, the coefficient `i` multiplied by `zeta^i` first, see
+    /// `coset_extend_cpu`.
+    fn coset_extend(
+        &self,
+        _handle: &DeviceHandle,
+        _coeffs: &[u8],
+        _log_n: u32,
+        _extended_log_n: u32,
+        _zeta: &[u8],
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// The inverse of `coset_extend`, the `2^log_n` coefficients of the
+    /// `2^extended_log_n` evaluations, see `extended_to_coeff_cpu`.
+    fn extended_to_coeff(
+        &self,
+        _handle: &DeviceHandle,
+        _values: &[u8],
+        _log_n: u32,
+        _extended_log_n: u32,
+        _zeta: &[u8],
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Whether the backend keeps buffers on the devices, see `DeviceBuffer`.
+    fn supports_buffers(&self) -> bool {
+        false
+    }
+
+    /// Copy the bytes into a new buffer on the device, returning its id.
+    fn alloc_buffer(&self, _device_id: usize, _bytes: &[u8]) -> Result<usize, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Copy the buffer back from the device.
+    fn read_buffer(
+        &self,
+        _device_id: usize,
+        _buffer_id: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Overwrite the buffer with bytes of the same length.
+    fn write_buffer(
+        &self,
+        _device_id: usize,
+        _buffer_id: usize,
+        _bytes: &[u8],
+    ) -> Result<(), DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Free the buffer on the device.
+    fn free_buffer(&self, _device_id: usize, _buffer_id: usize) {}
+
+    /// NTT of the buffer in place with the NTT unit of the handle, or the
+    /// inverse NTT, divided by the size, with `inverse`.
+    fn ntt_buffer(
+        &self,
+        _handle: &DeviceHandle,
+        _buffer_id: usize,
+        _log_n: u32,
+        _inverse: bool,
+    ) -> Result<(), DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// MSM of the scalars in the buffer with the bases at `bases_index`.
+    fn msm_buffer(
+        &self,
+        _handle: &DeviceHandle,
+        _buffer_id: usize,
+        _bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Whether the backend implements `pointwise` and `pointwise_buffer`.
+    fn supports_pointwise(&self) -> bool {
+        false
+    }
+
+    /// The elementwise operation with the field of the NTT unit of the
+    /// handle on the values in place, with `other` for the binary ones, see
+    /// `pointwise_cpu`. The constants of the operation are bytes too.
+    fn pointwise(
+        &self,
+        _handle: &DeviceHandle,
+        _values: &mut [u8],
+        _other: &[u8],
+        _op: &PointwiseOp<Vec<u8>>,
+    ) -> Result<(), DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// `pointwise` on a buffer in place, with the buffer `other_buffer_id` of
+    /// the same device for the binary operations.
+    fn pointwise_buffer(
+        &self,
+        _handle: &DeviceHandle,
+        _buffer_id: usize,
+        _other_buffer_id: Option<usize>,
+        _op: &PointwiseOp<Vec<u8>>,
+    ) -> Result<(), DeviceManagerError> {
+        Err(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Whether the device is busy with work the manager did not schedule.
+    fn is_busy(&self, _device_id: usize) -> bool {
+        false
+    }
+
+    /// The free memory of the device in bytes, `None` if unknown.
+    fn free_memory(&self, _device_id: usize) -> Option<usize> {
+        None
+    }
+}
+
+/// The panda GPU backend.
+#[derive(Debug, Default)]
+pub struct PandaBackend {
+    gms: Mutex<Vec<PandaGpuManager>>,
+}
+
+impl PandaBackend {
+    ///
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn gm(&self, handle: &DeviceHandle) -> Result<PandaGpuManager, DeviceManagerError> {
+        self.gms
+            .lock()
+            .unwrap()
+            .get(handle.handle_id)
+            .cloned()
+            .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)
+    }
+}
+
+impl DeviceBackend for PandaBackend {
+    fn device_number(&self) -> Result<usize, DeviceManagerError> {
+        Ok(panda::gpu_manager::get_device_number()
+            .unwrap()
+            .try_into()
+            .unwrap())
+    }
+
+    fn init(
+        &self,
+        device_id: usize,
+        init_device_unit_type: DeviceInitUnitType,
+        bases: Option<&[&[u8]]>,
+        omega: Option<&[u8]>,
+    ) -> Result<DeviceHandle, DeviceManagerError> {
+        // Mapping initialization of device computation types.
+        let init_uint_type = match init_device_unit_type {
+            DeviceInitUnitType::DeviceInitUnitTypeNone => {
+                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeNone
+            }
+            DeviceInitUnitType::DeviceInitUnitTypeMSM => {
+                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeMSM
+            }
+            DeviceInitUnitType::DeviceInitUnitTypeNTT => {
+                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeNTT
+            }
+            DeviceInitUnitType::DevicerInitUnitTypeALL => {
+                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeALL
+            }
+        };
+
+        // GPU init and get the handle of gpu manager. Setup and copy bases data
+        // on the GPU of the handle, so that every device gets its own copy.
+        let gpu_id = device_id
+            .try_into()
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorExecute)?;
+        let gm = PandaGpuManager::init(gpu_id, init_uint_type, bases, omega)
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorExecute)?;
+
+        let mut gms = self.gms.lock().unwrap();
+        gms.push(gm);
+        Ok(DeviceHandle {
+            device_id,
+            handle_id: gms.len() - 1,
+        })
+    }
+
+    fn deinit(&self, handle: &DeviceHandle) {
+        if let Ok(mut gm) = self.gm(handle) {
+            gm.deinit();
+        }
+    }
+
+    fn msm(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let gm = self.gm(handle)?;
+
+        // Call panda_msm_bn254_gpu and check the result
+        let mut msm_result = panda_msm_bn254_gpu(&gm, scalars, bases_index)
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorExecute)?;
+
+        let _span = info_span!("reduce", buckets = MSM_EXECUTION_RESULT_NUM).entered();
+
+        // Create a vector to hold G1 values with the desired capacity
+        let mut values = Vec::<G1>::with_capacity(MSM_EXECUTION_RESULT_NUM);
+
+        // Get pointers to the vectors' data
+        let values_ptr = values.as_mut_ptr() as *mut u8;
+        let msm_result_ptr = msm_result.as_mut_ptr();
+        let size = std::mem::size_of::<u8>() * msm_result.len();
+
+        // Copy `msm_result` into `values`
+        unsafe {
+            std::ptr::copy_nonoverlapping(msm_result_ptr, values_ptr, size);
+        }
+
+        // Release the ownership of `msm_result`
+        std::mem::forget(msm_result);
+
+        // Set the length of `values` to `count`
+        unsafe { values.set_len(MSM_EXECUTION_RESULT_NUM) };
+
+        let mut sum = G1::zero();
+        let mut running_sum = G1::zero();
+
+        for bucket in values.iter().rev() {
+            running_sum.double();
+            running_sum.add_assign(bucket);
+        }
+        sum.add_assign(&running_sum);
+
+        let mut result_values = vec![0u8; BN256_PROJECTIVE_BYTES];
+        let result_values_ptr = result_values.as_mut_ptr();
+
+        // Copy `sum` into `result_values`
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                &sum as *const G1 as *const u8,
+                result_values_ptr,
+                BN256_PROJECTIVE_BYTES,
+            );
+        }
+
+        // Set the length of `result_values` to `BN256_PROJECTIVE_BYTES`
+        unsafe { result_values.set_len(BN256_PROJECTIVE_BYTES) };
+
+        Ok(result_values)
+    }
+
+    fn ntt(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        let gm = self.gm(handle)?;
+        panda_ntt_bn254_gpu(&gm, scalars, log_n)
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorExecute)
+    }
+}
@@ -0,0 +1,406 @@
+use super::*;
+
+use halo2curves::ff::Field;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tracing::{debug, info_span, warn};
+
+/// The device id of the buffers kept on the host, for backends without
+/// `DeviceBackend::supports_buffers`.
+pub const DEVICE_BUFFER_HOST_ID: usize = usize::MAX;
+
+/// A live buffer of the device manager.
+#[derive(Clone, Debug)]
+pub struct DeviceBufferEntry {
+    ///
+    pub bytes: usize,
+    /// The values of a buffer on the host, see `DEVICE_BUFFER_HOST_ID`.
+    pub host: Option<Vec<u8>>,
+}
+
+/// The live buffers by GPU id and buffer id, shared with the `DeviceBuffer`s
+/// so that they free themselves when dropped.
+pub type DeviceBufferRegistry = Arc<Mutex<HashMap<(usize, usize), DeviceBufferEntry>>>;
+
+/// The next id of a buffer kept on the host, shared by the clones of a
+/// context so that an id is never handed out twice.
+pub type DeviceHostBufferIds = Arc<AtomicUsize>;
+
+/// A vector of `len` values of `F` kept on one device between operations,
+/// created by `upload_buffer`. It is only copied back by `download_buffer`,
+/// and freed when dropped.
+#[derive(Debug)]
+pub struct DeviceBuffer<F> {
+    /// The GPU id, or `DEVICE_BUFFER_HOST_ID`.
+    pub device_id: usize,
+    /// Backend specific id of the buffer.
+    pub buffer_id: usize,
+    ///
+    pub len: usize,
+    backend: Arc<dyn DeviceBackend>,
+    jobs_in_flight: Arc<Mutex<HashMap<usize, usize>>>,
+    device_workers: DeviceWorkers,
+    registry: DeviceBufferRegistry,
+    _marker: PhantomData<F>,
+}
+
+impl<F> DeviceBuffer<F> {
+    /// Whether the buffer is kept on the host.
+    pub fn is_host(&self) -> bool {
+        self.device_id == DEVICE_BUFFER_HOST_ID
+    }
+}
+
+impl<F> Drop for DeviceBuffer<F> {
+    fn drop(&mut self) {
+        // Already freed if the device manager was deinitialized.
+        let entry = match self.registry.lock() {
+            Ok(mut buffers) => buffers.remove(&(self.device_id, self.buffer_id)),
+            Err(_) => None,
+        };
+        if let Some(DeviceBufferEntry { host: None, .. }) = entry {
+            // Behind the calls of the device still using the buffer, without
+            // waiting for it.
+            let (device_id, buffer_id) = (self.device_id, self.buffer_id);
+            spawn_device_worker_job(
+                &self.backend,
+                &self.jobs_in_flight,
+                &self.device_workers,
+                device_id,
+                DeviceWorkerLane::Compute,
+                move |backend| {
+                    backend.free_buffer(device_id, buffer_id);
+                    Ok(())
+                },
+            );
+        }
+    }
+}
+
+impl DeviceManagerContext {
+    /// Copy the values to a new buffer on the ready NTT device with the
+    /// fewest buffer bytes. Without backend support the buffer is kept on the
+    /// host, and its operations run like their slice versions.
+    pub fn upload_buffer<F: Copy>(
+        &mut self,
+        values: &[F],
+    ) -> Result<DeviceBuffer<F>, DeviceManagerError> {
+        if !self.backend.supports_buffers() {
+            return self.upload_buffer_to(DEVICE_BUFFER_HOST_ID, values);
+        }
+        self.probe_quarantined_devices();
+        let device_id = self
+            .ready_ntt_device_ids()
+            .into_iter()
+            .min_by_key(|device_id| self.buffer_bytes(*device_id))
+            .ok_or(DeviceManagerError::DeviceManagerErrorNoAvailableDevice)?;
+        self.upload_buffer_to(device_id, values)
+    }
+
+    /// Copy the values to a new buffer on the device.
+    pub fn upload_buffer_to<F: Copy>(
+        &mut self,
+        device_id: usize,
+        values: &[F],
+    ) -> Result<DeviceBuffer<F>, DeviceManagerError> {
+        let _span = info_span!(
+            "device_manager.upload_buffer",
+            device_id,
+            len = values.len()
+        )
+        .entered();
+        let bytes = transmute_values(values).to_vec();
+        let bytes_len = bytes.len();
+        let (buffer_id, host) = if device_id == DEVICE_BUFFER_HOST_ID {
+            let buffer_id = self.host_buffer_ids.fetch_add(1, Ordering::Relaxed);
+            (buffer_id, Some(bytes))
+        } else {
+            let buffer_id = self.run_buffer_job(
+                device_id,
+                DeviceUnitType::DeviceUnitTypeNTT,
+                move |backend| backend.alloc_buffer(device_id, &bytes),
+            )?;
+            self.metrics
+                .record_bytes(DeviceUnitType::DeviceUnitTypeNTT, bytes_len, 0);
+            (buffer_id, None)
+        };
+        self.buffers()?.insert(
+            (device_id, buffer_id),
+            DeviceBufferEntry {
+                bytes: bytes_len,
+                host,
+            },
+        );
+
+        Ok(DeviceBuffer {
+            device_id,
+            buffer_id,
+            len: values.len(),
+            backend: self.backend.clone(),
+            jobs_in_flight: self.jobs_in_flight.clone(),
+            device_workers: self.device_workers.clone(),
+            registry: self.device_buffers.clone(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Copy the values of the buffer back from the device.
+    pub fn download_buffer<F: Copy>(
+        &mut self,
+        buffer: &DeviceBuffer<F>,
+    ) -> Result<Vec<F>, DeviceManagerError> {
+        let _span = info_span!(
+            "device_manager.download_buffer",
+            device_id = buffer.device_id,
+            len = buffer.len
+        )
+        .entered();
+        if buffer.is_host() {
+            return values_from_bytes(&self.host_buffer(buffer)?);
+        }
+        let (device_id, buffer_id) = (buffer.device_id, buffer.buffer_id);
+        let read = move |backend: &dyn DeviceBackend| backend.read_buffer(device_id, buffer_id);
+        let bytes = if self.quarantined_device_ids().contains(&device_id) {
+            // The device runs no more jobs, but its data can still be saved.
+            let limits = self.job_limits(&DeviceJobOptions::default());
+            self.run_device_job(device_id, &limits, read)?
+        } else {
+            self.run_buffer_job(device_id, DeviceUnitType::DeviceUnitTypeNTT, read)?
+        };
+        self.metrics
+            .record_bytes(DeviceUnitType::DeviceUnitTypeNTT, 0, bytes.len());
+        values_from_bytes(&bytes)
+    }
+
+    /// Free the buffer on its device like dropping it, but waiting for the
+    /// device to free it.
+    pub fn free_buffer<F>(&mut self, buffer: DeviceBuffer<F>) {
+        let entry = self
+            .buffers()
+            .ok()
+            .and_then(|mut buffers| buffers.remove(&(buffer.device_id, buffer.buffer_id)));
+        if let Some(DeviceBufferEntry { host: None, .. }) = entry {
+            let (device_id, buffer_id) = (buffer.device_id, buffer.buffer_id);
+            let limits = self.job_limits(&DeviceJobOptions::default());
+            let result = self.run_device_job(device_id, &limits, move |backend| {
+                backend.free_buffer(device_id, buffer_id);
+                Ok(())
+            });
+            if let Err(error) = result {
+                warn!(device_id, error = error.kind(), "Device buffer free failed");
+            }
+        }
+    }
+
+    /// The bytes of the live buffers on the device.
+    pub fn buffer_bytes(&self, device_id: usize) -> usize {
+        self.device_buffers
+            .lock()
+            .map(|buffers| {
+                buffers
+                    .iter()
+                    .filter(|((id, _), _)| *id == device_id)
+                    .map(|(_, entry)| entry.bytes)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// The live buffers.
+    pub(crate) fn buffers(
+        &self,
+    ) -> Result<MutexGuard<'_, HashMap<(usize, usize), DeviceBufferEntry>>, DeviceManagerError>
+    {
+        self.device_buffers
+            .lock()
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// The bytes of a buffer kept on the host.
+    pub(crate) fn host_buffer<F>(
+        &self,
+        buffer: &DeviceBuffer<F>,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        self.buffers()?
+            .get(&(buffer.device_id, buffer.buffer_id))
+            .and_then(|entry| entry.host.clone())
+            .ok_or(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    /// Replace the values of a buffer kept on the host.
+    pub(crate) fn set_host_buffer<F>(
+        &self,
+        buffer: &DeviceBuffer<F>,
+        values: &[F],
+    ) -> Result<(), DeviceManagerError> {
+        let bytes = transmute_values(values).to_vec();
+        match self
+            .buffers()?
+            .get_mut(&(buffer.device_id, buffer.buffer_id))
+        {
+            Some(DeviceBufferEntry {
+                host: Some(host), ..
+            }) if host.len() == bytes.len() => {
+                *host = bytes;
+                Ok(())
+            }
+            _ => Err(DeviceManagerError::DeviceManagerErrorExecute),
+        }
+    }
+
+    /// NTT of the buffer in place on its device, with the root of unity the
+    /// device has for the size.
+    pub fn execute_ntt_buffer<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        buffer: &DeviceBuffer<G>,
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        self.run_ntt_buffer::<Scalar, G>(buffer, log_n, false)
+    }
+
+    /// Inverse NTT of the buffer in place on its device, divided by the size.
+    pub fn execute_intt_buffer<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        buffer: &DeviceBuffer<G>,
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        self.run_ntt_buffer::<Scalar, G>(buffer, log_n, true)
+    }
+
+    fn run_ntt_buffer<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        buffer: &DeviceBuffer<G>,
+        log_n: u32,
+        inverse: bool,
+    ) -> Result<(), DeviceManagerError> {
+        assert_eq!(buffer.len, 1 << log_n);
+        let _span = info_span!(
+            "device_manager.execute_ntt_buffer",
+            device_id = buffer.device_id,
+            log_n,
+            inverse
+        )
+        .entered();
+        if buffer.is_host() {
+            let mut values = values_from_bytes::<G>(&self.host_buffer(buffer)?)?;
+            self.execute_ntt::<Scalar, G>(&mut values, log_n)?;
+            if inverse {
+                // The inverse NTT is the forward one with the outputs but the
+                // first reversed, divided by the size.
+                values[1..].reverse();
+                let mut n = Scalar::ONE;
+                for _ in 0..log_n {
+                    n = n.double();
+                }
+                let n_inv = n.invert().unwrap();
+                for value in values.iter_mut() {
+                    *value = *value * n_inv;
+                }
+            }
+            return self.set_host_buffer(buffer, &values);
+        }
+        let handle = self
+            .ntt_param_uints
+            .iter()
+            .find(|unit| unit.device_id == buffer.device_id)
+            .map(|unit| unit.handle.clone())
+            .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)?;
+        let buffer_id = buffer.buffer_id;
+        self.run_buffer_job(
+            buffer.device_id,
+            DeviceUnitType::DeviceUnitTypeNTT,
+            move |backend| backend.ntt_buffer(&handle, buffer_id, log_n, inverse),
+        )
+    }
+
+    /// MSM of the scalars in the buffer with the bases of the param on the
+    /// device of the buffer.
+    pub fn execute_msm_buffer<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases_index: usize,
+        buffer: &DeviceBuffer<C::Scalar>,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let _span = info_span!(
+            "device_manager.execute_msm_buffer",
+            device_id = buffer.device_id,
+            param_id = msm_param_id,
+            bases_index,
+            scalars_num = buffer.len
+        )
+        .entered();
+        if buffer.is_host() {
+            let scalars = values_from_bytes::<C::Scalar>(&self.host_buffer(buffer)?)?;
+            return self.execute_msm::<C>(msm_param_id, bases_index, &scalars);
+        }
+        if self.is_msm_param_streamed(msm_param_id) {
+            return Err(DeviceManagerError::DeviceManagerErrorOutOfMemory);
+        }
+        let handle = self
+            .msm_param_uints
+            .iter()
+            .find(|unit| unit.param_id == msm_param_id && unit.device_id == buffer.device_id)
+            .map(|unit| unit.handle.clone())
+            .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)?;
+        let buffer_id = buffer.buffer_id;
+        let start = Instant::now();
+        let result = self.run_buffer_job(
+            buffer.device_id,
+            DeviceUnitType::DeviceUnitTypeMSM,
+            move |backend| backend.msm_buffer(&handle, buffer_id, bases_index),
+        )?;
+        self.record_msm_throughput(buffer.device_id, buffer.len, start.elapsed());
+        Ok(result)
+    }
+
+    /// Run the buffer call on the device, which must be ready. The data stays
+    /// on the device, so a failure is not retried elsewhere.
+    pub(crate) fn run_buffer_job<T, F>(
+        &mut self,
+        device_id: usize,
+        device_unit_type: DeviceUnitType,
+        job: F,
+    ) -> Result<T, DeviceManagerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn DeviceBackend) -> Result<T, DeviceManagerError> + Send + 'static,
+    {
+        let index = self
+            .devices
+            .iter()
+            .position(|device| {
+                device.device_id == device_id
+                    && device.device_status == DeviceStatusType::DeviceStatusReady
+            })
+            .ok_or(DeviceManagerError::DeviceManagerErrorNoAvailableDevice)?;
+        let limits = self.job_limits(&DeviceJobOptions::default());
+        self.metrics.record_job(device_unit_type, device_id);
+
+        self.devices[index].device_status = DeviceStatusType::DeviceStatusRunning;
+        let start = Instant::now();
+        let result = self.run_device_job(device_id, &limits, job);
+        self.devices[index].device_status = DeviceStatusType::DeviceStatusReady;
+
+        match result {
+            Ok(value) => {
+                self.metrics
+                    .record_compute(device_unit_type, start.elapsed());
+                self.record_device_success(device_id);
+                self.last_device_id = Some(device_id);
+                debug!(device_id, "Device buffer job done");
+                Ok(value)
+            }
+            Err(error) => {
+                warn!(device_id, error = error.kind(), "Device buffer job failed");
+                if let Some(error) = self.job_limit_error(error.clone(), device_id) {
+                    return Err(error);
+                }
+                self.record_job_failure(device_id, &error);
+                Err(error)
+            }
+        }
+    }
+}
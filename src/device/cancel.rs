@@ -0,0 +1,309 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How often a waiting job checks its cancellation token.
+const DEVICE_JOB_CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Cancels the device jobs it was passed with, shared by cloning.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceCancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl DeviceCancellationToken {
+    ///
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    ///
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Per-call options of the `execute_*_with_options` functions and of
+/// `DeviceJobQueue::acquire_with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceJobOptions {
+    /// Overrides the default job timeout of the device manager.
+    pub timeout: Option<Duration>,
+    ///
+    pub cancel: Option<DeviceCancellationToken>,
+}
+
+/// The deadline and cancellation token of a call, shared by its retries.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceJobLimits {
+    ///
+    pub deadline: Option<Instant>,
+    ///
+    pub cancel: Option<DeviceCancellationToken>,
+}
+
+impl DeviceJobLimits {
+    ///
+    pub fn new(timeout: Option<Duration>, cancel: Option<DeviceCancellationToken>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancel,
+        }
+    }
+
+    /// Fail if the call was cancelled or is past its deadline.
+    pub fn check(&self) -> Result<(), DeviceManagerError> {
+        if self
+            .cancel
+            .as_ref()
+            .map_or(false, |cancel| cancel.is_cancelled())
+        {
+            return Err(DeviceManagerError::DeviceManagerErrorCancelled);
+        }
+        if self
+            .deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+        {
+            return Err(DeviceManagerError::DeviceManagerErrorTimeout);
+        }
+        Ok(())
+    }
+
+    /// How long to wait before checking again, `None` to wait forever.
+    pub fn wait_interval(&self) -> Option<Duration> {
+        let remaining = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (&self.cancel, remaining) {
+            (Some(_), Some(remaining)) => Some(remaining.min(DEVICE_JOB_CANCEL_POLL_INTERVAL)),
+            (Some(_), None) => Some(DEVICE_JOB_CANCEL_POLL_INTERVAL),
+            (None, remaining) => remaining,
+        }
+    }
+}
+
+/// The worker threads of a device, so that an upload can overlap the
+/// computation like on the copy and compute engines of a GPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceWorkerLane {
+    ///
+    Compute,
+    /// Uploads of data for later calls, see `execute_msm_streaming`.
+    Copy,
+}
+
+/// A backend call sent to a device worker.
+pub type DeviceWorkerJob = Box<dyn FnOnce() + Send>;
+
+/// The channels of the device worker threads by GPU id and lane. A worker
+/// runs the calls sent to it one after the other, and exits with its last
+/// sender.
+pub type DeviceWorkers =
+    Arc<Mutex<HashMap<(usize, DeviceWorkerLane), mpsc::Sender<DeviceWorkerJob>>>>;
+
+fn spawn_device_worker() -> mpsc::Sender<DeviceWorkerJob> {
+    let (sender, receiver) = mpsc::channel::<DeviceWorkerJob>();
+    std::thread::spawn(move || {
+        for job in receiver {
+            // A panicking call fails its own job only, see `DeviceJobPending::wait`.
+            let _ = std::panic::catch_unwind(AssertUnwindSafe(job));
+        }
+    });
+    sender
+}
+
+/// Counts the jobs of a device in flight, including abandoned ones.
+struct DeviceJobInFlight {
+    jobs_in_flight: Arc<Mutex<HashMap<usize, usize>>>,
+    device_id: usize,
+}
+
+impl DeviceJobInFlight {
+    fn new(jobs_in_flight: Arc<Mutex<HashMap<usize, usize>>>, device_id: usize) -> Self {
+        *jobs_in_flight.lock().unwrap().entry(device_id).or_insert(0) += 1;
+        Self {
+            jobs_in_flight,
+            device_id,
+        }
+    }
+}
+
+impl Drop for DeviceJobInFlight {
+    fn drop(&mut self) {
+        let mut jobs_in_flight = self.jobs_in_flight.lock().unwrap();
+        if let Some(count) = jobs_in_flight.get_mut(&self.device_id) {
+            *count -= 1;
+            if *count == 0 {
+                jobs_in_flight.remove(&self.device_id);
+            }
+        }
+    }
+}
+
+impl DeviceManagerContext {
+    /// The timeout of every execute call without one of its own, `None` to
+    /// wait for the devices forever.
+    pub fn set_job_timeout(&mut self, job_timeout: Option<Duration>) {
+        self.job_timeout = job_timeout;
+    }
+
+    /// The limits of a call with the options, starting now.
+    pub fn job_limits(&self, options: &DeviceJobOptions) -> DeviceJobLimits {
+        DeviceJobLimits::new(options.timeout.or(self.job_timeout), options.cancel.clone())
+    }
+
+    /// Whether the device is busy with work of its own or with a job that
+    /// was abandoned and has not finished yet.
+    pub fn is_device_busy(&self, device_id: usize) -> bool {
+        self.backend.is_busy(device_id)
+            || self.jobs_in_flight.lock().unwrap().contains_key(&device_id)
+    }
+
+    /// Run the backend call on the worker thread of the device and wait for
+    /// it within the limits.
+    ///
+    /// A call that times out or is cancelled is abandoned: the caller gets the
+    /// error at once, and the device is not scheduled again until the call
+    /// returns.
+    pub fn run_device_job<T, F>(
+        &mut self,
+        device_id: usize,
+        limits: &DeviceJobLimits,
+        job: F,
+    ) -> Result<T, DeviceManagerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn DeviceBackend) -> Result<T, DeviceManagerError> + Send + 'static,
+    {
+        limits.check()?;
+        let pending = self.spawn_device_job(device_id, job);
+        pending.wait(limits)
+    }
+
+    /// Start the backend call on the worker thread of the device without
+    /// waiting for it, so that several devices can run at once. The calls of
+    /// one device run in order.
+    pub fn spawn_device_job<T, F>(&self, device_id: usize, job: F) -> DeviceJobPending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn DeviceBackend) -> Result<T, DeviceManagerError> + Send + 'static,
+    {
+        self.spawn_device_job_on(device_id, DeviceWorkerLane::Compute, job)
+    }
+
+    /// `spawn_device_job` on a lane of the device.
+    pub fn spawn_device_job_on<T, F>(
+        &self,
+        device_id: usize,
+        lane: DeviceWorkerLane,
+        job: F,
+    ) -> DeviceJobPending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn DeviceBackend) -> Result<T, DeviceManagerError> + Send + 'static,
+    {
+        spawn_device_worker_job(
+            &self.backend,
+            &self.jobs_in_flight,
+            &self.device_workers,
+            device_id,
+            lane,
+            job,
+        )
+    }
+}
+
+/// `spawn_device_job_on` with the parts of the context it uses, for the
+/// callers without one, such as a dropped `DeviceBuffer`.
+pub(crate) fn spawn_device_worker_job<T, F>(
+    backend: &Arc<dyn DeviceBackend>,
+    jobs_in_flight: &Arc<Mutex<HashMap<usize, usize>>>,
+    device_workers: &DeviceWorkers,
+    device_id: usize,
+    lane: DeviceWorkerLane,
+    job: F,
+) -> DeviceJobPending<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn DeviceBackend) -> Result<T, DeviceManagerError> + Send + 'static,
+{
+    let backend = backend.clone();
+    let in_flight = DeviceJobInFlight::new(jobs_in_flight.clone(), device_id);
+    let (sender, receiver) = mpsc::channel();
+    let mut work: DeviceWorkerJob = Box::new(move || {
+        let result = job(backend.as_ref());
+        // Free the device before the caller can schedule it again.
+        drop(in_flight);
+        let _ = sender.send(result);
+    });
+
+    let mut workers = device_workers.lock().unwrap();
+    loop {
+        let worker = workers
+            .entry((device_id, lane))
+            .or_insert_with(spawn_device_worker);
+        match worker.send(work) {
+            Ok(()) => break,
+            // The worker is gone, start another one.
+            Err(mpsc::SendError(returned)) => {
+                workers.remove(&(device_id, lane));
+                work = returned;
+            }
+        }
+    }
+
+    DeviceJobPending {
+        device_id,
+        receiver,
+    }
+}
+
+/// A backend call started by `spawn_device_job`.
+#[derive(Debug)]
+pub struct DeviceJobPending<T> {
+    device_id: usize,
+    receiver: mpsc::Receiver<Result<T, DeviceManagerError>>,
+}
+
+impl<T> DeviceJobPending<T> {
+    /// Wait for the call within the limits, abandoning it past them.
+    pub fn wait(self, limits: &DeviceJobLimits) -> Result<T, DeviceManagerError> {
+        loop {
+            let received = match limits.wait_interval() {
+                Some(interval) => self.receiver.recv_timeout(interval),
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Timeout) => {}
+                // The backend call panicked.
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(DeviceManagerError::DeviceManagerErrorExecute)
+                }
+            }
+
+            if let Err(error) = limits.check() {
+                warn!(
+                    device_id = self.device_id,
+                    error = error.kind(),
+                    "Device job abandoned"
+                );
+                return Err(error);
+            }
+        }
+    }
+}
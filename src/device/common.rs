@@ -1,5 +1,7 @@
+use super::*;
 use libc::c_void;
 use panda::gpu_manager::*;
+use std::time::Instant;
 use std::{mem, ptr};
 
 /// GPU model
@@ -29,7 +31,7 @@ pub enum DeviceType {
 }
 
 /// The type of computing that is being performed on the device.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DeviceUnitType {
     ///
     DeviceUnitTypeNone,
@@ -37,10 +39,24 @@ pub enum DeviceUnitType {
     DeviceUnitTypeMSM,
     ///
     DeviceUnitTypeNTT,
+    /// Elementwise operations, see `PointwiseOp`.
+    DeviceUnitTypePointwise,
+}
+
+impl DeviceUnitType {
+    /// Short name used in cost model files and metric labels.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceUnitType::DeviceUnitTypeNone => "none",
+            DeviceUnitType::DeviceUnitTypeMSM => "msm",
+            DeviceUnitType::DeviceUnitTypeNTT => "ntt",
+            DeviceUnitType::DeviceUnitTypePointwise => "pointwise",
+        }
+    }
 }
 
 /// Current device status
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DeviceStatusType {
     ///
     DeviceStatusNone,
@@ -50,6 +66,8 @@ pub enum DeviceStatusType {
     DeviceStatusReady,
     ///
     DeviceStatusRunning,
+    /// Quarantined after repeated failures, until it passes a self-test.
+    DeviceStatusError,
 }
 
 /// The type of computation initialized on the device.
@@ -76,6 +94,10 @@ pub struct DeviceUnit {
     pub device_unit_type: DeviceUnitType,
     ///
     pub device_status: DeviceStatusType,
+    /// Failures since the last successful job.
+    pub consecutive_failures: usize,
+    /// When the device was quarantined or last failed its self-test.
+    pub quarantined_at: Option<Instant>,
 }
 
 /// Device info of GPU.
@@ -102,22 +124,26 @@ pub struct MSMParamUnit {
     ///
     pub param_id: usize,
     ///
+    pub device_id: usize,
+    ///
     pub in_usze: bool,
     ///
     pub init_flag: bool,
     ///
-    pub gm: PandaGpuManager,
+    pub handle: DeviceHandle,
 }
 
 /// NTT param unit as multiple circuits require multiple params.
 #[derive(Clone, Debug)]
 pub struct NTTParamUnit {
+    ///
+    pub device_id: usize,
     ///
     pub in_usze: bool,
     ///
     pub init_flag: bool,
     ///
-    pub gm: PandaGpuManager,
+    pub handle: DeviceHandle,
 }
 
 /// The error type of device manager.
@@ -135,4 +161,63 @@ pub enum DeviceManagerError {
     DeviceManagerSetDeviceError,
     ///
     DeviceManagerErrorGetDeviceInfo,
-}
\ No newline at end of file
+    /// The cost model file could not be read or written.
+    DeviceManagerErrorCostModelIO,
+    /// The device failed to run the job.
+    DeviceManagerErrorExecute,
+    /// The device returned a result that failed verification.
+    DeviceManagerErrorVerification,
+    /// The connection to the device daemon failed or broke the protocol.
+    DeviceManagerErrorDaemonIO,
+    /// The job queue is at its maximum depth.
+    DeviceManagerErrorQueueFull,
+    /// The job did not finish within its timeout.
+    DeviceManagerErrorTimeout,
+    /// The job was cancelled with its `DeviceCancellationToken`.
+    DeviceManagerErrorCancelled,
+    /// The job does not fit into the memory of the device.
+    DeviceManagerErrorOutOfMemory,
+}
+impl DeviceManagerError {
+    /// The name of the error kind, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DeviceManagerError::DeviceManagerErrorGetDeviceNum => "get_device_num",
+            DeviceManagerError::DeviceManagerErrorNoAvailableDevice => "no_available_device",
+            DeviceManagerError::DeviceManagerErrorBasesIndex => "bases_index",
+            DeviceManagerError::DeviceManagerErrorParamIdNone => "param_id_none",
+            DeviceManagerError::DeviceManagerSetDeviceError => "set_device",
+            DeviceManagerError::DeviceManagerErrorGetDeviceInfo => "get_device_info",
+            DeviceManagerError::DeviceManagerErrorCostModelIO => "cost_model_io",
+            DeviceManagerError::DeviceManagerErrorExecute => "execute",
+            DeviceManagerError::DeviceManagerErrorVerification => "verification",
+            DeviceManagerError::DeviceManagerErrorDaemonIO => "daemon_io",
+            DeviceManagerError::DeviceManagerErrorQueueFull => "queue_full",
+            DeviceManagerError::DeviceManagerErrorTimeout => "timeout",
+            DeviceManagerError::DeviceManagerErrorCancelled => "cancelled",
+            DeviceManagerError::DeviceManagerErrorOutOfMemory => "out_of_memory",
+        }
+    }
+
+    /// The error of the kind name, see `kind`.
+    pub fn from_kind(kind: &str) -> Option<Self> {
+        [
+            DeviceManagerError::DeviceManagerErrorGetDeviceNum,
+            DeviceManagerError::DeviceManagerErrorNoAvailableDevice,
+            DeviceManagerError::DeviceManagerErrorBasesIndex,
+            DeviceManagerError::DeviceManagerErrorParamIdNone,
+            DeviceManagerError::DeviceManagerSetDeviceError,
+            DeviceManagerError::DeviceManagerErrorGetDeviceInfo,
+            DeviceManagerError::DeviceManagerErrorCostModelIO,
+            DeviceManagerError::DeviceManagerErrorExecute,
+            DeviceManagerError::DeviceManagerErrorVerification,
+            DeviceManagerError::DeviceManagerErrorDaemonIO,
+            DeviceManagerError::DeviceManagerErrorQueueFull,
+            DeviceManagerError::DeviceManagerErrorTimeout,
+            DeviceManagerError::DeviceManagerErrorCancelled,
+            DeviceManagerError::DeviceManagerErrorOutOfMemory,
+        ]
+        .into_iter()
+        .find(|error| error.kind() == kind)
+    }
+}
@@ -0,0 +1,163 @@
+use super::*;
+
+use crate::arithmetic::{coset_scale_padded, coset_unscale_truncated};
+use halo2curves::ff::Field;
+use std::time::Instant;
+use tracing::info_span;
+
+impl DeviceManagerContext {
+    /// The evaluations of the `2^log_n` coefficients `poly` on the coset
+    /// `zeta` of the domain of `2^extended_log_n` elements, with the standard
+    /// root of unity of the devices.
+    ///
+    /// A backend with `supports_coset_extend` pads, scales and transforms in
+    /// one call. Others get the padded and scaled coefficients from the host
+    /// through `execute_ntt`.
+    pub fn execute_coset_extend<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        poly: &[G],
+        log_n: u32,
+        extended_log_n: u32,
+        zeta: Scalar,
+    ) -> Result<Vec<G>, DeviceManagerError> {
+        self.execute_coset_extend_with_options::<Scalar, G>(
+            poly,
+            log_n,
+            extended_log_n,
+            zeta,
+            &DeviceJobOptions::default(),
+        )
+    }
+
+    /// `execute_coset_extend` with a timeout and cancellation token.
+    pub fn execute_coset_extend_with_options<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        poly: &[G],
+        log_n: u32,
+        extended_log_n: u32,
+        zeta: Scalar,
+        options: &DeviceJobOptions,
+    ) -> Result<Vec<G>, DeviceManagerError> {
+        assert_eq!(poly.len(), 1 << log_n);
+        assert!(log_n <= extended_log_n);
+        if !self.backend.supports_coset_extend() {
+            let mut values = coset_scale_padded(poly, extended_log_n, zeta);
+            self.execute_ntt_with_options::<Scalar, G>(&mut values, extended_log_n, options)?;
+            return Ok(values);
+        }
+
+        let limits = self.job_limits(options);
+        let span = info_span!(
+            "device_manager.execute_coset_extend",
+            log_n,
+            extended_log_n,
+            device_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        self.probe_quarantined_devices();
+
+        let coeffs = transmute_values(poly).to_vec();
+        let zeta = transmute_values(&[zeta]).to_vec();
+        let result = self.run_ntt_job(
+            DeviceUnitType::DeviceUnitTypeNTT,
+            1 << extended_log_n,
+            &span,
+            |context, handle| {
+                let (coeffs, zeta, handle) = (coeffs.clone(), zeta.clone(), handle.clone());
+                context.session_coset(handle.device_id, coeffs.len(), &limits, move |backend| {
+                    backend.coset_extend(&handle, &coeffs, log_n, extended_log_n, &zeta)
+                })
+            },
+        )?;
+        values_from_bytes(&result)
+    }
+
+    /// The `2^log_n` coefficients of a polynomial from its `2^extended_log_n`
+    /// evaluations on the coset `zeta`, the inverse of
+    /// `execute_coset_extend`.
+    ///
+    /// A backend with `supports_coset_extend` transforms, scales and
+    /// truncates in one call. Others run the forward NTT with `execute_ntt`
+    /// and the host reverses, scales and truncates its output.
+    pub fn execute_extended_to_coeff<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        values: &[G],
+        log_n: u32,
+        extended_log_n: u32,
+        zeta: Scalar,
+    ) -> Result<Vec<G>, DeviceManagerError> {
+        self.execute_extended_to_coeff_with_options::<Scalar, G>(
+            values,
+            log_n,
+            extended_log_n,
+            zeta,
+            &DeviceJobOptions::default(),
+        )
+    }
+
+    /// `execute_extended_to_coeff` with a timeout and cancellation token.
+    pub fn execute_extended_to_coeff_with_options<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        values: &[G],
+        log_n: u32,
+        extended_log_n: u32,
+        zeta: Scalar,
+        options: &DeviceJobOptions,
+    ) -> Result<Vec<G>, DeviceManagerError> {
+        assert_eq!(values.len(), 1 << extended_log_n);
+        assert!(log_n <= extended_log_n);
+        if !self.backend.supports_coset_extend() {
+            let mut transformed = values.to_vec();
+            self.execute_ntt_with_options::<Scalar, G>(&mut transformed, extended_log_n, options)?;
+            return Ok(coset_unscale_truncated(
+                &transformed,
+                log_n,
+                extended_log_n,
+                zeta,
+            ));
+        }
+
+        let limits = self.job_limits(options);
+        let span = info_span!(
+            "device_manager.execute_extended_to_coeff",
+            log_n,
+            extended_log_n,
+            device_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        self.probe_quarantined_devices();
+
+        let values = transmute_values(values).to_vec();
+        let zeta = transmute_values(&[zeta]).to_vec();
+        let result = self.run_ntt_job(
+            DeviceUnitType::DeviceUnitTypeNTT,
+            values.len(),
+            &span,
+            |context, handle| {
+                let (values, zeta, handle) = (values.clone(), zeta.clone(), handle.clone());
+                context.session_coset(handle.device_id, values.len(), &limits, move |backend| {
+                    backend.extended_to_coeff(&handle, &values, log_n, extended_log_n, &zeta)
+                })
+            },
+        )?;
+        values_from_bytes(&result)
+    }
+
+    /// Run a fused coset call on the device, recording it as an NTT.
+    fn session_coset(
+        &mut self,
+        device_id: usize,
+        bytes: usize,
+        limits: &DeviceJobLimits,
+        job: impl FnOnce(&dyn DeviceBackend) -> Result<Vec<u8>, DeviceManagerError> + Send + 'static,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let _span = info_span!("compute", bytes).entered();
+        let compute_start = Instant::now();
+        let result = self.run_device_job(device_id, limits, job)?;
+        self.metrics
+            .record_compute(DeviceUnitType::DeviceUnitTypeNTT, compute_start.elapsed());
+        self.metrics
+            .record_bytes(DeviceUnitType::DeviceUnitTypeNTT, bytes, result.len());
+        Ok(result)
+    }
+}
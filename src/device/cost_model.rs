@@ -0,0 +1,429 @@
+use super::*;
+
+use crate::arithmetic::{best_fft_cpu, best_multiexp_cpu};
+use halo2curves::ff::Field;
+use rand_core::OsRng;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// The device id used in the cost model for the host CPU.
+pub const COST_MODEL_CPU_DEVICE: usize = usize::MAX;
+
+const COST_MODEL_FILE_HEADER: &str = "# halo2 device cost model v1";
+
+/// The timing of one calibration run.
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceCostSample {
+    ///
+    pub log_n: u32,
+    ///
+    pub secs: f64,
+}
+
+/// The cost of one computation unit on one device, `latency + n * secs_per_element`.
+#[derive(Clone, Debug)]
+pub struct DeviceCostEntry {
+    /// The GPU id, or `COST_MODEL_CPU_DEVICE` for the CPU.
+    pub device_id: usize,
+    ///
+    pub device_unit_type: DeviceUnitType,
+    /// Fixed cost of a call in seconds, including transfers setup and launch.
+    pub latency: f64,
+    /// Cost of one element in seconds, the inverse of the throughput.
+    pub secs_per_element: f64,
+}
+
+impl DeviceCostEntry {
+    /// Fit the latency and throughput to the samples by least squares.
+    pub fn fit(
+        device_id: usize,
+        device_unit_type: DeviceUnitType,
+        samples: &[DeviceCostSample],
+    ) -> Self {
+        let count = samples.len() as f64;
+        let (mut sum_x, mut sum_y, mut sum_xx, mut sum_xy) = (0f64, 0f64, 0f64, 0f64);
+        for sample in samples.iter() {
+            let x = (1u64 << sample.log_n) as f64;
+            sum_x += x;
+            sum_y += sample.secs;
+            sum_xx += x * x;
+            sum_xy += x * sample.secs;
+        }
+
+        let denom = count * sum_xx - sum_x * sum_x;
+        let (latency, secs_per_element) = if samples.is_empty() {
+            (0f64, 0f64)
+        } else if denom == 0f64 {
+            // A single size was measured, so all of the cost is per element.
+            (0f64, sum_y / sum_x)
+        } else {
+            let slope = (count * sum_xy - sum_x * sum_y) / denom;
+            let intercept = (sum_y - slope * sum_x) / count;
+            (intercept.max(0f64), slope.max(0f64))
+        };
+
+        DeviceCostEntry {
+            device_id,
+            device_unit_type,
+            latency,
+            secs_per_element,
+        }
+    }
+
+    /// The estimated time in seconds for `n` elements.
+    pub fn estimate(&self, n: usize) -> f64 {
+        self.latency + self.secs_per_element * n as f64
+    }
+}
+
+/// The per-device cost model built by `DeviceManagerContext::calibrate`.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceCostModel {
+    ///
+    pub entries: Vec<DeviceCostEntry>,
+}
+
+impl DeviceCostModel {
+    /// Create an empty model.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert an entry, replacing the previous one of the same device and unit.
+    pub fn insert(&mut self, entry: DeviceCostEntry) {
+        self.entries.retain(|e| {
+            e.device_id != entry.device_id || e.device_unit_type != entry.device_unit_type
+        });
+        self.entries.push(entry);
+    }
+
+    ///
+    pub fn get(
+        &self,
+        device_id: usize,
+        device_unit_type: DeviceUnitType,
+    ) -> Option<&DeviceCostEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.device_id == device_id && e.device_unit_type == device_unit_type)
+    }
+
+    /// The estimated time in seconds of `n` elements on the device.
+    pub fn estimate(
+        &self,
+        device_id: usize,
+        device_unit_type: DeviceUnitType,
+        n: usize,
+    ) -> Option<f64> {
+        self.get(device_id, device_unit_type).map(|e| e.estimate(n))
+    }
+
+    /// The smallest size at which the device is faster than the CPU,
+    /// or `None` if it never is.
+    pub fn crossover(&self, device_id: usize, device_unit_type: DeviceUnitType) -> Option<usize> {
+        let device = self.get(device_id, device_unit_type)?;
+        let cpu = self.get(COST_MODEL_CPU_DEVICE, device_unit_type)?;
+
+        if device.latency <= cpu.latency && device.secs_per_element <= cpu.secs_per_element {
+            return Some(0);
+        }
+        if device.secs_per_element >= cpu.secs_per_element {
+            return None;
+        }
+        let n = (device.latency - cpu.latency) / (cpu.secs_per_element - device.secs_per_element);
+        Some(n.ceil() as usize + 1)
+    }
+
+    /// Choose the fastest of `device_ids` and the CPU for `n` elements.
+    /// Devices without an entry are skipped, and `COST_MODEL_CPU_DEVICE` is
+    /// returned when the CPU wins or nothing has been measured.
+    pub fn best_device(
+        &self,
+        device_unit_type: DeviceUnitType,
+        n: usize,
+        device_ids: &[usize],
+    ) -> usize {
+        let mut best = COST_MODEL_CPU_DEVICE;
+        let mut best_cost = self
+            .estimate(COST_MODEL_CPU_DEVICE, device_unit_type, n)
+            .unwrap_or(f64::INFINITY);
+
+        for &device_id in device_ids.iter() {
+            if let Some(cost) = self.estimate(device_id, device_unit_type, n) {
+                if cost < best_cost {
+                    best = device_id;
+                    best_cost = cost;
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Persist the model to a text file, one entry per line.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), DeviceManagerError> {
+        let mut file =
+            File::create(path).map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)?;
+
+        let mut lines = vec![COST_MODEL_FILE_HEADER.to_string()];
+        for entry in self.entries.iter() {
+            let device = if entry.device_id == COST_MODEL_CPU_DEVICE {
+                "cpu".to_string()
+            } else {
+                entry.device_id.to_string()
+            };
+            if let DeviceUnitType::DeviceUnitTypeNone = entry.device_unit_type {
+                continue;
+            }
+            let unit = entry.device_unit_type.name();
+            lines.push(format!(
+                "{} {} {} {}",
+                device, unit, entry.latency, entry.secs_per_element
+            ));
+        }
+
+        file.write_all((lines.join("\n") + "\n").as_bytes())
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)
+    }
+
+    /// Load a model written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, DeviceManagerError> {
+        let file =
+            File::open(path).map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)?;
+
+        let mut model = DeviceCostModel::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 {
+                return Err(DeviceManagerError::DeviceManagerErrorCostModelIO);
+            }
+            let device_id = match fields[0] {
+                "cpu" => COST_MODEL_CPU_DEVICE,
+                id => id
+                    .parse()
+                    .map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)?,
+            };
+            let device_unit_type = match fields[1] {
+                "msm" => DeviceUnitType::DeviceUnitTypeMSM,
+                "ntt" => DeviceUnitType::DeviceUnitTypeNTT,
+                "pointwise" => DeviceUnitType::DeviceUnitTypePointwise,
+                _ => return Err(DeviceManagerError::DeviceManagerErrorCostModelIO),
+            };
+            let latency = fields[2]
+                .parse()
+                .map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)?;
+            let secs_per_element = fields[3]
+                .parse()
+                .map_err(|_| DeviceManagerError::DeviceManagerErrorCostModelIO)?;
+
+            model.insert(DeviceCostEntry {
+                device_id,
+                device_unit_type,
+                latency,
+                secs_per_element,
+            });
+        }
+
+        Ok(model)
+    }
+}
+
+impl DeviceManagerContext {
+    /// Time MSM and NTT on the CPU and on every device holding the param for
+    /// sizes `2^min_k..=2^max_k`, fit the cost model and keep it for scheduling.
+    /// `bases` must be the bases registered at `bases_index` of `msm_param_id`.
+    pub fn calibrate<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases_index: usize,
+        bases: &[C],
+        omega: C::Scalar,
+        min_k: u32,
+        max_k: u32,
+    ) -> Result<DeviceCostModel, DeviceManagerError> {
+        let _span = tracing::info_span!(
+            "device_manager.calibrate",
+            param_id = msm_param_id,
+            bases_index,
+            min_k,
+            max_k
+        )
+        .entered();
+
+        if !self.init_flag {
+            return Err(DeviceManagerError::DeviceManagerErrorGetDeviceNum);
+        }
+
+        // Never run past the registered bases.
+        let mut max_k = max_k;
+        while max_k > 0 && (1usize << max_k) > bases.len() {
+            max_k -= 1;
+        }
+        if min_k > max_k {
+            return Err(DeviceManagerError::DeviceManagerErrorBasesIndex);
+        }
+
+        let msm_units: Vec<(usize, DeviceHandle)> = self
+            .msm_param_uints
+            .iter()
+            .filter(|unit| unit.param_id == msm_param_id)
+            .map(|unit| (unit.device_id, unit.handle.clone()))
+            .collect();
+        let ntt_units: Vec<(usize, DeviceHandle)> = self
+            .ntt_param_uints
+            .iter()
+            .map(|unit| (unit.device_id, unit.handle.clone()))
+            .collect();
+
+        let mut cpu_msm_samples = vec![];
+        let mut cpu_ntt_samples = vec![];
+        let mut msm_samples = vec![vec![]; msm_units.len()];
+        let mut ntt_samples = vec![vec![]; ntt_units.len()];
+
+        // Calibration jobs are not production work, keep them out of the metrics.
+        let metrics = self.metrics.clone();
+        let limits = self.job_limits(&DeviceJobOptions::default());
+
+        let scalars = (0..(1usize << max_k))
+            .map(|_| C::Scalar::random(OsRng))
+            .collect::<Vec<_>>();
+
+        for log_n in min_k..=max_k {
+            let n = 1usize << log_n;
+
+            let start = Instant::now();
+            best_multiexp_cpu(&scalars[..n], &bases[..n]);
+            cpu_msm_samples.push(DeviceCostSample {
+                log_n,
+                secs: start.elapsed().as_secs_f64(),
+            });
+
+            for ((_, handle), samples) in msm_units.iter().zip(msm_samples.iter_mut()) {
+                let start = Instant::now();
+                self.session_msm::<C>(handle, &scalars[..n], bases_index, &limits)?;
+                samples.push(DeviceCostSample {
+                    log_n,
+                    secs: start.elapsed().as_secs_f64(),
+                });
+            }
+
+            let mut values = scalars[..n].to_vec();
+            let start = Instant::now();
+            best_fft_cpu(&mut values, omega, log_n);
+            cpu_ntt_samples.push(DeviceCostSample {
+                log_n,
+                secs: start.elapsed().as_secs_f64(),
+            });
+
+            for ((_, handle), samples) in ntt_units.iter().zip(ntt_samples.iter_mut()) {
+                let mut values = scalars[..n].to_vec();
+                let start = Instant::now();
+                self.session_ntt::<C::Scalar, C::Scalar>(handle, &mut values, log_n, &limits)?;
+                samples.push(DeviceCostSample {
+                    log_n,
+                    secs: start.elapsed().as_secs_f64(),
+                });
+            }
+        }
+
+        self.metrics = metrics;
+
+        let mut model = self.cost_model.take().unwrap_or_default();
+        model.insert(DeviceCostEntry::fit(
+            COST_MODEL_CPU_DEVICE,
+            DeviceUnitType::DeviceUnitTypeMSM,
+            &cpu_msm_samples,
+        ));
+        model.insert(DeviceCostEntry::fit(
+            COST_MODEL_CPU_DEVICE,
+            DeviceUnitType::DeviceUnitTypeNTT,
+            &cpu_ntt_samples,
+        ));
+        for ((device_id, _), samples) in msm_units.iter().zip(msm_samples.iter()) {
+            model.insert(DeviceCostEntry::fit(
+                *device_id,
+                DeviceUnitType::DeviceUnitTypeMSM,
+                samples,
+            ));
+        }
+        for ((device_id, _), samples) in ntt_units.iter().zip(ntt_samples.iter()) {
+            model.insert(DeviceCostEntry::fit(
+                *device_id,
+                DeviceUnitType::DeviceUnitTypeNTT,
+                samples,
+            ));
+        }
+
+        self.cost_model = Some(model.clone());
+
+        Ok(model)
+    }
+
+    /// Use a cost model persisted by `DeviceCostModel::save`.
+    pub fn load_cost_model<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DeviceManagerError> {
+        self.cost_model = Some(DeviceCostModel::load(path)?);
+        Ok(())
+    }
+
+    /// Whether the cost model says the CPU beats every ready device for `n` elements.
+    pub fn prefer_cpu(&self, device_unit_type: DeviceUnitType, n: usize) -> bool {
+        match &self.cost_model {
+            Some(model) => {
+                let device_ids = self.ready_device_ids();
+                model.best_device(device_unit_type, n, &device_ids) == COST_MODEL_CPU_DEVICE
+            }
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_cost_model_fit_and_crossover() {
+    // cpu: 1us per element, gpu: 5ms launch and 0.1us per element.
+    let cpu_samples = (10..16)
+        .map(|log_n| DeviceCostSample {
+            log_n,
+            secs: 1e-6 * (1u64 << log_n) as f64,
+        })
+        .collect::<Vec<_>>();
+    let gpu_samples = (10..16)
+        .map(|log_n| DeviceCostSample {
+            log_n,
+            secs: 5e-3 + 1e-7 * (1u64 << log_n) as f64,
+        })
+        .collect::<Vec<_>>();
+
+    let mut model = DeviceCostModel::new();
+    model.insert(DeviceCostEntry::fit(
+        COST_MODEL_CPU_DEVICE,
+        DeviceUnitType::DeviceUnitTypeMSM,
+        &cpu_samples,
+    ));
+    model.insert(DeviceCostEntry::fit(
+        0,
+        DeviceUnitType::DeviceUnitTypeMSM,
+        &gpu_samples,
+    ));
+
+    let crossover = model
+        .crossover(0, DeviceUnitType::DeviceUnitTypeMSM)
+        .unwrap();
+    assert!((5500..5600).contains(&crossover));
+    assert_eq!(
+        model.best_device(DeviceUnitType::DeviceUnitTypeMSM, 1 << 12, &[0]),
+        COST_MODEL_CPU_DEVICE
+    );
+    assert_eq!(
+        model.best_device(DeviceUnitType::DeviceUnitTypeMSM, 1 << 13, &[0]),
+        0
+    );
+    assert_eq!(model.crossover(0, DeviceUnitType::DeviceUnitTypeNTT), None);
+}
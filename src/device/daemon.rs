@@ -0,0 +1,784 @@
+use super::*;
+
+use crate::arithmetic::best_fft_cpu;
+use halo2curves::bn256::{Fr, G1Affine, G1};
+use halo2curves::ff::{Field, PrimeField};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{info_span, warn};
+
+/// Report the daemon devices and metrics.
+pub const DEVICE_DAEMON_OP_STATUS: u8 = 1;
+/// Register bases and omega, set up once per distinct content.
+pub const DEVICE_DAEMON_OP_REGISTER: u8 = 2;
+/// Run an MSM with registered bases.
+pub const DEVICE_DAEMON_OP_MSM: u8 = 3;
+/// Run an NTT.
+pub const DEVICE_DAEMON_OP_NTT: u8 = 4;
+
+/// The longest frame the daemon and its clients read, enough for the bases
+/// of `2^26` BN254 points. Longer frames are rejected before allocating.
+pub const DEVICE_DAEMON_MAX_FRAME_LEN: usize = 1 << 33;
+
+const DEVICE_DAEMON_STATUS_OK: u8 = 0;
+const DEVICE_DAEMON_STATUS_ERROR: u8 = 1;
+
+fn write_frame<W: Write>(stream: &mut W, body: &[u8]) -> io::Result<()> {
+    stream.write_all(&(body.len() as u64).to_le_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > DEVICE_DAEMON_MAX_FRAME_LEN as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "device daemon frame too long",
+        ));
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Decodes the fields of a frame.
+struct DeviceDaemonReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> DeviceDaemonReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DeviceManagerError> {
+        if self.bytes.len() < n {
+            return Err(DeviceManagerError::DeviceManagerErrorDaemonIO);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeviceManagerError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, DeviceManagerError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DeviceManagerError> {
+        let len = self.u64()? as usize;
+        self.take(len)
+    }
+
+    fn u64s(&mut self) -> Result<Vec<usize>, DeviceManagerError> {
+        let len = self.u64()?;
+        (0..len).map(|_| Ok(self.u64()? as usize)).collect()
+    }
+
+    fn rest(self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+fn unit_type_to_u8(init_device_unit_type: &DeviceInitUnitType) -> u8 {
+    match init_device_unit_type {
+        DeviceInitUnitType::DeviceInitUnitTypeNone => 0,
+        DeviceInitUnitType::DeviceInitUnitTypeMSM => 1,
+        DeviceInitUnitType::DeviceInitUnitTypeNTT => 2,
+        DeviceInitUnitType::DevicerInitUnitTypeALL => 3,
+    }
+}
+
+/// The unit type of a registration, which sets up at least one unit.
+fn unit_type_from_u8(value: u8) -> Result<DeviceInitUnitType, DeviceManagerError> {
+    match value {
+        1 => Ok(DeviceInitUnitType::DeviceInitUnitTypeMSM),
+        2 => Ok(DeviceInitUnitType::DeviceInitUnitTypeNTT),
+        3 => Ok(DeviceInitUnitType::DevicerInitUnitTypeALL),
+        _ => Err(DeviceManagerError::DeviceManagerErrorDaemonIO),
+    }
+}
+
+fn unit_from_u8(value: u8) -> Result<DeviceUnitType, DeviceManagerError> {
+    match value {
+        0 => Ok(DeviceUnitType::DeviceUnitTypeNone),
+        1 => Ok(DeviceUnitType::DeviceUnitTypeMSM),
+        2 => Ok(DeviceUnitType::DeviceUnitTypeNTT),
+        3 => Ok(DeviceUnitType::DeviceUnitTypePointwise),
+        _ => Err(DeviceManagerError::DeviceManagerErrorDaemonIO),
+    }
+}
+
+fn unit_to_u8(device_unit_type: DeviceUnitType) -> u8 {
+    match device_unit_type {
+        DeviceUnitType::DeviceUnitTypeNone => 0,
+        DeviceUnitType::DeviceUnitTypeMSM => 1,
+        DeviceUnitType::DeviceUnitTypeNTT => 2,
+        DeviceUnitType::DeviceUnitTypePointwise => 3,
+    }
+}
+
+fn put_queue_entries(out: &mut Vec<u8>, entries: &[DeviceJobQueueEntry]) {
+    put_u64(out, entries.len() as u64);
+    for entry in entries {
+        put_u64(out, entry.ticket);
+        out.push(entry.job.priority.to_u8());
+        put_u64(out, entry.job.tenant);
+        out.push(unit_to_u8(entry.job.device_unit_type));
+        put_u64(out, entry.job.n as u64);
+        put_u64(out, entry.position as u64);
+        put_u64(out, entry.waited.as_nanos() as u64);
+        put_u64(out, entry.estimated_wait.as_nanos() as u64);
+    }
+}
+
+fn read_queue_entries(
+    reader: &mut DeviceDaemonReader,
+) -> Result<Vec<DeviceJobQueueEntry>, DeviceManagerError> {
+    let len = reader.u64()?;
+    (0..len)
+        .map(|_| {
+            Ok(DeviceJobQueueEntry {
+                ticket: reader.u64()?,
+                job: DeviceJob {
+                    priority: DeviceJobPriority::from_u8(reader.u8()?)
+                        .ok_or(DeviceManagerError::DeviceManagerErrorDaemonIO)?,
+                    tenant: reader.u64()?,
+                    device_unit_type: unit_from_u8(reader.u8()?)?,
+                    n: reader.u64()? as usize,
+                },
+                position: reader.u64()? as usize,
+                waited: Duration::from_nanos(reader.u64()?),
+                estimated_wait: Duration::from_nanos(reader.u64()?),
+            })
+        })
+        .collect()
+}
+
+/// Read the priority and tenant of an MSM or NTT request.
+fn read_job(
+    reader: &mut DeviceDaemonReader,
+    device_unit_type: DeviceUnitType,
+    n: usize,
+) -> Result<DeviceJob, DeviceManagerError> {
+    Ok(DeviceJob {
+        priority: DeviceJobPriority::from_u8(reader.u8()?)
+            .ok_or(DeviceManagerError::DeviceManagerErrorDaemonIO)?,
+        tenant: reader.u64()?,
+        device_unit_type,
+        n,
+    })
+}
+
+/// The state of the daemon devices, see `DEVICE_DAEMON_OP_STATUS`.
+#[derive(Clone, Debug)]
+pub struct DeviceDaemonStatus {
+    ///
+    pub device_num: usize,
+    /// Distinct bases and omega set up on the devices.
+    pub registration_num: usize,
+    ///
+    pub ready_device_ids: Vec<usize>,
+    ///
+    pub quarantined_device_ids: Vec<usize>,
+    /// The jobs of all clients, with their queue positions and wait estimates.
+    pub queue: DeviceJobQueueStatus,
+    /// The daemon metrics in the Prometheus text exposition format.
+    pub metrics: String,
+}
+
+/// The state of the daemon, locked only briefly and never across device
+/// work.
+#[derive(Debug)]
+struct DeviceDaemonState {
+    /// The registrations and the device health, cloned into the compute
+    /// contexts.
+    device_manager: DeviceManager,
+    /// Compute contexts not running a job, all of the current generation.
+    idle_contexts: Vec<DeviceManagerContext>,
+    /// Bumped by every registration, the compute contexts of an older one
+    /// miss it and are dropped.
+    generation: u64,
+    /// The metrics of all the contexts.
+    metrics: DeviceMetrics,
+    /// The registrations by the hash of their content.
+    registrations: HashMap<u64, Vec<DeviceDaemonRegistration>>,
+    /// The registered omega and the log size of its domain by param id.
+    ntt_domains: HashMap<usize, (Fr, u32)>,
+}
+
+impl DeviceDaemonState {
+    fn registration_num(&self) -> usize {
+        self.registrations.values().map(Vec::len).sum()
+    }
+
+    /// The param id of the registration with the content, `None` if it is new.
+    fn find_registration(
+        &self,
+        key: u64,
+        unit_type: u8,
+        bases: &[&[u8]],
+        omega: Option<&[u8]>,
+    ) -> Option<usize> {
+        self.registrations
+            .get(&key)?
+            .iter()
+            .find_map(|registration| {
+                (registration.unit_type == unit_type
+                    && registration
+                        .bases
+                        .iter()
+                        .map(Vec::as_slice)
+                        .eq(bases.iter().copied())
+                    && registration.omega.as_deref() == omega)
+                    .then_some(registration.param_id)
+            })
+    }
+}
+
+/// A registered param with its content, which is compared in full before
+/// the param is shared, as different content may have the same hash.
+#[derive(Debug)]
+struct DeviceDaemonRegistration {
+    unit_type: u8,
+    bases: Vec<Vec<u8>>,
+    omega: Option<Vec<u8>>,
+    param_id: usize,
+}
+
+/// The log size of the domain of the root of unity, `None` if it is not a
+/// root of unity of a power of two order.
+fn omega_log_n(omega: Fr) -> Option<u32> {
+    let mut power = omega;
+    for log_n in 0..=Fr::S {
+        if power == Fr::ONE {
+            return Some(log_n);
+        }
+        power = power.square();
+    }
+    None
+}
+
+#[derive(Debug)]
+struct DeviceDaemonShared {
+    state: Mutex<DeviceDaemonState>,
+    /// Serializes the registrations, which upload the bases.
+    register: Mutex<()>,
+    queue: DeviceJobQueue,
+}
+
+impl DeviceDaemonShared {
+    fn state(&self) -> Result<MutexGuard<'_, DeviceDaemonState>, DeviceManagerError> {
+        self.state
+            .lock()
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorDaemonIO)
+    }
+
+    /// A compute context with every registration and the current device
+    /// health, and its generation.
+    ///
+    /// The contexts share the device workers and the jobs in flight, so the
+    /// jobs of concurrent contexts go to different devices when they can.
+    fn checkout(&self) -> Result<(DeviceManagerContext, u64), DeviceManagerError> {
+        let mut state = self.state()?;
+        let mut context = match state.idle_contexts.pop() {
+            Some(context) => context,
+            None => state.device_manager.get_handle().clone(),
+        };
+        context.devices = state.device_manager.get_handle().devices.clone();
+        Ok((context, state.generation))
+    }
+
+    /// Return a compute context after its job, keeping its metrics and the
+    /// device health it saw.
+    fn checkin(
+        &self,
+        mut context: DeviceManagerContext,
+        generation: u64,
+    ) -> Result<(), DeviceManagerError> {
+        let mut state = self.state()?;
+        state.metrics.merge(&context.metrics);
+        context.reset_metrics();
+        if generation == state.generation {
+            state.device_manager.get_handle_mut().devices = context.devices.clone();
+            state.idle_contexts.push(context);
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, request: &[u8]) -> Result<Vec<u8>, DeviceManagerError> {
+        let mut reader = DeviceDaemonReader { bytes: request };
+        let mut response = vec![];
+
+        match reader.u8()? {
+            DEVICE_DAEMON_OP_STATUS => {
+                let queue_status = self.queue.status();
+                let state = self.state()?;
+                let handle = state.device_manager.get_handle();
+
+                put_u64(&mut response, handle.backend.device_number()? as u64);
+                put_u64(&mut response, state.registration_num() as u64);
+                for device_ids in [handle.ready_device_ids(), handle.quarantined_device_ids()] {
+                    put_u64(&mut response, device_ids.len() as u64);
+                    for device_id in device_ids {
+                        put_u64(&mut response, device_id as u64);
+                    }
+                }
+                put_u64(&mut response, queue_status.max_depth as u64);
+                put_queue_entries(&mut response, &queue_status.running);
+                put_queue_entries(&mut response, &queue_status.waiting);
+                response.extend_from_slice(state.metrics.to_prometheus_text().as_bytes());
+            }
+            DEVICE_DAEMON_OP_REGISTER => {
+                let unit_type = reader.u8()?;
+                let bases_num = reader.u64()?;
+                let bases = (0..bases_num)
+                    .map(|_| reader.bytes())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let omega = match reader.u8()? {
+                    0 => None,
+                    _ => Some(reader.bytes()?),
+                };
+
+                // Processes proving the same circuit register the same bases,
+                // they share one copy on the devices.
+                let mut hasher = DefaultHasher::new();
+                unit_type.hash(&mut hasher);
+                bases.hash(&mut hasher);
+                omega.hash(&mut hasher);
+                let key = hasher.finish();
+                let raw_unit_type = unit_type;
+                let unit_type = unit_type_from_u8(unit_type)?;
+                let ntt_domain = match omega {
+                    Some(omega) => {
+                        let omega = match values_from_bytes::<Fr>(omega).as_deref() {
+                            Ok([omega]) => *omega,
+                            _ => return Err(DeviceManagerError::DeviceManagerErrorDaemonIO),
+                        };
+                        let log_n = omega_log_n(omega)
+                            .ok_or(DeviceManagerError::DeviceManagerErrorDaemonIO)?;
+                        Some((omega, log_n))
+                    }
+                    None => None,
+                };
+
+                // The bases are uploaded to a clone, the jobs meanwhile run
+                // on the contexts without the registration.
+                let _register = self
+                    .register
+                    .lock()
+                    .map_err(|_| DeviceManagerError::DeviceManagerErrorDaemonIO)?;
+                let (param_id, mut context) = {
+                    let state = self.state()?;
+                    if let Some(param_id) =
+                        state.find_registration(key, raw_unit_type, &bases, omega)
+                    {
+                        put_u64(&mut response, param_id as u64);
+                        return Ok(response);
+                    }
+                    (
+                        state.registration_num(),
+                        state.device_manager.get_handle().clone(),
+                    )
+                };
+                let _span = info_span!("device_daemon.register", param_id).entered();
+                let result = context.init(
+                    unit_type,
+                    Some(param_id),
+                    (!bases.is_empty()).then_some(&bases[..]),
+                    omega,
+                );
+
+                let mut state = self.state()?;
+                state.metrics.merge(&context.metrics);
+                context.reset_metrics();
+                result?;
+                // Keep the device health seen by the jobs during the upload.
+                let device_num = state.device_manager.get_handle().devices.len();
+                context.devices[..device_num]
+                    .clone_from_slice(&state.device_manager.get_handle().devices);
+                state.device_manager.handle = Box::new(context);
+                state.idle_contexts.clear();
+                state.generation += 1;
+                state
+                    .registrations
+                    .entry(key)
+                    .or_default()
+                    .push(DeviceDaemonRegistration {
+                        unit_type: raw_unit_type,
+                        bases: bases.iter().map(|bases| bases.to_vec()).collect(),
+                        omega: omega.map(|omega| omega.to_vec()),
+                        param_id,
+                    });
+                if let Some(ntt_domain) = ntt_domain {
+                    state.ntt_domains.insert(param_id, ntt_domain);
+                }
+                put_u64(&mut response, param_id as u64);
+            }
+            DEVICE_DAEMON_OP_MSM => {
+                let param_id = reader.u64()? as usize;
+                let bases_index = reader.u64()? as usize;
+                let job = read_job(&mut reader, DeviceUnitType::DeviceUnitTypeMSM, 0)?;
+                let scalars = values_from_bytes::<Fr>(reader.rest())
+                    .map_err(|_| DeviceManagerError::DeviceManagerErrorDaemonIO)?;
+
+                let _permit = self.queue.acquire(DeviceJob {
+                    n: scalars.len(),
+                    ..job
+                })?;
+                let (mut context, generation) = self.checkout()?;
+                let result = context.execute_msm::<G1Affine>(param_id, bases_index, &scalars);
+                self.checkin(context, generation)?;
+                response = result?;
+            }
+            DEVICE_DAEMON_OP_NTT => {
+                let param_id = reader.u64()? as usize;
+                let log_n = reader.u64()?;
+                let (omega, domain_log_n) = *self
+                    .state()?
+                    .ntt_domains
+                    .get(&param_id)
+                    .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)?;
+                if log_n > domain_log_n as u64 {
+                    return Err(DeviceManagerError::DeviceManagerErrorDaemonIO);
+                }
+                let log_n = log_n as u32;
+                let job = read_job(&mut reader, DeviceUnitType::DeviceUnitTypeNTT, 1 << log_n)?;
+                let mut scalars = values_from_bytes::<Fr>(reader.rest())
+                    .map_err(|_| DeviceManagerError::DeviceManagerErrorDaemonIO)?;
+                if scalars.len() != 1 << log_n {
+                    return Err(DeviceManagerError::DeviceManagerErrorDaemonIO);
+                }
+
+                // The devices use the powers of `Fr::ROOT_OF_UNITY`, a
+                // registration with another root runs on the host.
+                let mut root = omega;
+                for _ in log_n..domain_log_n {
+                    root = root.square();
+                }
+                let mut device_root = Fr::ROOT_OF_UNITY;
+                for _ in log_n..Fr::S {
+                    device_root = device_root.square();
+                }
+
+                let _permit = self.queue.acquire(job)?;
+                if root == device_root {
+                    let (mut context, generation) = self.checkout()?;
+                    let result = context.execute_ntt::<Fr, Fr>(&mut scalars, log_n);
+                    self.checkin(context, generation)?;
+                    result?;
+                } else {
+                    best_fft_cpu(&mut scalars, root, log_n);
+                }
+                response.extend_from_slice(transmute_values(&scalars));
+            }
+            _ => return Err(DeviceManagerError::DeviceManagerErrorDaemonIO),
+        }
+
+        Ok(response)
+    }
+}
+
+/// A daemon owning a `DeviceManager`, so that the prover processes on a host
+/// share the devices and the uploaded bases through a Unix domain socket.
+///
+/// The jobs of all clients wait in one `DeviceJobQueue`. Each running job
+/// takes a clone of the device manager, so that with several queue slots
+/// the jobs run at once on different devices. Only BN254 is served, like the
+/// GPUs.
+#[derive(Debug)]
+pub struct DeviceDaemon {
+    listener: UnixListener,
+    shared: Arc<DeviceDaemonShared>,
+}
+
+impl DeviceDaemon {
+    /// Listen on `path`, replacing the socket of an earlier daemon.
+    pub fn bind<P: AsRef<Path>>(path: P, mut device_manager: DeviceManager) -> io::Result<Self> {
+        let path = path.as_ref();
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        // The compute contexts start from the device manager without metrics.
+        let metrics = device_manager.get_handle().metrics_snapshot();
+        device_manager.get_handle_mut().reset_metrics();
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            shared: Arc::new(DeviceDaemonShared {
+                state: Mutex::new(DeviceDaemonState {
+                    device_manager,
+                    idle_contexts: vec![],
+                    generation: 0,
+                    metrics,
+                    registrations: HashMap::new(),
+                    ntt_domains: HashMap::new(),
+                }),
+                register: Mutex::new(()),
+                queue: DeviceJobQueue::new(DeviceJobQueueConfig::default()),
+            }),
+        })
+    }
+
+    ///
+    pub fn set_queue_config(&self, config: DeviceJobQueueConfig) {
+        self.shared.queue.set_config(config);
+    }
+
+    /// Serve the clients until the listener fails, one thread per connection.
+    pub fn serve(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            std::thread::spawn(move || {
+                if let Err(error) = Self::serve_connection(stream, shared) {
+                    if error.kind() != io::ErrorKind::UnexpectedEof {
+                        warn!(%error, "Device daemon connection failed");
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn serve_connection(mut stream: UnixStream, shared: Arc<DeviceDaemonShared>) -> io::Result<()> {
+        loop {
+            let request = read_frame(&mut stream)?;
+            let result = shared.handle_request(&request);
+
+            let mut response = vec![];
+            match result {
+                Ok(payload) => {
+                    response.push(DEVICE_DAEMON_STATUS_OK);
+                    response.extend_from_slice(&payload);
+                }
+                Err(error) => {
+                    response.push(DEVICE_DAEMON_STATUS_ERROR);
+                    response.extend_from_slice(error.kind().as_bytes());
+                }
+            }
+            write_frame(&mut stream, &response)?;
+        }
+    }
+}
+
+/// A `DeviceBackend` running the jobs on a `DeviceDaemon`.
+///
+/// The daemon is one device to the client, it does the scheduling across
+/// the real devices itself. Registrations are shared with the other clients
+/// and live as long as the daemon, so `deinit` releases nothing.
+#[derive(Debug)]
+pub struct DaemonClientBackend {
+    path: PathBuf,
+    stream: Mutex<Option<UnixStream>>,
+    priority: DeviceJobPriority,
+    tenant: Option<u64>,
+}
+
+impl DaemonClientBackend {
+    /// Connect to the daemon on `path` at the first request.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            stream: Mutex::new(None),
+            priority: DeviceJobPriority::Normal,
+            tenant: None,
+        }
+    }
+
+    /// Queue the jobs with the priority and tenant. Without a tenant, jobs are
+    /// accounted to their daemon param id.
+    pub fn with_job_class(mut self, priority: DeviceJobPriority, tenant: Option<u64>) -> Self {
+        self.priority = priority;
+        self.tenant = tenant;
+        self
+    }
+
+    fn put_job_class(&self, out: &mut Vec<u8>, handle: &DeviceHandle) {
+        out.push(self.priority.to_u8());
+        put_u64(out, self.tenant.unwrap_or(handle.handle_id as u64));
+    }
+
+    /// Send one request and return the payload of the response.
+    fn request(&self, request: &[u8]) -> Result<Vec<u8>, DeviceManagerError> {
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| DeviceManagerError::DeviceManagerErrorDaemonIO)?;
+
+        let result = (|| -> io::Result<Vec<u8>> {
+            if stream.is_none() {
+                *stream = Some(UnixStream::connect(&self.path)?);
+            }
+            let connection = stream.as_mut().unwrap();
+            write_frame(connection, request)?;
+            read_frame(connection)
+        })();
+
+        let response = match result {
+            Ok(response) => response,
+            Err(error) => {
+                // Reconnect at the next request.
+                *stream = None;
+                warn!(%error, path = ?self.path, "Device daemon request failed");
+                return Err(DeviceManagerError::DeviceManagerErrorDaemonIO);
+            }
+        };
+
+        match response.split_first() {
+            Some((&DEVICE_DAEMON_STATUS_OK, payload)) => Ok(payload.to_vec()),
+            Some((&DEVICE_DAEMON_STATUS_ERROR, kind)) => Err(std::str::from_utf8(kind)
+                .ok()
+                .and_then(DeviceManagerError::from_kind)
+                .unwrap_or(DeviceManagerError::DeviceManagerErrorDaemonIO)),
+            _ => Err(DeviceManagerError::DeviceManagerErrorDaemonIO),
+        }
+    }
+
+    /// The state of the daemon devices.
+    pub fn status(&self) -> Result<DeviceDaemonStatus, DeviceManagerError> {
+        let response = self.request(&[DEVICE_DAEMON_OP_STATUS])?;
+        let mut reader = DeviceDaemonReader { bytes: &response };
+        Ok(DeviceDaemonStatus {
+            device_num: reader.u64()? as usize,
+            registration_num: reader.u64()? as usize,
+            ready_device_ids: reader.u64s()?,
+            quarantined_device_ids: reader.u64s()?,
+            queue: DeviceJobQueueStatus {
+                max_depth: reader.u64()? as usize,
+                running: read_queue_entries(&mut reader)?,
+                waiting: read_queue_entries(&mut reader)?,
+            },
+            metrics: String::from_utf8_lossy(reader.rest()).into_owned(),
+        })
+    }
+}
+
+impl DeviceBackend for DaemonClientBackend {
+    fn device_number(&self) -> Result<usize, DeviceManagerError> {
+        Ok(1)
+    }
+
+    fn init(
+        &self,
+        device_id: usize,
+        init_device_unit_type: DeviceInitUnitType,
+        bases: Option<&[&[u8]]>,
+        omega: Option<&[u8]>,
+    ) -> Result<DeviceHandle, DeviceManagerError> {
+        let bases = bases.unwrap_or(&[]);
+        let mut request = vec![
+            DEVICE_DAEMON_OP_REGISTER,
+            unit_type_to_u8(&init_device_unit_type),
+        ];
+        put_u64(&mut request, bases.len() as u64);
+        for bases_bytes in bases {
+            put_bytes(&mut request, bases_bytes);
+        }
+        match omega {
+            Some(omega) => {
+                request.push(1);
+                put_bytes(&mut request, omega);
+            }
+            None => request.push(0),
+        }
+
+        let response = self.request(&request)?;
+        let param_id = DeviceDaemonReader { bytes: &response }.u64()? as usize;
+        Ok(DeviceHandle {
+            device_id,
+            handle_id: param_id,
+        })
+    }
+
+    fn deinit(&self, _handle: &DeviceHandle) {}
+
+    fn msm(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let mut request = vec![DEVICE_DAEMON_OP_MSM];
+        put_u64(&mut request, handle.handle_id as u64);
+        put_u64(&mut request, bases_index as u64);
+        self.put_job_class(&mut request, handle);
+        request.extend_from_slice(scalars);
+        let response = self.request(&request)?;
+        if response.len() != std::mem::size_of::<G1>() {
+            return Err(DeviceManagerError::DeviceManagerErrorDaemonIO);
+        }
+        Ok(response)
+    }
+
+    fn ntt(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        let mut request = vec![DEVICE_DAEMON_OP_NTT];
+        put_u64(&mut request, handle.handle_id as u64);
+        put_u64(&mut request, log_n as u64);
+        self.put_job_class(&mut request, handle);
+        request.extend_from_slice(scalars);
+
+        let response = self.request(&request)?;
+        if response.len() != scalars.len() {
+            return Err(DeviceManagerError::DeviceManagerErrorDaemonIO);
+        }
+        scalars.copy_from_slice(&response);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_device_daemon_registration_hash_collision() {
+    let mut state = DeviceDaemonState {
+        device_manager: DeviceManager::with_backend(Arc::new(MockDeviceBackend::new(1))),
+        idle_contexts: vec![],
+        generation: 0,
+        metrics: DeviceMetrics::default(),
+        registrations: HashMap::new(),
+        ntt_domains: HashMap::new(),
+    };
+    state.registrations.insert(
+        7,
+        vec![DeviceDaemonRegistration {
+            unit_type: 1,
+            bases: vec![vec![1, 2]],
+            omega: None,
+            param_id: 0,
+        }],
+    );
+
+    // Another content with the same hash is a new registration.
+    let (bases, other_bases): (&[u8], &[u8]) = (&[1, 2], &[1, 3]);
+    assert_eq!(state.find_registration(7, 1, &[bases], None), Some(0));
+    assert_eq!(state.find_registration(7, 1, &[other_bases], None), None);
+    assert_eq!(state.find_registration(7, 2, &[bases], None), None);
+    assert_eq!(state.find_registration(7, 1, &[bases], Some(bases)), None);
+    assert_eq!(state.find_registration(8, 1, &[bases], None), None);
+    assert_eq!(state.registration_num(), 1);
+}
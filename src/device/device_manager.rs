@@ -1,17 +1,23 @@
 use super::CurveAffine;
-use group::{GroupOpsOwned, ScalarMulOwned};
+use group::{Group, GroupOpsOwned, ScalarMulOwned};
 use halo2curves::ff::Field;
 
 use super::*;
 use panda::gpu_manager::*;
 
+use crate::arithmetic::FixedBaseConfig;
 use crate::poly::Basis;
 use crate::poly::Polynomial;
 use lazy_static::lazy_static;
-use std::sync::Mutex;
-///
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, warn};
+/// The elements an NTT runs on. They are compared by value, so that device
+/// results can be verified, see `verify_ntt_output`.
 pub trait FftGroup<Scalar: Field>:
-    Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar>
+    Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar> + PartialEq
 {
 }
 
@@ -19,7 +25,7 @@ pub trait FftGroup<Scalar: Field>:
 impl<T, Scalar> FftGroup<Scalar> for T
 where
     Scalar: Field,
-    T: Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar>,
+    T: Copy + Send + Sync + 'static + GroupOpsOwned + ScalarMulOwned<Scalar> + PartialEq,
 {
 }
 
@@ -39,13 +45,38 @@ pub struct DeviceManager {
 impl DeviceManager {
     /// Create
     pub fn new() -> Self {
+        Self::with_backend(Arc::new(PandaBackend::new()))
+    }
+
+    /// Create with another hardware backend, such as `MockDeviceBackend`.
+    pub fn with_backend(backend: Arc<dyn DeviceBackend>) -> Self {
         let context = DeviceManagerContext {
+            backend,
             gpu_device_num: 0,
             actived_device_num: 0,
             devices: Vec::<DeviceUnit>::new(),
             msm_param_uints: Vec::<MSMParamUnit>::new(),
             ntt_param_uints: Vec::<NTTParamUnit>::new(),
             init_flag: false,
+            cost_model: None,
+            metrics: DeviceMetrics::default(),
+            health_config: DeviceHealthConfig::default(),
+            verify_config: None,
+            last_device_id: None,
+            job_timeout: Some(DEVICE_JOB_DEFAULT_TIMEOUT),
+            jobs_in_flight: Arc::new(Mutex::new(HashMap::new())),
+            device_workers: Arc::new(Mutex::new(HashMap::new())),
+            shard_config: None,
+            msm_throughput: HashMap::new(),
+            streamed_msm_param_ids: vec![],
+            last_msm_stream_report: None,
+            fixed_base_configs: HashMap::new(),
+            fixed_base_tables: HashMap::new(),
+            glv_bases: HashMap::new(),
+            device_buffers: Arc::new(Mutex::new(HashMap::new())),
+            host_buffer_ids: Arc::new(AtomicUsize::new(0)),
+            device_memory: None,
+            probe_bases: HashMap::new(),
         };
         Self {
             handle: Box::new(context),
@@ -66,6 +97,8 @@ impl DeviceManager {
 ///
 #[derive(Clone, Debug)]
 pub struct DeviceManagerContext {
+    /// The hardware interface all device work goes through.
+    pub backend: Arc<dyn DeviceBackend>,
     ///
     pub gpu_device_num: usize,
     ///
@@ -78,10 +111,52 @@ pub struct DeviceManagerContext {
     pub ntt_param_uints: Vec<NTTParamUnit>,
     ///
     pub init_flag: bool,
+    /// Measured by `calibrate`, used to choose between the devices and the CPU.
+    pub cost_model: Option<DeviceCostModel>,
+    /// Counters and histograms of the device jobs.
+    pub metrics: DeviceMetrics,
+    /// When to quarantine a failing device and when to probe it again.
+    pub health_config: DeviceHealthConfig,
+    /// Opt-in checks of the device results, `None` to trust the devices.
+    pub verify_config: Option<DeviceVerifyConfig>,
+    /// The GPU that ran the last successful job.
+    pub last_device_id: Option<usize>,
+    /// The timeout of the execute calls without one of their own.
+    pub job_timeout: Option<Duration>,
+    /// Backend calls not returned yet by GPU id, including abandoned ones.
+    pub jobs_in_flight: Arc<Mutex<HashMap<usize, usize>>>,
+    /// The worker threads running the backend calls, see `run_device_job`.
+    pub device_workers: DeviceWorkers,
+    /// When to split one MSM across the devices, `None` to never split.
+    pub shard_config: Option<DeviceShardConfig>,
+    /// The moving average MSM throughput by GPU id, in elements per second.
+    pub msm_throughput: HashMap<usize, f64>,
+    /// The params whose bases exceed the device memory and stay on the host.
+    pub streamed_msm_param_ids: Vec<usize>,
+    /// How the last streamed MSM went.
+    pub last_msm_stream_report: Option<DeviceMsmStreamReport>,
+    /// The params whose bases get a fixed-base table, by param id.
+    pub fixed_base_configs: HashMap<usize, FixedBaseConfig>,
+    /// The fixed-base tables on the host by param id and bases index, a
+    /// `Vec` of the affine points.
+    pub fixed_base_tables: HashMap<(usize, usize), DeviceFixedBaseTable>,
+    /// The endomorphisms of the bases on the host by param id and bases
+    /// index, for the CPU MSMs of the curves with GLV.
+    pub glv_bases: HashMap<(usize, usize), DeviceGlvBases>,
+    /// The live `DeviceBuffer`s by GPU id and buffer id.
+    pub device_buffers: DeviceBufferRegistry,
+    /// The ids of the buffers kept on the host.
+    pub host_buffer_ids: DeviceHostBufferIds,
+    /// The memory of every device in bytes for backends that do not report
+    /// it, `None` for unlimited.
+    pub device_memory: Option<usize>,
+    /// The first bases of every MSM param by param id, up to the self-test
+    /// size, to check the self-test MSM on the host.
+    pub probe_bases: HashMap<usize, Vec<u8>>,
 }
 
 impl DeviceManagerContext {
-    /// Initialize
+    /// Initialize, `DeviceInitUnitTypeNone` is an error as it sets up no unit.
     pub fn init(
         &mut self,
         init_device_unit_type: DeviceInitUnitType,
@@ -89,44 +164,76 @@ impl DeviceManagerContext {
         bases: Option<&[&[u8]]>,
         omega: Option<&[u8]>,
     ) -> Result<(), DeviceManagerError> {
+        let span = info_span!(
+            "device_manager.init",
+            unit = ?init_device_unit_type,
+            param_id = ?param_id,
+            bases_num = bases.map_or(0, |bases| bases.len()),
+            bases_bytes = bases.map_or(0, |bases| bases.iter().map(|b| b.len()).sum::<usize>()),
+            device_num = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+
+        if let DeviceInitUnitType::DeviceInitUnitTypeNone = init_device_unit_type {
+            return Err(DeviceManagerError::DeviceManagerErrorExecute);
+        }
+        let msm = !matches!(
+            init_device_unit_type,
+            DeviceInitUnitType::DeviceInitUnitTypeNTT
+        );
+        if msm && param_id.is_none() {
+            return Err(DeviceManagerError::DeviceManagerErrorParamIdNone);
+        }
+
         // Get the number of GPUs
         self.gpu_device_num = self.get_gpu_device_number().unwrap();
+        span.record("device_num", self.gpu_device_num);
 
         // In case the number of GPUs is 0, return.
         if self.gpu_device_num == 0 {
             return Err(DeviceManagerError::DeviceManagerErrorGetDeviceNum);
         }
 
-        // Mapping initialization of device computation types.
-        let init_uint_type = match init_device_unit_type {
-            DeviceInitUnitType::DeviceInitUnitTypeNone => {
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeNone
+        // Bases larger than the memory of one of the devices stay on the host
+        // for all of them and are streamed through a device by
+        // `execute_msm_streaming`.
+        let streamed =
+            msm && (0..self.gpu_device_num).any(|device_id| !self.msm_bases_fit(device_id, bases));
+        if let (true, Some(id)) = (msm, param_id) {
+            self.streamed_msm_param_ids
+                .retain(|param_id| *param_id != id);
+            if streamed {
+                warn!(
+                    param_id = id,
+                    "MSM bases exceed the device memory, streaming them"
+                );
+                self.streamed_msm_param_ids.push(id);
             }
-            DeviceInitUnitType::DeviceInitUnitTypeMSM => {
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeMSM
-            }
-            DeviceInitUnitType::DeviceInitUnitTypeNTT => {
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeNTT
-            }
-            DeviceInitUnitType::DevicerInitUnitTypeALL => {
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeALL
-            }
-        };
+        }
 
         // init
         for device_id in 0..self.gpu_device_num {
-            // GPU init and get the handle of gpu manager. Setup and copy bases data
-            let gm = PandaGpuManager::init(0, init_uint_type.clone(), bases, omega).unwrap();
-
-            match init_uint_type {
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeNone => todo!(),
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeMSM => {
+            // Device init and get the handle. Setup and copy bases data
+            let upload_span = info_span!("device_manager.upload", device_id);
+            let handle = upload_span.in_scope(|| {
+                self.backend.init(
+                    device_id,
+                    init_device_unit_type.clone(),
+                    if streamed { None } else { bases },
+                    omega,
+                )
+            })?;
+
+            match init_device_unit_type {
+                DeviceInitUnitType::DeviceInitUnitTypeNone => unreachable!(),
+                DeviceInitUnitType::DeviceInitUnitTypeMSM => {
                     if let Some(id) = param_id {
                         let msm_param_uint = MSMParamUnit {
                             param_id: id,
+                            device_id,
                             in_usze: true,
                             init_flag: true,
-                            gm,
+                            handle,
                         };
                         self.msm_param_uints.push(msm_param_uint);
                         // Generate new device unit of MSM.
@@ -135,17 +242,20 @@ impl DeviceManagerContext {
                             device_type: DeviceType::DeviceTypeGPU,
                             device_unit_type: DeviceUnitType::DeviceUnitTypeMSM,
                             device_status: DeviceStatusType::DeviceStatusReady,
+                            consecutive_failures: 0,
+                            quarantined_at: None,
                         };
                         self.devices.push(device);
                     } else {
                         return Err(DeviceManagerError::DeviceManagerErrorGetDeviceNum);
                     }
                 }
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeNTT => {
+                DeviceInitUnitType::DeviceInitUnitTypeNTT => {
                     let ntt_param_uint = NTTParamUnit {
+                        device_id,
                         in_usze: true,
                         init_flag: true,
-                        gm,
+                        handle,
                     };
 
                     self.ntt_param_uints.push(ntt_param_uint);
@@ -155,16 +265,19 @@ impl DeviceManagerContext {
                         device_type: DeviceType::DeviceTypeGPU,
                         device_unit_type: DeviceUnitType::DeviceUnitTypeNTT,
                         device_status: DeviceStatusType::DeviceStatusReady,
+                        consecutive_failures: 0,
+                        quarantined_at: None,
                     };
                     self.devices.push(device);
                 }
-                PandaGpuManagerInitUnitType::PandaGpuManagerInitUnitTypeALL => {
+                DeviceInitUnitType::DevicerInitUnitTypeALL => {
                     if let Some(id) = param_id {
                         let msm_param_uint = MSMParamUnit {
                             param_id: id,
+                            device_id,
                             in_usze: true,
                             init_flag: true,
-                            gm: gm.clone(),
+                            handle: handle.clone(),
                         };
                         self.msm_param_uints.push(msm_param_uint);
                         // Generate new device unit of MSM.
@@ -173,6 +286,8 @@ impl DeviceManagerContext {
                             device_type: DeviceType::DeviceTypeGPU,
                             device_unit_type: DeviceUnitType::DeviceUnitTypeMSM,
                             device_status: DeviceStatusType::DeviceStatusReady,
+                            consecutive_failures: 0,
+                            quarantined_at: None,
                         };
                         self.devices.push(device);
                     } else {
@@ -180,9 +295,10 @@ impl DeviceManagerContext {
                     }
                     // Generate new device unit of NTT.
                     let ntt_param_uint = NTTParamUnit {
+                        device_id,
                         in_usze: true,
                         init_flag: true,
-                        gm: gm.clone(),
+                        handle: handle.clone(),
                     };
 
                     self.ntt_param_uints.push(ntt_param_uint);
@@ -192,12 +308,24 @@ impl DeviceManagerContext {
                         device_type: DeviceType::DeviceTypeGPU,
                         device_unit_type: DeviceUnitType::DeviceUnitTypeNTT,
                         device_status: DeviceStatusType::DeviceStatusReady,
+                        consecutive_failures: 0,
+                        quarantined_at: None,
                     };
                     self.devices.push(device);
                 }
             }
         }
 
+        // The self-test MSM is checked against these on the host.
+        if let (true, Some(id), Some(first)) = (msm, param_id, bases.and_then(|b| b.first())) {
+            let point_bytes = std::mem::size_of::<halo2curves::bn256::G1Affine>();
+            let len = first
+                .len()
+                .min(point_bytes << self.health_config.probe_log_n);
+            self.probe_bases
+                .insert(id, first[..len - len % point_bytes].to_vec());
+        }
+
         // Set actived device number and may be a need to use when performing calculations.
         self.actived_device_num = self.gpu_device_num;
         self.init_flag = true;
@@ -207,21 +335,41 @@ impl DeviceManagerContext {
 
     /// Deinitialization
     pub fn deinit(&mut self) -> Result<(), DeviceManagerError> {
+        let _span = info_span!(
+            "device_manager.deinit",
+            msm_param_num = self.msm_param_uints.len(),
+            ntt_param_num = self.ntt_param_uints.len(),
+        )
+        .entered();
+
         // Set the GPU and active device numbers to 0 to indicate deinitialization.
         self.gpu_device_num = 0;
         self.actived_device_num = 0;
 
+        // Buffers not dropped yet go with the devices.
+        let buffers: Vec<_> = match self.device_buffers.lock() {
+            Ok(mut buffers) => buffers.drain().collect(),
+            Err(_) => vec![],
+        };
+        for ((device_id, buffer_id), entry) in buffers {
+            if entry.host.is_none() {
+                self.backend.free_buffer(device_id, buffer_id);
+            }
+        }
+
         // Clear the device lists and flags.
         for msm_param_uint in self.msm_param_uints.iter() {
-            let mut gm = msm_param_uint.gm.clone();
-            gm.deinit();
+            self.backend.deinit(&msm_param_uint.handle);
         }
         self.msm_param_uints.clear();
         for ntt_param_uint in self.ntt_param_uints.iter() {
-            let mut gm = ntt_param_uint.gm.clone();
-            gm.deinit();
+            self.backend.deinit(&ntt_param_uint.handle);
         }
         self.ntt_param_uints.clear();
+        self.streamed_msm_param_ids.clear();
+        self.fixed_base_tables.clear();
+        self.glv_bases.clear();
+        self.probe_bases.clear();
         self.devices.clear();
         self.init_flag = false;
 
@@ -237,93 +385,394 @@ impl DeviceManagerContext {
         Ok(self.gpu_device_num)
     }
 
-    /// Get available devices.
-    fn get_available_device(&mut self) -> Result<usize, DeviceManagerError> {
-        for i in 0..self.actived_device_num {
+    /// Get available devices, skipping the GPUs in `excluded`.
+    fn get_available_device(&mut self, excluded: &[usize]) -> Result<usize, DeviceManagerError> {
+        // There is a unit per param and computation type on each GPU, so scan
+        // all of them rather than the first `actived_device_num`.
+        for i in 0..self.devices.len() {
+            if excluded.contains(&self.devices[i].device_id)
+                || self.is_device_busy(self.devices[i].device_id)
+            {
+                continue;
+            }
             match self.devices[i].device_status {
-                DeviceStatusType::DeviceStatusNone => todo!(),
-                DeviceStatusType::DeviceStatusIdle => todo!(),
                 DeviceStatusType::DeviceStatusReady => {
                     return Ok(i);
                 }
-                DeviceStatusType::DeviceStatusRunning => todo!(),
+                // Busy, quarantined or not set up yet.
+                DeviceStatusType::DeviceStatusNone
+                | DeviceStatusType::DeviceStatusIdle
+                | DeviceStatusType::DeviceStatusRunning
+                | DeviceStatusType::DeviceStatusError => {}
             }
         }
         Ok(NO_AVAILABE_DEVICE)
     }
 
+    /// Get the available device with the lowest estimated cost for `n` elements,
+    /// or the first available device if there is no cost model.
+    fn get_available_device_by_cost(
+        &mut self,
+        device_unit_type: DeviceUnitType,
+        n: usize,
+        excluded: &[usize],
+    ) -> Result<usize, DeviceManagerError> {
+        let model = match &self.cost_model {
+            Some(model) => model,
+            None => {
+                let found = self.get_available_device(excluded)?;
+                debug!(
+                    unit = device_unit_type.name(),
+                    n,
+                    device_index = found,
+                    "first available device"
+                );
+                return Ok(found);
+            }
+        };
+
+        let mut found = NO_AVAILABE_DEVICE;
+        let mut found_cost = f64::INFINITY;
+        for i in 0..self.devices.len() {
+            if excluded.contains(&self.devices[i].device_id)
+                || self.is_device_busy(self.devices[i].device_id)
+            {
+                continue;
+            }
+            if let DeviceStatusType::DeviceStatusReady = self.devices[i].device_status {
+                let cost = model
+                    .estimate(self.devices[i].device_id, device_unit_type, n)
+                    .unwrap_or(f64::INFINITY);
+                if found == NO_AVAILABE_DEVICE || cost < found_cost {
+                    found = i;
+                    found_cost = cost;
+                }
+            }
+        }
+        debug!(
+            unit = device_unit_type.name(),
+            n,
+            device_index = found,
+            estimate_secs = found_cost,
+            "cheapest available device"
+        );
+        Ok(found)
+    }
+
+    /// The GPU ids of the devices that are ready.
+    pub fn ready_device_ids(&self) -> Vec<usize> {
+        let mut device_ids: Vec<usize> = self
+            .devices
+            .iter()
+            .filter(|device| matches!(device.device_status, DeviceStatusType::DeviceStatusReady))
+            .map(|device| device.device_id)
+            .collect();
+        device_ids.sort();
+        device_ids.dedup();
+        device_ids
+    }
+
+    /// Handle a timeout or cancellation of a job on the GPU, which ends the
+    /// call instead of a retry. A timeout quarantines the device, as it may be
+    /// hung with the abandoned call.
+    pub(crate) fn job_limit_error(
+        &mut self,
+        error: DeviceManagerError,
+        device_id: usize,
+    ) -> Option<DeviceManagerError> {
+        match error {
+            DeviceManagerError::DeviceManagerErrorTimeout => {
+                warn!(device_id, "Device job timed out");
+                self.quarantine_device(device_id);
+            }
+            DeviceManagerError::DeviceManagerErrorCancelled => {
+                debug!(device_id, "Device job cancelled");
+            }
+            _ => return None,
+        }
+        self.metrics.record_error(&error);
+        Some(error)
+    }
+
     /// Run the MSM calculation process.
+    ///
+    /// A failing device is retried on the next healthy device, and an error is
+    /// only returned once every device has been tried, so the caller can fall
+    /// back to the CPU.
     pub fn execute_msm<C: CurveAffine>(
         &mut self,
         msm_param_id: usize,
         bases_index: usize,
         scalars: &[C::Scalar],
     ) -> Result<Vec<u8>, DeviceManagerError> {
-        let device_id = self.get_available_device().unwrap();
+        self.execute_msm_with_options::<C>(
+            msm_param_id,
+            bases_index,
+            scalars,
+            &DeviceJobOptions::default(),
+        )
+    }
+
+    /// Run the MSM calculation process with a timeout and cancellation token.
+    ///
+    /// On timeout or cancellation the error is returned without retrying, and
+    /// the device is not scheduled again until its abandoned call returns.
+    pub fn execute_msm_with_options<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases_index: usize,
+        scalars: &[C::Scalar],
+        options: &DeviceJobOptions,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let limits = self.job_limits(options);
+        let span = info_span!(
+            "device_manager.execute_msm",
+            param_id = msm_param_id,
+            bases_index,
+            scalars_num = scalars.len(),
+            device_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+
+        // The bases are not on the devices, see `execute_msm_streaming`.
+        if self.is_msm_param_streamed(msm_param_id) {
+            let error = DeviceManagerError::DeviceManagerErrorOutOfMemory;
+            self.metrics.record_error(&error);
+            return Err(error);
+        }
 
-        let mut msm_result = Vec::<u8>::new();
-        if device_id != NO_AVAILABE_DEVICE {
-            if let Some(device) = self.devices.get_mut(device_id) {
-                device.device_id = device_id;
-                device.device_status = DeviceStatusType::DeviceStatusRunning;
-                // todo Need new type~
-                device.device_unit_type = DeviceUnitType::DeviceUnitTypeMSM;
+        // Trailing zero scalars add nothing, and an MSM of zeros needs no
+        // device at all.
+        let len = scalars
+            .iter()
+            .rposition(|scalar| !scalar.is_zero_vartime())
+            .map_or(0, |last| last + 1);
+        if len < scalars.len() {
+            debug!(
+                dropped = scalars.len() - len,
+                "MSM trailing zero scalars dropped"
+            );
+        }
+        if len == 0 {
+            self.last_device_id = None;
+            return Ok(transmute_values(&[C::Curve::identity()]).to_vec());
+        }
+        let scalars = &scalars[..len];
+
+        self.probe_quarantined_devices();
+
+        // Large MSMs are split across the idle devices holding the bases.
+        let shards = self.plan_msm_shards(msm_param_id, scalars.len());
+        if !shards.is_empty() {
+            return self.execute_msm_sharded::<C>(
+                msm_param_id,
+                bases_index,
+                scalars,
+                &shards,
+                &limits,
+            );
+        }
 
-                let mut found_msm_param_uint: Option<&MSMParamUnit> = None;
+        let start = Instant::now();
+        let mut tried_device_ids = vec![];
+        let mut out_of_memory = false;
+        loop {
+            let device_id = self
+                .get_available_device_by_cost(
+                    DeviceUnitType::DeviceUnitTypeMSM,
+                    scalars.len(),
+                    &tried_device_ids,
+                )
+                .unwrap();
+
+            if device_id == NO_AVAILABE_DEVICE {
+                // The job did not fit into any device that tried it.
+                if out_of_memory {
+                    return Err(DeviceManagerError::DeviceManagerErrorOutOfMemory);
+                }
+                warn!("Execute MSM No available device");
+                let error = DeviceManagerError::DeviceManagerErrorNoAvailableDevice;
+                self.metrics.record_error(&error);
+                return Err(error);
+            }
 
-                for msm_param_uint in self.msm_param_uints.iter() {
-                    if msm_param_uint.param_id == msm_param_id {
+            let device = &mut self.devices[device_id];
+            device.device_status = DeviceStatusType::DeviceStatusRunning;
+            // todo Need new type~
+            device.device_unit_type = DeviceUnitType::DeviceUnitTypeMSM;
+            let gpu_device_id = device.device_id;
+            tried_device_ids.push(gpu_device_id);
+            span.record("device_id", gpu_device_id);
+            self.metrics
+                .record_queue_wait(DeviceUnitType::DeviceUnitTypeMSM, start.elapsed());
+            self.metrics
+                .record_job(DeviceUnitType::DeviceUnitTypeMSM, gpu_device_id);
+
+            let mut found_msm_param_uint: Option<&MSMParamUnit> = None;
+
+            // Prefer the bases uploaded to the chosen GPU.
+            for msm_param_uint in self.msm_param_uints.iter() {
+                if msm_param_uint.param_id == msm_param_id {
+                    if found_msm_param_uint.is_none() || msm_param_uint.device_id == gpu_device_id {
                         found_msm_param_uint = Some(msm_param_uint);
-                        break;
                     }
                 }
-                if let Some(param_uint) = found_msm_param_uint {
-                    let gm = &param_uint.gm.clone();
-                    msm_result = self.session_msm::<C>(gm, scalars, bases_index).unwrap();
-                }
             }
-
-            if let Some(device) = self.devices.get_mut(device_id) {
-                device.device_status = DeviceStatusType::DeviceStatusReady;
-                device.device_unit_type = DeviceUnitType::DeviceUnitTypeNone;
+            let result = match found_msm_param_uint {
+                Some(param_uint) => {
+                    let handle = &param_uint.handle.clone();
+                    self.session_msm::<C>(handle, scalars, bases_index, &limits)
+                }
+                None => Err(DeviceManagerError::DeviceManagerErrorParamIdNone),
+            };
+
+            let device = &mut self.devices[device_id];
+            device.device_status = DeviceStatusType::DeviceStatusReady;
+            device.device_unit_type = DeviceUnitType::DeviceUnitTypeNone;
+
+            match result {
+                Ok(msm_result) => {
+                    self.record_device_success(gpu_device_id);
+                    self.last_device_id = Some(gpu_device_id);
+                    return Ok(msm_result);
+                }
+                // Not a device fault, another device would fail the same way.
+                Err(DeviceManagerError::DeviceManagerErrorParamIdNone) => {
+                    let error = DeviceManagerError::DeviceManagerErrorParamIdNone;
+                    self.metrics.record_error(&error);
+                    return Err(error);
+                }
+                Err(error) => {
+                    if let Some(error) = self.job_limit_error(error.clone(), gpu_device_id) {
+                        return Err(error);
+                    }
+                    warn!(
+                        device_id = gpu_device_id,
+                        error = error.kind(),
+                        "Execute MSM failed, retrying on another device"
+                    );
+                    out_of_memory |=
+                        matches!(error, DeviceManagerError::DeviceManagerErrorOutOfMemory);
+                    self.record_job_failure(gpu_device_id, &error);
+                }
             }
-        } else {
-            println!("Warning: Execute MSM No available device");
-            return Err(DeviceManagerError::DeviceManagerErrorNoAvailableDevice);
         }
-
-        Ok(msm_result)
     }
 
     /// Run the NTT calculation process.
+    ///
+    /// A failing device is retried on the next healthy device. On error the
+    /// input is left untouched, so the caller can fall back to the CPU.
     pub fn execute_ntt<Scalar: Field, G: FftGroup<Scalar>>(
         &mut self,
         scalars: &mut [G],
         log_n: u32,
     ) -> Result<(), DeviceManagerError> {
-        let device_id = self.get_available_device().unwrap();
+        self.execute_ntt_with_options::<Scalar, G>(scalars, log_n, &DeviceJobOptions::default())
+    }
 
-        if device_id != NO_AVAILABE_DEVICE {
-            if let Some(device) = self.devices.get_mut(device_id) {
-                device.device_id = device_id;
-                device.device_status = DeviceStatusType::DeviceStatusRunning;
-                // todo Need new type~
-                device.device_unit_type = DeviceUnitType::DeviceUnitTypeNTT;
+    /// Run the NTT calculation process with a timeout and cancellation token.
+    ///
+    /// On timeout or cancellation the error is returned without retrying and
+    /// the input is left untouched, as `session_ntt` only writes it back on
+    /// success.
+    pub fn execute_ntt_with_options<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        log_n: u32,
+        options: &DeviceJobOptions,
+    ) -> Result<(), DeviceManagerError> {
+        let limits = self.job_limits(options);
+        let span = info_span!(
+            "device_manager.execute_ntt",
+            log_n,
+            scalars_num = scalars.len(),
+            device_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+
+        self.probe_quarantined_devices();
+
+        self.run_ntt_job(
+            DeviceUnitType::DeviceUnitTypeNTT,
+            scalars.len(),
+            &span,
+            |context, handle| context.session_ntt::<Scalar, G>(handle, scalars, log_n, &limits),
+        )
+    }
 
-                let gm = &self.ntt_param_uints[0].gm.clone();
-                self.session_ntt::<Scalar, G>(gm, scalars, log_n).unwrap();
+    /// Run the job on the NTT handle of the cheapest ready device for `n`
+    /// elements of the unit, retrying a failure on the next one.
+    pub(crate) fn run_ntt_job<T>(
+        &mut self,
+        device_unit_type: DeviceUnitType,
+        n: usize,
+        span: &tracing::Span,
+        mut job: impl FnMut(&mut Self, &DeviceHandle) -> Result<T, DeviceManagerError>,
+    ) -> Result<T, DeviceManagerError> {
+        let start = Instant::now();
+        let mut tried_device_ids = vec![];
+        let mut out_of_memory = false;
+        loop {
+            let device_id = self
+                .get_available_device_by_cost(device_unit_type, n, &tried_device_ids)
+                .unwrap();
+
+            if device_id == NO_AVAILABE_DEVICE {
+                // The job did not fit into any device that tried it.
+                if out_of_memory {
+                    return Err(DeviceManagerError::DeviceManagerErrorOutOfMemory);
+                }
+                warn!("Execute NTT No available device");
+                let error = DeviceManagerError::DeviceManagerErrorNoAvailableDevice;
+                self.metrics.record_error(&error);
+                return Err(error);
             }
 
-            if let Some(device) = self.devices.get_mut(device_id) {
-                device.device_status = DeviceStatusType::DeviceStatusReady;
-                device.device_unit_type = DeviceUnitType::DeviceUnitTypeNone;
+            let device = &mut self.devices[device_id];
+            device.device_status = DeviceStatusType::DeviceStatusRunning;
+            // todo Need new type~
+            device.device_unit_type = device_unit_type;
+            let gpu_device_id = device.device_id;
+            tried_device_ids.push(gpu_device_id);
+            span.record("device_id", gpu_device_id);
+            self.metrics
+                .record_queue_wait(device_unit_type, start.elapsed());
+            self.metrics.record_job(device_unit_type, gpu_device_id);
+
+            let ntt_param_uint = self
+                .ntt_param_uints
+                .iter()
+                .find(|unit| unit.device_id == gpu_device_id)
+                .unwrap_or(&self.ntt_param_uints[0]);
+            let handle = &ntt_param_uint.handle.clone();
+            let result = job(self, handle);
+
+            let device = &mut self.devices[device_id];
+            device.device_status = DeviceStatusType::DeviceStatusReady;
+            device.device_unit_type = DeviceUnitType::DeviceUnitTypeNone;
+
+            match result {
+                Ok(value) => {
+                    self.record_device_success(gpu_device_id);
+                    self.last_device_id = Some(gpu_device_id);
+                    return Ok(value);
+                }
+                Err(error) => {
+                    if let Some(error) = self.job_limit_error(error.clone(), gpu_device_id) {
+                        return Err(error);
+                    }
+                    warn!(
+                        device_id = gpu_device_id,
+                        error = error.kind(),
+                        "Execute NTT failed, retrying on another device"
+                    );
+                    out_of_memory |=
+                        matches!(error, DeviceManagerError::DeviceManagerErrorOutOfMemory);
+                    self.record_job_failure(gpu_device_id, &error);
+                }
             }
-        } else {
-            println!("Warning: Execute NTT No available device");
-            return Err(DeviceManagerError::DeviceManagerErrorNoAvailableDevice);
         }
-
-        Ok(())
     }
 
     /// Get the numbere of units of GPU.
@@ -383,10 +832,7 @@ impl DeviceManagerContext {
 
     ///Get device number of GPUs.
     pub fn get_gpu_device_number(&mut self) -> Result<usize, DeviceManagerError> {
-        Ok(panda::gpu_manager::get_device_number()
-            .unwrap()
-            .try_into()
-            .unwrap())
+        self.backend.device_number()
     }
 
     /// Set device for GPU.
@@ -1,88 +1,209 @@
 use super::*;
 
+use crate::arithmetic::{glv_encode, recode_signed_digits, MultiexpScalarKind};
 use crate::poly::Basis;
 use crate::poly::Polynomial;
-use halo2curves::ff::Field;
+use halo2curves::ff::{Field, PrimeField};
 use std::ptr;
+use std::time::Instant;
+use tracing::info_span;
 
 use super::CurveAffine;
-use panda::gpu_manager::unit::*;
-use panda::gpu_manager::wrapper::*;
+
+/// An MSM goes to a backend with `DeviceBackend::supports_msm_sparse` as
+/// its nonzero scalars and their indices when at most this fraction of its
+/// scalars is nonzero.
+pub const DEVICE_MSM_SPARSE_DENSITY: f64 = 0.25;
+
+/// How `session_msm` passes the scalars to the backend.
+#[derive(Clone, Debug)]
+enum MsmScalarEncoding {
+    Raw,
+    /// The nonzero scalars, with their indices as little-endian `u32`.
+    Sparse(Vec<u8>),
+    /// A little-endian `u64` per scalar.
+    Small,
+    /// `recode_signed_digits` in windows of the bits.
+    SignedDigits(usize),
+    /// `glv_encode`.
+    Glv,
+}
+
+/// The indices of the nonzero scalars as little-endian `u32` and the bytes of
+/// these scalars, if at most `DEVICE_MSM_SPARSE_DENSITY` of them are nonzero.
+fn sparse_scalars_bytes<F: PrimeField>(scalars: &[F]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let nonzero = scalars
+        .iter()
+        .filter(|scalar| !scalar.is_zero_vartime())
+        .count();
+    if nonzero as f64 > DEVICE_MSM_SPARSE_DENSITY * scalars.len() as f64 {
+        return None;
+    }
+
+    let mut indices = Vec::with_capacity(nonzero * 4);
+    let mut values = Vec::with_capacity(nonzero);
+    for (i, scalar) in scalars.iter().enumerate() {
+        if !scalar.is_zero_vartime() {
+            indices.extend_from_slice(&(i as u32).to_le_bytes());
+            values.push(*scalar);
+        }
+    }
+    Some((indices, transmute_values(&values).to_vec()))
+}
+
+/// The scalars as little-endian `u64`, if they all fit.
+fn small_scalars_bytes<F: PrimeField>(scalars: &[F]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(scalars.len() * 8);
+    for scalar in scalars {
+        let value = match MultiexpScalarKind::of(scalar) {
+            MultiexpScalarKind::Zero => 0,
+            MultiexpScalarKind::One => 1,
+            MultiexpScalarKind::Small(value) => value,
+            MultiexpScalarKind::Large => return None,
+        };
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    Some(bytes)
+}
 
 impl DeviceManagerContext {
     /// The core session of the MSM computation execution.
+    ///
+    /// The scalars are copied for the device call, so that a call past the
+    /// limits can be abandoned, see `run_device_job`.
     pub fn session_msm<C: CurveAffine>(
         &mut self,
-        gm: &PandaGpuManager,
+        handle: &DeviceHandle,
         scalars: &[C::Scalar],
         bases_index: usize,
+        limits: &DeviceJobLimits,
     ) -> Result<Vec<u8>, DeviceManagerError> {
-        // Convert scalars to bytes using transmute_values
-        let scalars_bytes = transmute_values(scalars.as_ref().as_ref());
-
-        // Call panda_msm_bn254_gpu and unwrap the result
-        let mut msm_result = panda_msm_bn254_gpu(gm, scalars_bytes, bases_index).unwrap();
-
-        // Create a vector to hold G1 values with the desired capacity
-        let mut values = Vec::<G1>::with_capacity(MSM_EXECUTION_RESULT_NUM);
-
-        // Get pointers to the vectors' data
-        let values_ptr = values.as_mut_ptr() as *mut u8;
-        let msm_result_ptr = msm_result.as_mut_ptr();
-        let size = std::mem::size_of::<u8>() * msm_result.len();
-
-        // Copy `msm_result` into `values`
-        unsafe {
-            std::ptr::copy_nonoverlapping(msm_result_ptr, values_ptr, size);
-        }
-
-        // Release the ownership of `msm_result`
-        std::mem::forget(msm_result);
-
-        // Set the length of `values` to `count`
-        unsafe { values.set_len(MSM_EXECUTION_RESULT_NUM) };
-
-        let mut sum = G1::zero();
-        let mut running_sum = G1::zero();
-
-        for bucket in values.iter().rev() {
-            running_sum.double();
-            running_sum.add_assign(bucket);
-        }
-        sum.add_assign(&running_sum);
-
-        let mut result_values = vec![0u8; BN256_PROJECTIVE_BYTES];
-        let result_values_ptr = result_values.as_mut_ptr();
-
-        // Copy `sum` into `result_values`
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                &sum as *const G1 as *const u8,
-                result_values_ptr,
-                BN256_PROJECTIVE_BYTES,
-            );
-        }
-
-        // Set the length of `result_values` to `BN256_PROJECTIVE_BYTES`
-        unsafe { result_values.set_len(BN256_PROJECTIVE_BYTES) };
-
-        Ok(result_values)
+        let _session_span = info_span!(
+            "device_manager.session_msm",
+            bases_index,
+            scalars_num = scalars.len()
+        )
+        .entered();
+
+        let phase_span = info_span!("encode").entered();
+        // Backends that take sparse scalars get the nonzero ones when there
+        // are few, backends that take small scalars get them as `u64` when
+        // they all fit, backends that take GLV halves or signed digits get the
+        // scalars decomposed or recoded, others the raw scalars.
+        let sparse_bytes = if self.backend.supports_msm_sparse() {
+            sparse_scalars_bytes(scalars)
+        } else {
+            None
+        };
+        let small_bytes = if sparse_bytes.is_some() {
+            None
+        } else if self.backend.supports_msm_small() {
+            small_scalars_bytes(scalars)
+        } else {
+            None
+        };
+        let glv_bytes = if small_bytes.is_some() {
+            None
+        } else if self.backend.supports_msm_glv() {
+            glv_encode::<C>(scalars)
+        } else {
+            None
+        };
+        let window = self.backend.msm_signed_digit_window(scalars.len());
+        let (encoding, scalars_bytes) = match (sparse_bytes, small_bytes, glv_bytes, window) {
+            (Some((indices, values)), _, _, _) => (MsmScalarEncoding::Sparse(indices), values),
+            (None, Some(small_bytes), _, _) => (MsmScalarEncoding::Small, small_bytes),
+            (None, None, Some(glv_bytes), _) => (MsmScalarEncoding::Glv, glv_bytes),
+            (None, None, None, Some(window)) => {
+                let digits: Vec<i32> = scalars
+                    .iter()
+                    .flat_map(|scalar| recode_signed_digits(scalar.to_repr().as_ref(), window))
+                    .map(|digit| digit as i32)
+                    .collect();
+                (
+                    MsmScalarEncoding::SignedDigits(window),
+                    transmute_values(&digits).to_vec(),
+                )
+            }
+            // Convert scalars to bytes using transmute_values
+            (None, None, None, None) => (
+                MsmScalarEncoding::Raw,
+                transmute_values(scalars.as_ref().as_ref()).to_vec(),
+            ),
+        };
+        let scalars_bytes_len = match &encoding {
+            MsmScalarEncoding::Sparse(indices) => indices.len() + scalars_bytes.len(),
+            _ => scalars_bytes.len(),
+        };
+        drop(phase_span);
+
+        // The device call includes the reduction of the buckets on the host.
+        let phase_span = info_span!("compute", bytes = scalars_bytes_len).entered();
+        let compute_start = Instant::now();
+        let job_handle = handle.clone();
+        let msm_result =
+            self.run_device_job(handle.device_id, limits, move |backend| match encoding {
+                MsmScalarEncoding::Raw => backend.msm(&job_handle, &scalars_bytes, bases_index),
+                MsmScalarEncoding::Sparse(indices) => {
+                    backend.msm_sparse(&job_handle, &indices, &scalars_bytes, bases_index)
+                }
+                MsmScalarEncoding::Small => {
+                    backend.msm_small(&job_handle, &scalars_bytes, bases_index)
+                }
+                MsmScalarEncoding::SignedDigits(window) => {
+                    backend.msm_signed_digits(&job_handle, &scalars_bytes, window, bases_index)
+                }
+                MsmScalarEncoding::Glv => backend.msm_glv(&job_handle, &scalars_bytes, bases_index),
+            })?;
+        self.record_msm_throughput(handle.device_id, scalars.len(), compute_start.elapsed());
+        self.metrics
+            .record_compute(DeviceUnitType::DeviceUnitTypeMSM, compute_start.elapsed());
+        self.metrics.record_bytes(
+            DeviceUnitType::DeviceUnitTypeMSM,
+            scalars_bytes_len,
+            msm_result.len(),
+        );
+        drop(phase_span);
+
+        Ok(msm_result)
     }
 
     /// The core session of the NTT computation execution.
+    ///
+    /// The device works on a copy of the scalars, which is written back only
+    /// on success.
     pub fn session_ntt<Scalar: Field, G: FftGroup<Scalar>>(
         &mut self,
-        gm: &PandaGpuManager,
+        handle: &DeviceHandle,
         scalars: &mut [G],
         log_n: u32,
+        limits: &DeviceJobLimits,
     ) -> Result<(), DeviceManagerError> {
-        //let time = start_timer!(|| "[device manager][ntt session] transmute scalars");
-        let scalars_bytes = transmute_values_mut(scalars.as_ref().as_ref());
-        //end_timer!(time);
-
-        //let time = start_timer!(|| "[device manager][ntt session] gpu run ntt");
-        panda_ntt_bn254_gpu(gm, scalars_bytes, log_n).unwrap();
-        //end_timer!(time);
+        let _session_span = info_span!(
+            "device_manager.session_ntt",
+            log_n,
+            scalars_num = scalars.len()
+        )
+        .entered();
+
+        let mut job_bytes = transmute_values(scalars).to_vec();
+        let bytes_len = job_bytes.len();
+
+        // The transfers are fused into the device call, so they are part of this span.
+        let phase_span = info_span!("compute", bytes = bytes_len).entered();
+        let compute_start = Instant::now();
+        let job_handle = handle.clone();
+        let job_bytes = self.run_device_job(handle.device_id, limits, move |backend| {
+            backend.ntt(&job_handle, &mut job_bytes, log_n)?;
+            Ok(job_bytes)
+        })?;
+        transmute_values_mut(scalars).copy_from_slice(&job_bytes);
+        self.metrics
+            .record_compute(DeviceUnitType::DeviceUnitTypeNTT, compute_start.elapsed());
+        // The NTT runs in place, so the same bytes go to the device and back.
+        self.metrics
+            .record_bytes(DeviceUnitType::DeviceUnitTypeNTT, bytes_len, bytes_len);
+        drop(phase_span);
 
         Ok(())
     }
@@ -0,0 +1,161 @@
+use super::*;
+
+use crate::arithmetic::{fixed_base_precompute, glv_endo_bases, FixedBaseConfig};
+use std::any::Any;
+use std::time::Instant;
+use tracing::{debug, info, info_span, warn};
+
+/// The fixed-base table of the bases of a param on the host, see
+/// `fixed_base_precompute`.
+#[derive(Clone, Debug)]
+pub struct DeviceFixedBaseTable {
+    ///
+    pub config: FixedBaseConfig,
+    /// The GPUs whose backend keeps the table too.
+    pub device_ids: Vec<usize>,
+    /// A `Vec` of the affine points of the curve of the param.
+    points: Arc<dyn Any + Send + Sync>,
+}
+
+impl DeviceFixedBaseTable {
+    /// The points of the table, if the param is on the curve of `C`.
+    pub fn points<C: CurveAffine>(&self) -> Option<Arc<Vec<C>>> {
+        self.points.clone().downcast::<Vec<C>>().ok()
+    }
+}
+
+/// The endomorphisms of the bases of a param on the host, see
+/// `glv_endo_bases`.
+#[derive(Clone, Debug)]
+pub struct DeviceGlvBases {
+    /// A `Vec` of the affine points of the curve of the param.
+    points: Arc<dyn Any + Send + Sync>,
+}
+
+impl DeviceGlvBases {
+    /// The endomorphisms of the bases, if the param is on the curve of `C`.
+    pub fn points<C: CurveAffine>(&self) -> Option<Arc<Vec<C>>> {
+        self.points.clone().downcast::<Vec<C>>().ok()
+    }
+}
+
+impl DeviceManagerContext {
+    /// Precompute a fixed-base table of the bases of the param at its
+    /// registration with `init_fixed_base`, or not with `None`. The table
+    /// takes `config.tables` times the memory of the bases.
+    pub fn set_fixed_base_config(&mut self, msm_param_id: usize, config: Option<FixedBaseConfig>) {
+        match config {
+            Some(config) => self.fixed_base_configs.insert(msm_param_id, config),
+            None => self.fixed_base_configs.remove(&msm_param_id),
+        };
+    }
+
+    /// The fixed-base table of the bases at `bases_index` of the param.
+    pub fn fixed_base_table(
+        &self,
+        msm_param_id: usize,
+        bases_index: usize,
+    ) -> Option<&DeviceFixedBaseTable> {
+        self.fixed_base_tables.get(&(msm_param_id, bases_index))
+    }
+
+    /// The endomorphisms of the bases at `bases_index` of the param.
+    pub fn glv_bases(&self, msm_param_id: usize, bases_index: usize) -> Option<&DeviceGlvBases> {
+        self.glv_bases.get(&(msm_param_id, bases_index))
+    }
+
+    /// Compute the endomorphisms of the bases of the param for its CPU MSMs,
+    /// if the curve has GLV. They take the memory of the bases again.
+    pub fn init_glv_bases<C: CurveAffine>(&mut self, msm_param_id: usize, bases: &[&[C]]) {
+        for (bases_index, bases) in bases.iter().enumerate() {
+            if let Some(points) = glv_endo_bases(bases) {
+                self.glv_bases.insert(
+                    (msm_param_id, bases_index),
+                    DeviceGlvBases {
+                        points: Arc::new(points),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Precompute the fixed-base tables of the bases of the param if it has a
+    /// config, and offer them to the backend on each device holding the
+    /// bases. The CPU MSMs of the param use the tables on the host.
+    ///
+    /// A backend that fails to take a table is only logged, its MSMs keep
+    /// using the raw bases.
+    pub fn init_fixed_base<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases: &[&[C]],
+    ) -> Result<(), DeviceManagerError> {
+        let config = match self.fixed_base_configs.get(&msm_param_id) {
+            Some(config) => *config,
+            None => return Ok(()),
+        };
+        let _span = info_span!(
+            "device_manager.init_fixed_base",
+            param_id = msm_param_id,
+            window = config.window,
+            tables = config.tables,
+        )
+        .entered();
+
+        // The bases of streamed params are not on the devices.
+        let handles: Vec<DeviceHandle> = if self.is_msm_param_streamed(msm_param_id) {
+            vec![]
+        } else {
+            self.msm_param_uints
+                .iter()
+                .filter(|unit| unit.param_id == msm_param_id)
+                .map(|unit| unit.handle.clone())
+                .collect()
+        };
+
+        for (bases_index, bases) in bases.iter().enumerate() {
+            let start = Instant::now();
+            let points = fixed_base_precompute(bases, &config);
+            info!(
+                bases_index,
+                n = bases.len(),
+                bytes = std::mem::size_of_val(&points[..]),
+                secs = start.elapsed().as_secs_f64(),
+                "Fixed-base table precomputed"
+            );
+
+            let mut device_ids = vec![];
+            for handle in handles.iter() {
+                let table = transmute_values(&points[..]);
+                match self
+                    .backend
+                    .init_fixed_base(handle, bases_index, table, &config)
+                {
+                    Ok(true) => device_ids.push(handle.device_id),
+                    Ok(false) => {
+                        debug!(
+                            device_id = handle.device_id,
+                            "Fixed-base table not kept by the device"
+                        )
+                    }
+                    Err(error) => warn!(
+                        device_id = handle.device_id,
+                        error = error.kind(),
+                        "Fixed-base table upload failed"
+                    ),
+                }
+            }
+
+            self.fixed_base_tables.insert(
+                (msm_param_id, bases_index),
+                DeviceFixedBaseTable {
+                    config,
+                    device_ids,
+                    points: Arc::new(points),
+                },
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use super::*;
+
+use crate::arithmetic::parallelize;
+use halo2curves::bn256::Fr;
+use halo2curves::ff::{Field, PrimeField};
+use rayon::prelude::*;
+use std::time::Instant;
+use tracing::{debug, info_span, warn};
+
+/// The primitive `2^log_n`-th root of unity the devices use, a power of
+/// `Fr::ROOT_OF_UNITY` as in halo2's evaluation domains.
+pub fn device_ntt_root(log_n: u32) -> Fr {
+    let mut root = Fr::ROOT_OF_UNITY;
+    for _ in log_n..Fr::S {
+        root = root.square();
+    }
+    root
+}
+
+/// The transposition of the row-major `rows x cols` matrix `input`.
+fn transpose<G: Copy + Send + Sync>(input: &[G], rows: usize, cols: usize) -> Vec<G> {
+    assert_eq!(input.len(), rows * cols);
+    (0..rows * cols)
+        .into_par_iter()
+        .map(|i| input[(i % rows) * cols + i / rows])
+        .collect()
+}
+
+/// Multiply the element `k` of the row `j` of the row-major `rows x cols`
+/// matrix by `omega^(j * k)`.
+fn multiply_twiddles<Scalar: Field, G: FftGroup<Scalar>>(
+    values: &mut [G],
+    omega: Scalar,
+    cols: usize,
+) {
+    parallelize(values, |values, start| {
+        let mut row = start / cols;
+        let mut step = omega.pow_vartime([row as u64]);
+        let mut twiddle = step.pow_vartime([(start % cols) as u64]);
+        for (i, value) in values.iter_mut().enumerate() {
+            if (start + i) % cols == 0 && i != 0 {
+                row += 1;
+                step = omega.pow_vartime([row as u64]);
+                twiddle = Scalar::ONE;
+            }
+            *value *= twiddle;
+            twiddle *= step;
+        }
+    });
+}
+
+impl DeviceManagerContext {
+    /// The GPUs with an NTT unit that are healthy and idle.
+    pub fn ready_ntt_device_ids(&self) -> Vec<usize> {
+        let mut device_ids: Vec<usize> = self
+            .ntt_param_uints
+            .iter()
+            .map(|unit| unit.device_id)
+            .filter(|device_id| {
+                !self.is_device_busy(*device_id)
+                    && self
+                        .devices
+                        .iter()
+                        .filter(|device| device.device_id == *device_id)
+                        .all(|device| device.device_status == DeviceStatusType::DeviceStatusReady)
+            })
+            .collect();
+        device_ids.sort();
+        device_ids.dedup();
+        device_ids
+    }
+
+    /// Run a large NTT across the devices with `execute_ntt_four_step`, one
+    /// larger than their memory with `execute_ntt_out_of_core`, or a smaller
+    /// one on one device with `execute_ntt_with_options`.
+    ///
+    /// `omega` must be `device_ntt_root(log_n)` or its inverse, otherwise
+    /// this returns `DeviceManagerErrorExecute` for the caller to run it on
+    /// the host.
+    pub fn execute_ntt_multi_device<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        omega: Scalar,
+        log_n: u32,
+        options: &DeviceJobOptions,
+    ) -> Result<(), DeviceManagerError> {
+        // The inverse transform is the forward one with its outputs but the
+        // first in reverse order.
+        let root = device_ntt_root(log_n);
+        let inverse = if transmute_values(&[omega]) == transmute_values(&[root]) {
+            false
+        } else if transmute_values(&[omega]) == transmute_values(&[root.invert().unwrap()]) {
+            true
+        } else {
+            debug!(log_n, "omega is not a root of unity of the devices");
+            return Err(DeviceManagerError::DeviceManagerErrorExecute);
+        };
+        let omega = if inverse {
+            omega.invert().unwrap()
+        } else {
+            omega
+        };
+
+        self.run_ntt_multi_device::<Scalar, G>(scalars, omega, log_n, options)?;
+        if inverse {
+            scalars[1..].reverse();
+        }
+        Ok(())
+    }
+
+    /// `execute_ntt_multi_device` with the root of unity of the devices.
+    fn run_ntt_multi_device<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        omega: Scalar,
+        log_n: u32,
+        options: &DeviceJobOptions,
+    ) -> Result<(), DeviceManagerError> {
+        self.probe_quarantined_devices();
+        let device_ids = self.ready_ntt_device_ids();
+        let limits = self.job_limits(options);
+
+        if let Some(max_log_n) = self.max_device_ntt_log_n::<G>(&device_ids) {
+            if log_n > max_log_n {
+                return self.execute_ntt_out_of_core::<Scalar, G>(
+                    scalars,
+                    omega,
+                    log_n,
+                    max_log_n,
+                    &device_ids,
+                    &limits,
+                );
+            }
+        }
+
+        let large = matches!(&self.shard_config, Some(config) if log_n >= config.min_ntt_log_n);
+        if large && device_ids.len() > 1 {
+            return self.execute_ntt_four_step::<Scalar, G>(
+                scalars,
+                omega,
+                log_n,
+                &device_ids,
+                &limits,
+            );
+        }
+        self.execute_ntt_with_options::<Scalar, G>(scalars, log_n, options)
+    }
+
+    /// The four-step NTT over the devices.
+    ///
+    /// The `2^log_n` scalars are a `n1 x n2` matrix, row-major. The devices run
+    /// the `n2` NTTs of size `n1` of its columns, then the host multiplies the
+    /// element `k1` of the column `j2` by `omega^(j2 * k1)`, and the devices
+    /// run the `n1` NTTs of size `n2` of its rows. The element `k2` of the row
+    /// `k1` is the output `k1 + n1 * k2`. The sub-transforms use the roots of
+    /// unity the devices have for their size, which are powers of `omega` for
+    /// the standard roots halo2 uses.
+    ///
+    /// The scalars are only written when every step succeeded.
+    pub fn execute_ntt_four_step<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        omega: Scalar,
+        log_n: u32,
+        device_ids: &[usize],
+        limits: &DeviceJobLimits,
+    ) -> Result<(), DeviceManagerError> {
+        let log_n1 = (log_n + 1) / 2;
+        self.run_ntt_four_step::<Scalar, G>(scalars, omega, log_n, log_n1, device_ids, limits)
+    }
+
+    /// The four-step NTT with columns of `2^log_n1` elements.
+    pub(crate) fn run_ntt_four_step<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        omega: Scalar,
+        log_n: u32,
+        log_n1: u32,
+        device_ids: &[usize],
+        limits: &DeviceJobLimits,
+    ) -> Result<(), DeviceManagerError> {
+        let log_n2 = log_n - log_n1;
+        let (n1, n2) = (1usize << log_n1, 1usize << log_n2);
+        assert_eq!(scalars.len(), n1 * n2);
+
+        let _span = info_span!(
+            "device_manager.execute_ntt_four_step",
+            log_n,
+            log_n1,
+            log_n2,
+            device_num = device_ids.len(),
+        )
+        .entered();
+        let start = Instant::now();
+
+        let mut columns = transpose(scalars, n1, n2);
+        self.run_ntt_batch::<Scalar, G>(&mut columns, log_n1, device_ids, limits)?;
+
+        multiply_twiddles::<Scalar, G>(&mut columns, omega, n1);
+
+        let mut rows = transpose(&columns, n2, n1);
+        drop(columns);
+        self.run_ntt_batch::<Scalar, G>(&mut rows, log_n2, device_ids, limits)?;
+
+        scalars.copy_from_slice(&transpose(&rows, n1, n2));
+
+        let bytes = std::mem::size_of_val(scalars);
+        self.metrics
+            .record_compute(DeviceUnitType::DeviceUnitTypeNTT, start.elapsed());
+        self.metrics
+            .record_bytes(DeviceUnitType::DeviceUnitTypeNTT, bytes * 2, bytes * 2);
+        self.last_device_id = None;
+
+        Ok(())
+    }
+
+    /// Run the NTTs of size `2^log_sub` of the consecutive parts of `values`,
+    /// a share of them on each device at once. The share of a failed device
+    /// is retried on a device that finished its own.
+    ///
+    /// A share is one `ntt_batch` launch, or as many as it takes for each to
+    /// fit into the memory of the devices.
+    fn run_ntt_batch<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        values: &mut [G],
+        log_sub: u32,
+        device_ids: &[usize],
+        limits: &DeviceJobLimits,
+    ) -> Result<(), DeviceManagerError> {
+        let count = values.len() >> log_sub;
+        let share_len = ((count + device_ids.len() - 1) / device_ids.len()) << log_sub;
+        let launch_len = match self.max_device_ntt_log_n::<G>(device_ids) {
+            Some(max_log_n) => share_len.min(1 << max_log_n.max(log_sub)),
+            None => share_len,
+        };
+        let sub_bytes = std::mem::size_of::<G>() << log_sub;
+        let launch_bytes = sub_bytes * (launch_len >> log_sub);
+        limits.check()?;
+
+        let handle_of = |context: &Self, device_id: usize| {
+            context
+                .ntt_param_uints
+                .iter()
+                .find(|unit| unit.device_id == device_id)
+                .map(|unit| unit.handle.clone())
+                .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)
+        };
+        let spawn = |context: &Self, handle: DeviceHandle, mut bytes: Vec<u8>| {
+            context.spawn_device_job(handle.device_id, move |backend| {
+                for launch in bytes.chunks_mut(launch_bytes) {
+                    let batch = launch.len() / sub_bytes;
+                    backend.ntt_batch(&handle, launch, log_sub, batch)?;
+                }
+                Ok(bytes)
+            })
+        };
+
+        let mut pending = vec![];
+        for (share, device_id) in values.chunks(share_len).zip(device_ids.iter()) {
+            let handle = handle_of(self, *device_id)?;
+            self.set_device_status(*device_id, DeviceStatusType::DeviceStatusRunning);
+            self.metrics
+                .record_job(DeviceUnitType::DeviceUnitTypeNTT, *device_id);
+            let bytes = transmute_values(share).to_vec();
+            pending.push((*device_id, spawn(self, handle, bytes)));
+        }
+
+        let mut failed = vec![];
+        let mut finished_device_ids = vec![];
+        let mut limit_error = None;
+        for ((device_id, pending), share) in pending.into_iter().zip(values.chunks_mut(share_len)) {
+            let result = match limit_error {
+                // Abandon the remaining shares.
+                Some(_) => Err(DeviceManagerError::DeviceManagerErrorCancelled),
+                None => pending.wait(limits),
+            };
+            self.set_device_status(device_id, DeviceStatusType::DeviceStatusReady);
+            match result {
+                Ok(bytes) => {
+                    transmute_values_mut(share).copy_from_slice(&bytes);
+                    self.record_device_success(device_id);
+                    finished_device_ids.push(device_id);
+                }
+                Err(error) if limit_error.is_some() => {
+                    debug!(device_id, error = error.kind(), "NTT share abandoned");
+                }
+                Err(error) => {
+                    if let Some(error) = self.job_limit_error(error.clone(), device_id) {
+                        limit_error = Some(error);
+                        continue;
+                    }
+                    warn!(
+                        device_id,
+                        error = error.kind(),
+                        "NTT share failed, retrying on another device"
+                    );
+                    self.record_job_failure(device_id, &error);
+                    failed.push(share);
+                }
+            }
+        }
+        if let Some(error) = limit_error {
+            return Err(error);
+        }
+
+        for share in failed {
+            let mut result = Err(DeviceManagerError::DeviceManagerErrorNoAvailableDevice);
+            for device_id in finished_device_ids.clone() {
+                let handle = handle_of(self, device_id)?;
+                self.metrics
+                    .record_job(DeviceUnitType::DeviceUnitTypeNTT, device_id);
+                limits.check()?;
+                let bytes = transmute_values(share).to_vec();
+                result = spawn(self, handle, bytes).wait(limits);
+                match &result {
+                    Ok(_) => break,
+                    Err(error) => {
+                        if let Some(error) = self.job_limit_error(error.clone(), device_id) {
+                            return Err(error);
+                        }
+                        self.record_job_failure(device_id, error);
+                        finished_device_ids.retain(|id| *id != device_id);
+                    }
+                }
+            }
+            match result {
+                Ok(bytes) => transmute_values_mut(share).copy_from_slice(&bytes),
+                Err(error) => {
+                    warn!("Execute four-step NTT No available device");
+                    self.metrics.record_error(&error);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ntt_four_step_host_steps() {
+    let values: Vec<Fr> = (0..12u64).map(Fr::from).collect();
+    let transposed = transpose(&values, 3, 4);
+    let expected: Vec<Fr> = [0u64, 4, 8, 1, 5, 9, 2, 6, 10, 3, 7, 11]
+        .into_iter()
+        .map(Fr::from)
+        .collect();
+    assert_eq!(transposed, expected);
+
+    let omega = Fr::from(3);
+    let mut twiddled = vec![Fr::ONE; 12];
+    multiply_twiddles::<Fr, Fr>(&mut twiddled, omega, 4);
+    for (i, value) in twiddled.iter().enumerate() {
+        assert_eq!(*value, omega.pow_vartime([((i / 4) * (i % 4)) as u64]));
+    }
+}
@@ -0,0 +1,198 @@
+use super::*;
+
+use crate::arithmetic::{best_fft_cpu, best_multiexp_cpu};
+use halo2curves::bn256::{Fr, G1Affine};
+use halo2curves::ff::Field;
+use rand_core::OsRng;
+use std::time::{Duration, Instant};
+use tracing::{info, info_span, warn};
+
+/// When to quarantine a failing device and when to probe it again.
+#[derive(Clone, Debug)]
+pub struct DeviceHealthConfig {
+    /// Consecutive failures after which the device is quarantined.
+    pub failure_threshold: usize,
+    /// How long a quarantined device waits before its next self-test.
+    pub probe_interval: Duration,
+    /// The size of the self-test NTT is `2^probe_log_n`.
+    pub probe_log_n: u32,
+}
+
+impl Default for DeviceHealthConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            probe_interval: Duration::from_secs(30),
+            probe_log_n: 10,
+        }
+    }
+}
+
+impl DeviceManagerContext {
+    ///
+    pub fn set_health_config(&mut self, health_config: DeviceHealthConfig) {
+        self.health_config = health_config;
+    }
+
+    /// Count a failed job on the GPU and quarantine all of its units once the
+    /// failure threshold is reached.
+    pub fn record_device_failure(&mut self, device_id: usize) {
+        let threshold = self.health_config.failure_threshold;
+        for device in self.devices.iter_mut() {
+            if device.device_id != device_id {
+                continue;
+            }
+            device.consecutive_failures += 1;
+            if device.consecutive_failures >= threshold
+                && device.device_status != DeviceStatusType::DeviceStatusError
+            {
+                warn!(
+                    device_id,
+                    failures = device.consecutive_failures,
+                    "Device quarantined"
+                );
+                device.device_status = DeviceStatusType::DeviceStatusError;
+                device.quarantined_at = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Count a failed job on the GPU, unless the job did not fit into its
+    /// memory, which is no fault of the device.
+    pub fn record_job_failure(&mut self, device_id: usize, error: &DeviceManagerError) {
+        self.metrics.record_error(error);
+        if !matches!(error, DeviceManagerError::DeviceManagerErrorOutOfMemory) {
+            self.record_device_failure(device_id);
+        }
+    }
+
+    /// Quarantine all units of the GPU at once, for faults that a retry
+    /// cannot be trusted to catch such as wrong results.
+    pub fn quarantine_device(&mut self, device_id: usize) {
+        warn!(device_id, "Device quarantined");
+        for device in self.devices.iter_mut() {
+            if device.device_id == device_id {
+                device.consecutive_failures += 1;
+                device.device_status = DeviceStatusType::DeviceStatusError;
+                device.quarantined_at = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Reset the failure count of the GPU after a successful job.
+    pub fn record_device_success(&mut self, device_id: usize) {
+        for device in self.devices.iter_mut() {
+            if device.device_id == device_id {
+                device.consecutive_failures = 0;
+            }
+        }
+    }
+
+    /// The GPU ids of the quarantined devices.
+    pub fn quarantined_device_ids(&self) -> Vec<usize> {
+        let mut device_ids: Vec<usize> = self
+            .devices
+            .iter()
+            .filter(|device| device.device_status == DeviceStatusType::DeviceStatusError)
+            .map(|device| device.device_id)
+            .collect();
+        device_ids.sort();
+        device_ids.dedup();
+        device_ids
+    }
+
+    /// Self-test the quarantined devices whose probe interval has passed, and
+    /// readmit the ones that pass.
+    pub fn probe_quarantined_devices(&mut self) {
+        let probe_interval = self.health_config.probe_interval;
+        let due_device_ids: Vec<usize> = self
+            .quarantined_device_ids()
+            .into_iter()
+            .filter(|device_id| {
+                self.devices.iter().any(|device| {
+                    device.device_id == *device_id
+                        && device
+                            .quarantined_at
+                            .map_or(true, |at| at.elapsed() >= probe_interval)
+                })
+            })
+            .collect();
+
+        for device_id in due_device_ids {
+            let passed = self.self_test_device(device_id);
+            for device in self.devices.iter_mut() {
+                if device.device_id != device_id {
+                    continue;
+                }
+                if passed {
+                    device.device_status = DeviceStatusType::DeviceStatusReady;
+                    device.consecutive_failures = 0;
+                    device.quarantined_at = None;
+                } else {
+                    device.quarantined_at = Some(Instant::now());
+                }
+            }
+            if passed {
+                info!(device_id, "Device passed its self-test and was readmitted");
+            } else {
+                warn!(device_id, "Device failed its self-test");
+            }
+        }
+    }
+
+    /// Run a small NTT and MSM of random values on the GPU and check them
+    /// against `best_fft_cpu` and `best_multiexp_cpu`.
+    ///
+    /// The MSM is capped at the bases kept by `init` for the param. Its
+    /// random scalars are dense, so it reaches the device rather than the
+    /// shortcuts for sparse or zero scalars.
+    pub fn self_test_device(&mut self, device_id: usize) -> bool {
+        let _span = info_span!("device_manager.self_test", device_id).entered();
+        let log_n = self.health_config.probe_log_n;
+        let limits = self.job_limits(&DeviceJobOptions::default());
+
+        let ntt_handle = self
+            .ntt_param_uints
+            .iter()
+            .find(|unit| unit.device_id == device_id)
+            .map(|unit| unit.handle.clone());
+        if let Some(handle) = ntt_handle {
+            let mut values: Vec<Fr> = (0..1 << log_n).map(|_| Fr::random(OsRng)).collect();
+            let mut expected = values.clone();
+            best_fft_cpu(&mut expected, device_ntt_root(log_n), log_n);
+            match self.session_ntt::<Fr, Fr>(&handle, &mut values, log_n, &limits) {
+                Ok(()) if values == expected => {}
+                _ => return false,
+            }
+        }
+
+        // The bases of a streamed param are not on the device.
+        let msm_unit = self
+            .msm_param_uints
+            .iter()
+            .find(|unit| {
+                unit.device_id == device_id
+                    && !self.is_msm_param_streamed(unit.param_id)
+                    && self.probe_bases.contains_key(&unit.param_id)
+            })
+            .map(|unit| (unit.param_id, unit.handle.clone()));
+        if let Some((param_id, handle)) = msm_unit {
+            let bases = match values_from_bytes::<G1Affine>(&self.probe_bases[&param_id]) {
+                Ok(bases) => bases,
+                Err(_) => return false,
+            };
+            let bases = &bases[..bases.len().min(1 << log_n)];
+            let scalars: Vec<Fr> = bases.iter().map(|_| Fr::random(OsRng)).collect();
+            let result = match self.session_msm::<G1Affine>(&handle, &scalars, 0, &limits) {
+                Ok(result) => result,
+                Err(_) => return false,
+            };
+            match curve_from_bytes::<G1Affine>(&result) {
+                Ok(point) if point == best_multiexp_cpu(&scalars, bases) => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
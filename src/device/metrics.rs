@@ -0,0 +1,365 @@
+use super::*;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the time histogram buckets.
+const DEVICE_METRICS_TIME_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// A histogram of durations with fixed buckets.
+#[derive(Clone, Debug)]
+pub struct DeviceMetricsHistogram {
+    /// Upper bounds in seconds, the last bucket is `+Inf`.
+    pub bounds: Vec<f64>,
+    /// Non-cumulative count of each bucket, with one extra for `+Inf`.
+    pub counts: Vec<u64>,
+    ///
+    pub sum: f64,
+    ///
+    pub count: u64,
+}
+
+impl Default for DeviceMetricsHistogram {
+    fn default() -> Self {
+        Self {
+            bounds: DEVICE_METRICS_TIME_BUCKETS.to_vec(),
+            counts: vec![0; DEVICE_METRICS_TIME_BUCKETS.len() + 1],
+            sum: 0f64,
+            count: 0,
+        }
+    }
+}
+
+impl DeviceMetricsHistogram {
+    ///
+    pub fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// Add the observations of a histogram with the same buckets.
+    pub fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+fn merge_counters<K: Ord + Clone>(into: &mut BTreeMap<K, u64>, from: &BTreeMap<K, u64>) {
+    for (key, value) in from.iter() {
+        *into.entry(key.clone()).or_insert(0) += value;
+    }
+}
+
+fn merge_histograms<K: Ord + Clone>(
+    into: &mut BTreeMap<K, DeviceMetricsHistogram>,
+    from: &BTreeMap<K, DeviceMetricsHistogram>,
+) {
+    for (key, value) in from.iter() {
+        into.entry(key.clone()).or_default().merge(value);
+    }
+}
+
+/// Counters and histograms of the device jobs.
+///
+/// Jobs are labelled by computation unit and GPU id, times by unit, and
+/// errors by `DeviceManagerError::kind`.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMetrics {
+    /// Jobs run, by (unit, device id).
+    pub jobs: BTreeMap<(&'static str, usize), u64>,
+    /// Time the wrappers such as `best_multiexp_gpu` waited in the job queue
+    /// and for the lock of the global device manager, by unit.
+    pub job_wait_secs: BTreeMap<&'static str, DeviceMetricsHistogram>,
+    /// Time from entering `execute_*` until a device was acquired, by unit.
+    pub queue_wait_secs: BTreeMap<&'static str, DeviceMetricsHistogram>,
+    /// Time spent in the device call and the host reduction, by unit. The
+    /// backends move the data within their calls, so this includes the
+    /// transfers.
+    pub compute_secs: BTreeMap<&'static str, DeviceMetricsHistogram>,
+    /// Bytes sent to the devices, by unit.
+    pub bytes_to_device: BTreeMap<&'static str, u64>,
+    /// Bytes read back from the devices, by unit.
+    pub bytes_from_device: BTreeMap<&'static str, u64>,
+    /// Jobs run on the CPU instead of a device, by unit.
+    pub fallbacks: BTreeMap<&'static str, u64>,
+    /// Jobs streamed through a device because their data exceeds its memory,
+    /// by unit.
+    pub streamed: BTreeMap<&'static str, u64>,
+    /// Errors, by `DeviceManagerError::kind`.
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+impl DeviceMetrics {
+    ///
+    pub fn record_job(&mut self, device_unit_type: DeviceUnitType, device_id: usize) {
+        *self
+            .jobs
+            .entry((device_unit_type.name(), device_id))
+            .or_insert(0) += 1;
+    }
+
+    ///
+    pub fn record_queue_wait(&mut self, device_unit_type: DeviceUnitType, value: Duration) {
+        self.queue_wait_secs
+            .entry(device_unit_type.name())
+            .or_default()
+            .observe(value);
+    }
+
+    ///
+    pub fn record_job_wait(&mut self, device_unit_type: DeviceUnitType, value: Duration) {
+        self.job_wait_secs
+            .entry(device_unit_type.name())
+            .or_default()
+            .observe(value);
+    }
+
+    ///
+    pub fn record_compute(&mut self, device_unit_type: DeviceUnitType, value: Duration) {
+        self.compute_secs
+            .entry(device_unit_type.name())
+            .or_default()
+            .observe(value);
+    }
+
+    ///
+    pub fn record_bytes(
+        &mut self,
+        device_unit_type: DeviceUnitType,
+        to_device: usize,
+        from_device: usize,
+    ) {
+        *self
+            .bytes_to_device
+            .entry(device_unit_type.name())
+            .or_insert(0) += to_device as u64;
+        *self
+            .bytes_from_device
+            .entry(device_unit_type.name())
+            .or_insert(0) += from_device as u64;
+    }
+
+    ///
+    pub fn record_fallback(&mut self, device_unit_type: DeviceUnitType) {
+        *self.fallbacks.entry(device_unit_type.name()).or_insert(0) += 1;
+    }
+
+    ///
+    pub fn record_streamed(&mut self, device_unit_type: DeviceUnitType) {
+        *self.streamed.entry(device_unit_type.name()).or_insert(0) += 1;
+    }
+
+    ///
+    pub fn record_error(&mut self, error: &DeviceManagerError) {
+        *self.errors.entry(error.kind()).or_insert(0) += 1;
+    }
+
+    /// Add the metrics of another context, such as a clone that ran jobs of
+    /// its own.
+    pub fn merge(&mut self, other: &DeviceMetrics) {
+        merge_counters(&mut self.jobs, &other.jobs);
+        merge_histograms(&mut self.job_wait_secs, &other.job_wait_secs);
+        merge_histograms(&mut self.queue_wait_secs, &other.queue_wait_secs);
+        merge_histograms(&mut self.compute_secs, &other.compute_secs);
+        merge_counters(&mut self.bytes_to_device, &other.bytes_to_device);
+        merge_counters(&mut self.bytes_from_device, &other.bytes_from_device);
+        merge_counters(&mut self.fallbacks, &other.fallbacks);
+        merge_counters(&mut self.streamed, &other.streamed);
+        merge_counters(&mut self.errors, &other.errors);
+    }
+
+    /// Render in the Prometheus text exposition format.
+    pub fn to_prometheus_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# HELP halo2_device_jobs_total Jobs run on a device.").unwrap();
+        writeln!(out, "# TYPE halo2_device_jobs_total counter").unwrap();
+        for ((unit, device_id), value) in self.jobs.iter() {
+            writeln!(
+                out,
+                "halo2_device_jobs_total{{unit=\"{}\",device=\"{}\"}} {}",
+                unit, device_id, value
+            )
+            .unwrap();
+        }
+
+        for (name, help, histograms) in [
+            (
+                "halo2_device_job_wait_seconds",
+                "Time waiting in the job queue and for the device manager.",
+                &self.job_wait_secs,
+            ),
+            (
+                "halo2_device_queue_wait_seconds",
+                "Time waiting for an available device.",
+                &self.queue_wait_secs,
+            ),
+            (
+                "halo2_device_compute_seconds",
+                "Time computing on a device, including the transfers.",
+                &self.compute_secs,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            for (unit, histogram) in histograms.iter() {
+                let mut cumulative = 0;
+                for (bound, count) in histogram.bounds.iter().zip(histogram.counts.iter()) {
+                    cumulative += count;
+                    writeln!(
+                        out,
+                        "{}_bucket{{unit=\"{}\",le=\"{}\"}} {}",
+                        name, unit, bound, cumulative
+                    )
+                    .unwrap();
+                }
+                writeln!(
+                    out,
+                    "{}_bucket{{unit=\"{}\",le=\"+Inf\"}} {}",
+                    name, unit, histogram.count
+                )
+                .unwrap();
+                writeln!(out, "{}_sum{{unit=\"{}\"}} {}", name, unit, histogram.sum).unwrap();
+                writeln!(
+                    out,
+                    "{}_count{{unit=\"{}\"}} {}",
+                    name, unit, histogram.count
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(
+            out,
+            "# HELP halo2_device_bytes_total Bytes moved between host and device."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE halo2_device_bytes_total counter").unwrap();
+        for (direction, counters) in [
+            ("to_device", &self.bytes_to_device),
+            ("from_device", &self.bytes_from_device),
+        ] {
+            for (unit, value) in counters.iter() {
+                writeln!(
+                    out,
+                    "halo2_device_bytes_total{{unit=\"{}\",direction=\"{}\"}} {}",
+                    unit, direction, value
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(
+            out,
+            "# HELP halo2_device_fallbacks_total Jobs run on the CPU instead of a device."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE halo2_device_fallbacks_total counter").unwrap();
+        for (unit, value) in self.fallbacks.iter() {
+            writeln!(
+                out,
+                "halo2_device_fallbacks_total{{unit=\"{}\"}} {}",
+                unit, value
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP halo2_device_streamed_total Jobs streamed through a device larger than its memory."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE halo2_device_streamed_total counter").unwrap();
+        for (unit, value) in self.streamed.iter() {
+            writeln!(
+                out,
+                "halo2_device_streamed_total{{unit=\"{}\"}} {}",
+                unit, value
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP halo2_device_errors_total Device manager errors."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE halo2_device_errors_total counter").unwrap();
+        for (kind, value) in self.errors.iter() {
+            writeln!(
+                out,
+                "halo2_device_errors_total{{kind=\"{}\"}} {}",
+                kind, value
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+impl DeviceManagerContext {
+    /// A snapshot of the device job metrics.
+    pub fn metrics_snapshot(&self) -> DeviceMetrics {
+        self.metrics.clone()
+    }
+
+    /// The device job metrics in the Prometheus text exposition format.
+    pub fn metrics_prometheus_text(&self) -> String {
+        self.metrics.to_prometheus_text()
+    }
+
+    /// Clear the device job metrics.
+    pub fn reset_metrics(&mut self) {
+        self.metrics = DeviceMetrics::default();
+    }
+}
+
+/// The metrics of the global device manager in the Prometheus text exposition
+/// format, to be served from the prover's metrics endpoint.
+pub fn device_metrics_prometheus_text() -> String {
+    let binding = GLOBAL_DEVICE_MANAGER.lock().unwrap();
+    binding.get_handle().metrics_prometheus_text()
+}
+
+#[test]
+fn test_device_metrics_prometheus_text() {
+    let mut metrics = DeviceMetrics::default();
+    metrics.record_job(DeviceUnitType::DeviceUnitTypeMSM, 0);
+    metrics.record_job(DeviceUnitType::DeviceUnitTypeMSM, 0);
+    metrics.record_compute(DeviceUnitType::DeviceUnitTypeMSM, Duration::from_millis(3));
+    metrics.record_compute(DeviceUnitType::DeviceUnitTypeMSM, Duration::from_secs(10));
+    metrics.record_bytes(DeviceUnitType::DeviceUnitTypeNTT, 64, 64);
+    metrics.record_job_wait(DeviceUnitType::DeviceUnitTypeNTT, Duration::from_millis(2));
+    metrics.record_error(&DeviceManagerError::DeviceManagerErrorNoAvailableDevice);
+
+    let text = metrics.to_prometheus_text();
+    assert!(text.contains("halo2_device_jobs_total{unit=\"msm\",device=\"0\"} 2"));
+    assert!(text.contains("halo2_device_compute_seconds_bucket{unit=\"msm\",le=\"0.001\"} 0"));
+    assert!(text.contains("halo2_device_compute_seconds_bucket{unit=\"msm\",le=\"0.005\"} 1"));
+    assert!(text.contains("halo2_device_compute_seconds_bucket{unit=\"msm\",le=\"5\"} 1"));
+    assert!(text.contains("halo2_device_compute_seconds_bucket{unit=\"msm\",le=\"+Inf\"} 2"));
+    assert!(text.contains("halo2_device_bytes_total{unit=\"ntt\",direction=\"from_device\"} 64"));
+    assert!(text.contains("halo2_device_job_wait_seconds_count{unit=\"ntt\"} 1"));
+    assert!(text.contains("halo2_device_errors_total{kind=\"no_available_device\"} 1"));
+
+    let mut merged = metrics.clone();
+    merged.merge(&metrics);
+    let text = merged.to_prometheus_text();
+    assert!(text.contains("halo2_device_jobs_total{unit=\"msm\",device=\"0\"} 4"));
+    assert!(text.contains("halo2_device_compute_seconds_bucket{unit=\"msm\",le=\"+Inf\"} 4"));
+    assert!(text.contains("halo2_device_bytes_total{unit=\"ntt\",direction=\"from_device\"} 128"));
+}
@@ -0,0 +1,894 @@
+use super::*;
+
+use crate::arithmetic::{
+    best_fft_cpu, best_multiexp_cpu, coset_extend_cpu, extended_to_coeff_cpu, fixed_base_multiexp,
+    glv_lambda, pointwise_cpu, signed_digit_num, FixedBaseConfig, PointwiseOp, GLV_SCALAR_BYTES,
+};
+use group::Group;
+use halo2curves::bn256::{Fr, G1Affine, G1};
+use halo2curves::ff::Field;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Read the one scalar of the bytes, such as a coset generator.
+fn scalar_from_bytes(bytes: &[u8]) -> Result<Fr, DeviceManagerError> {
+    match values_from_bytes::<Fr>(bytes)?[..] {
+        [scalar] => Ok(scalar),
+        _ => Err(DeviceManagerError::DeviceManagerErrorExecute),
+    }
+}
+
+/// A fault injected by `MockDeviceBackend`.
+#[derive(Clone, Debug)]
+pub enum MockDeviceFault {
+    /// The call fails with the error.
+    Error(DeviceManagerError),
+    /// The call succeeds with a flipped byte in the result.
+    CorruptResult,
+    /// The MSM call succeeds with the generator added to its point, the other
+    /// calls are left alone.
+    OffsetResult,
+}
+
+/// A scripted fault, matched against the calls in order.
+#[derive(Clone, Debug)]
+pub struct MockDeviceFaultRule {
+    /// Only calls on this GPU, or on any GPU with `None`.
+    pub device_id: Option<usize>,
+    /// The call it applies to, counted from 1 over the matching calls, or
+    /// every matching call with `None`.
+    pub call: Option<usize>,
+    ///
+    pub fault: MockDeviceFault,
+}
+
+#[derive(Debug, Default)]
+struct MockDeviceState {
+    device_num: usize,
+    latency: Duration,
+    /// The memory of every device in bytes, `None` for unlimited.
+    memory: Option<usize>,
+    /// The window of the signed digits the devices take, `None` for raw
+    /// scalars.
+    signed_digit_window: Option<usize>,
+    /// The number of `msm_signed_digits` calls.
+    signed_digit_calls: usize,
+    /// Whether the devices take GLV halves.
+    glv: bool,
+    /// The number of `msm_glv` calls.
+    glv_calls: usize,
+    /// Whether the devices take small scalars as `u64`.
+    small: bool,
+    /// The number of `msm_small` calls.
+    small_calls: usize,
+    /// Whether the devices take the nonzero scalars with their indices.
+    sparse: bool,
+    /// The number of `msm_sparse` calls.
+    sparse_calls: usize,
+    /// Whether the devices run the fused coset transforms.
+    coset: bool,
+    /// The number of `coset_extend`/`extended_to_coeff` calls.
+    coset_calls: usize,
+    /// The number of `ntt_batch` calls.
+    ntt_batch_calls: usize,
+    /// The number of `write_bases` calls.
+    bases_writes: usize,
+    /// The number of `deinit` calls.
+    deinit_calls: usize,
+    /// Whether the devices keep buffers.
+    buffers: bool,
+    /// The buffers by GPU id and buffer id.
+    buffer_data: HashMap<(usize, usize), Vec<u8>>,
+    next_buffer_id: usize,
+    /// The number of `read_buffer` calls.
+    buffer_reads: usize,
+    /// Whether the devices run elementwise operations.
+    pointwise: bool,
+    /// The number of `pointwise`/`pointwise_buffer` calls.
+    pointwise_calls: usize,
+    /// The fixed-base tables by `DeviceHandle::handle_id` and bases index.
+    fixed_bases: HashMap<(usize, usize), (FixedBaseConfig, Vec<G1Affine>)>,
+    /// The number of MSMs run with a fixed-base table.
+    fixed_base_calls: usize,
+    rules: Vec<MockDeviceFaultRule>,
+    busy_device_ids: Vec<usize>,
+    /// The bases of each handle, indexed by `DeviceHandle::handle_id`.
+    handles: Vec<Vec<Vec<G1Affine>>>,
+    /// The device id of each `msm`/`ntt` call, in order.
+    calls: Vec<usize>,
+}
+
+/// A deterministic BN254 backend computing on the CPU, with scripted latency
+/// and faults, for testing the scheduling, fallback, retry and health logic
+/// of `DeviceManagerContext` without hardware.
+///
+/// The NTT uses the standard `2^log_n`-th root of unity of `Fr`, as halo2's
+/// evaluation domains do.
+#[derive(Debug, Default)]
+pub struct MockDeviceBackend {
+    state: Mutex<MockDeviceState>,
+}
+
+impl MockDeviceBackend {
+    /// Simulate `device_num` devices.
+    pub fn new(device_num: usize) -> Self {
+        Self {
+            state: Mutex::new(MockDeviceState {
+                device_num,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Sleep this long in every `msm`/`ntt` call.
+    pub fn with_latency(self, latency: Duration) -> Self {
+        self.state.lock().unwrap().latency = latency;
+        self
+    }
+
+    /// Give every device `memory` bytes. Uploads and calls with more data fail
+    /// with `DeviceManagerErrorOutOfMemory`, and `free_memory` reports it.
+    pub fn with_memory(self, memory: usize) -> Self {
+        self.state.lock().unwrap().memory = Some(memory);
+        self
+    }
+
+    /// Take the MSM scalars as signed digits of `window` bits.
+    pub fn with_signed_digits(self, window: usize) -> Self {
+        self.state.lock().unwrap().signed_digit_window = Some(window);
+        self
+    }
+
+    /// Take the MSM scalars decomposed by GLV.
+    pub fn with_glv(self) -> Self {
+        self.state.lock().unwrap().glv = true;
+        self
+    }
+
+    /// Take the MSM scalars below `2^64` as `u64`.
+    pub fn with_small_scalars(self) -> Self {
+        self.state.lock().unwrap().small = true;
+        self
+    }
+
+    /// Take the MSMs with few nonzero scalars as the nonzero ones and their
+    /// indices.
+    pub fn with_sparse_scalars(self) -> Self {
+        self.state.lock().unwrap().sparse = true;
+        self
+    }
+
+    /// Run `coset_extend` and `extended_to_coeff` in one call.
+    pub fn with_coset_extend(self) -> Self {
+        self.state.lock().unwrap().coset = true;
+        self
+    }
+
+    /// Keep buffers on the devices.
+    pub fn with_buffers(self) -> Self {
+        self.state.lock().unwrap().buffers = true;
+        self
+    }
+
+    /// Run elementwise operations.
+    pub fn with_pointwise(self) -> Self {
+        self.state.lock().unwrap().pointwise = true;
+        self
+    }
+
+    /// Add a scripted fault.
+    pub fn with_fault(self, rule: MockDeviceFaultRule) -> Self {
+        self.state.lock().unwrap().rules.push(rule);
+        self
+    }
+
+    /// Fail the `call`-th call on any device with the error.
+    pub fn fail_on_call(self, call: usize, error: DeviceManagerError) -> Self {
+        self.with_fault(MockDeviceFaultRule {
+            device_id: None,
+            call: Some(call),
+            fault: MockDeviceFault::Error(error),
+        })
+    }
+
+    /// Fail every call on the device with `DeviceManagerErrorExecute`.
+    pub fn fail_device(self, device_id: usize) -> Self {
+        self.with_fault(MockDeviceFaultRule {
+            device_id: Some(device_id),
+            call: None,
+            fault: MockDeviceFault::Error(DeviceManagerError::DeviceManagerErrorExecute),
+        })
+    }
+
+    /// Corrupt the result of every call on the device.
+    pub fn corrupt_device(self, device_id: usize) -> Self {
+        self.with_fault(MockDeviceFaultRule {
+            device_id: Some(device_id),
+            call: None,
+            fault: MockDeviceFault::CorruptResult,
+        })
+    }
+
+    /// Change the latency of the following calls.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Mark the device as stuck running work of its own.
+    pub fn set_busy(&self, device_id: usize, busy: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.busy_device_ids.retain(|id| *id != device_id);
+        if busy {
+            state.busy_device_ids.push(device_id);
+        }
+    }
+
+    /// Remove all scripted faults, as if the devices were repaired.
+    pub fn clear_faults(&self) {
+        self.state.lock().unwrap().rules.clear();
+    }
+
+    /// The device id of each `msm`/`ntt` call so far, in order.
+    pub fn calls(&self) -> Vec<usize> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// The number of MSMs that got signed digits so far.
+    pub fn signed_digit_calls(&self) -> usize {
+        self.state.lock().unwrap().signed_digit_calls
+    }
+
+    /// The number of MSMs that got GLV halves so far.
+    pub fn glv_calls(&self) -> usize {
+        self.state.lock().unwrap().glv_calls
+    }
+
+    /// The number of MSMs that got `u64` scalars so far.
+    pub fn small_calls(&self) -> usize {
+        self.state.lock().unwrap().small_calls
+    }
+
+    /// The number of MSMs that got only the nonzero scalars so far.
+    pub fn sparse_calls(&self) -> usize {
+        self.state.lock().unwrap().sparse_calls
+    }
+
+    /// The number of fused coset transforms so far.
+    pub fn coset_calls(&self) -> usize {
+        self.state.lock().unwrap().coset_calls
+    }
+
+    /// The number of batched NTT launches so far.
+    pub fn ntt_batch_calls(&self) -> usize {
+        self.state.lock().unwrap().ntt_batch_calls
+    }
+
+    /// The number of bases written to existing handles so far.
+    pub fn bases_writes(&self) -> usize {
+        self.state.lock().unwrap().bases_writes
+    }
+
+    /// The number of handles released so far.
+    pub fn deinit_calls(&self) -> usize {
+        self.state.lock().unwrap().deinit_calls
+    }
+
+    /// The number of buffers copied back so far.
+    pub fn buffer_reads(&self) -> usize {
+        self.state.lock().unwrap().buffer_reads
+    }
+
+    /// The number of elementwise operations so far.
+    pub fn pointwise_calls(&self) -> usize {
+        self.state.lock().unwrap().pointwise_calls
+    }
+
+    /// The number of buffers on all devices.
+    pub fn buffer_count(&self) -> usize {
+        self.state.lock().unwrap().buffer_data.len()
+    }
+
+    /// The values of the buffer, as the backend holds them.
+    fn buffer_values(
+        &self,
+        device_id: usize,
+        buffer_id: usize,
+    ) -> Result<Vec<Fr>, DeviceManagerError> {
+        self.state
+            .lock()
+            .unwrap()
+            .buffer_data
+            .get(&(device_id, buffer_id))
+            .map(|bytes| values_from_bytes::<Fr>(bytes))
+            .ok_or(DeviceManagerError::DeviceManagerErrorExecute)?
+    }
+
+    /// The number of MSMs that used a fixed-base table so far.
+    pub fn fixed_base_calls(&self) -> usize {
+        self.state.lock().unwrap().fixed_base_calls
+    }
+
+    /// The MSM of the scalars with the bases of the handle, or with their
+    /// fixed-base table.
+    fn run_msm(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[Fr],
+        bases_index: usize,
+        bases_offset: usize,
+        fault: Option<MockDeviceFault>,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let corrupt = |mut result_bytes: Vec<u8>| {
+            match fault {
+                Some(MockDeviceFault::CorruptResult) => result_bytes[0] ^= 1,
+                Some(MockDeviceFault::OffsetResult) => {
+                    let result = curve_from_bytes::<G1Affine>(&result_bytes).unwrap();
+                    result_bytes = transmute_values(&[result + G1::generator()]).to_vec();
+                }
+                _ => {}
+            }
+            result_bytes
+        };
+
+        let bases = {
+            let mut state = self.state.lock().unwrap();
+            let fixed_base = state
+                .fixed_bases
+                .get(&(handle.handle_id, bases_index))
+                .filter(|_| bases_offset == 0)
+                .cloned();
+            if let Some((config, table)) = fixed_base {
+                state.fixed_base_calls += 1;
+                drop(state);
+                let result: G1 = fixed_base_multiexp(scalars, &table, &config);
+                return Ok(corrupt(transmute_values(&[result]).to_vec()));
+            }
+            state
+                .handles
+                .get(handle.handle_id)
+                .and_then(|bases| bases.get(bases_index))
+                .cloned()
+                .ok_or(DeviceManagerError::DeviceManagerErrorBasesIndex)?
+        };
+        if bases_offset + scalars.len() > bases.len() {
+            return Err(DeviceManagerError::DeviceManagerErrorBasesIndex);
+        }
+
+        let result: G1 =
+            best_multiexp_cpu(scalars, &bases[bases_offset..bases_offset + scalars.len()]);
+        Ok(corrupt(transmute_values(&[result]).to_vec()))
+    }
+
+    /// Count the call and return the fault to inject, if any.
+    fn begin_call(&self, device_id: usize, bytes: usize) -> Option<MockDeviceFault> {
+        let (latency, fault) = {
+            let mut state = self.state.lock().unwrap();
+            state.calls.push(device_id);
+            if state.memory.map_or(false, |memory| bytes > memory) {
+                return Some(MockDeviceFault::Error(
+                    DeviceManagerError::DeviceManagerErrorOutOfMemory,
+                ));
+            }
+
+            let fault = state.rules.iter().find_map(|rule| {
+                if rule.device_id.map_or(false, |id| id != device_id) {
+                    return None;
+                }
+                let matching_calls = state
+                    .calls
+                    .iter()
+                    .filter(|id| rule.device_id.map_or(true, |rule_id| rule_id == **id))
+                    .count();
+                match rule.call {
+                    Some(call) if call != matching_calls => None,
+                    _ => Some(rule.fault.clone()),
+                }
+            });
+            (state.latency, fault)
+        };
+
+        if !latency.is_zero() {
+            std::thread::sleep(latency);
+        }
+        fault
+    }
+}
+
+impl DeviceBackend for MockDeviceBackend {
+    fn device_number(&self) -> Result<usize, DeviceManagerError> {
+        Ok(self.state.lock().unwrap().device_num)
+    }
+
+    fn init(
+        &self,
+        device_id: usize,
+        _init_device_unit_type: DeviceInitUnitType,
+        bases: Option<&[&[u8]]>,
+        _omega: Option<&[u8]>,
+    ) -> Result<DeviceHandle, DeviceManagerError> {
+        let mut state = self.state.lock().unwrap();
+        if device_id >= state.device_num {
+            return Err(DeviceManagerError::DeviceManagerSetDeviceError);
+        }
+        let bytes: usize = bases.map_or(0, |bases| bases.iter().map(|b| b.len()).sum());
+        if state.memory.map_or(false, |memory| bytes > memory) {
+            return Err(DeviceManagerError::DeviceManagerErrorOutOfMemory);
+        }
+
+        let bases = bases
+            .unwrap_or(&[])
+            .iter()
+            .map(|bytes| values_from_bytes::<G1Affine>(bytes))
+            .collect::<Result<_, _>>()?;
+        state.handles.push(bases);
+
+        Ok(DeviceHandle {
+            device_id,
+            handle_id: state.handles.len() - 1,
+        })
+    }
+
+    fn deinit(&self, _handle: &DeviceHandle) {
+        self.state.lock().unwrap().deinit_calls += 1;
+    }
+
+    fn supports_write_bases(&self) -> bool {
+        true
+    }
+
+    fn write_bases(
+        &self,
+        handle: &DeviceHandle,
+        bases_index: usize,
+        bases: &[u8],
+    ) -> Result<(), DeviceManagerError> {
+        let bases = values_from_bytes::<G1Affine>(bases)?;
+        let mut state = self.state.lock().unwrap();
+        if state
+            .memory
+            .map_or(false, |memory| std::mem::size_of_val(&bases[..]) > memory)
+        {
+            return Err(DeviceManagerError::DeviceManagerErrorOutOfMemory);
+        }
+        let slots = state
+            .handles
+            .get_mut(handle.handle_id)
+            .ok_or(DeviceManagerError::DeviceManagerErrorExecute)?;
+        if slots.len() <= bases_index {
+            slots.resize(bases_index + 1, vec![]);
+        }
+        slots[bases_index] = bases;
+        state.bases_writes += 1;
+        Ok(())
+    }
+
+    fn msm(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        self.msm_offset(handle, scalars, bases_index, 0)
+    }
+
+    fn supports_msm_offset(&self) -> bool {
+        true
+    }
+
+    fn msm_offset(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+        bases_offset: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, scalars.len());
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let scalars = values_from_bytes::<Fr>(scalars)?;
+        self.run_msm(handle, &scalars, bases_index, bases_offset, fault)
+    }
+
+    fn msm_signed_digit_window(&self, _n: usize) -> Option<usize> {
+        self.state.lock().unwrap().signed_digit_window
+    }
+
+    fn msm_signed_digits(
+        &self,
+        handle: &DeviceHandle,
+        digits: &[u8],
+        window: usize,
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, digits.len());
+        self.state.lock().unwrap().signed_digit_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        // Horner's rule over the digits of each scalar, from the top.
+        let radix = Fr::from(1 << window);
+        let scalars: Vec<Fr> = values_from_bytes::<i32>(digits)?
+            .chunks(signed_digit_num(256, window))
+            .map(|digits| {
+                digits.iter().rev().fold(Fr::ZERO, |scalar, digit| {
+                    let magnitude = Fr::from(digit.unsigned_abs() as u64);
+                    scalar * radix + if *digit < 0 { -magnitude } else { magnitude }
+                })
+            })
+            .collect();
+        self.run_msm(handle, &scalars, bases_index, 0, fault)
+    }
+
+    fn supports_msm_sparse(&self) -> bool {
+        self.state.lock().unwrap().sparse
+    }
+
+    fn msm_sparse(
+        &self,
+        handle: &DeviceHandle,
+        indices: &[u8],
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, indices.len() + scalars.len());
+        self.state.lock().unwrap().sparse_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let indices: Vec<usize> = indices
+            .chunks(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .collect();
+        let nonzero = values_from_bytes::<Fr>(scalars)?;
+        if nonzero.len() != indices.len() {
+            return Err(DeviceManagerError::DeviceManagerErrorExecute);
+        }
+        let n = indices.last().map_or(0, |last| last + 1);
+        let mut scalars = vec![Fr::ZERO; n];
+        for (index, scalar) in indices.into_iter().zip(nonzero) {
+            scalars[index] = scalar;
+        }
+        self.run_msm(handle, &scalars, bases_index, 0, fault)
+    }
+
+    fn supports_msm_small(&self) -> bool {
+        self.state.lock().unwrap().small
+    }
+
+    fn msm_small(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, scalars.len());
+        self.state.lock().unwrap().small_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let scalars: Vec<Fr> = scalars
+            .chunks(8)
+            .map(|bytes| Fr::from(u64::from_le_bytes(bytes.try_into().unwrap())))
+            .collect();
+        self.run_msm(handle, &scalars, bases_index, 0, fault)
+    }
+
+    fn supports_msm_glv(&self) -> bool {
+        self.state.lock().unwrap().glv
+    }
+
+    fn msm_glv(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, scalars.len());
+        self.state.lock().unwrap().glv_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let lambda = glv_lambda::<G1Affine>().unwrap();
+        let half = |bytes: &[u8], negative: u8| {
+            let value = Fr::from_u128(u128::from_le_bytes(bytes.try_into().unwrap()));
+            if negative == 1 {
+                -value
+            } else {
+                value
+            }
+        };
+        let scalars: Vec<Fr> = scalars
+            .chunks(GLV_SCALAR_BYTES)
+            .map(|bytes| half(&bytes[..16], bytes[32]) + half(&bytes[16..32], bytes[33]) * lambda)
+            .collect();
+        self.run_msm(handle, &scalars, bases_index, 0, fault)
+    }
+
+    fn init_fixed_base(
+        &self,
+        handle: &DeviceHandle,
+        bases_index: usize,
+        table: &[u8],
+        config: &FixedBaseConfig,
+    ) -> Result<bool, DeviceManagerError> {
+        let mut state = self.state.lock().unwrap();
+        if state.memory.map_or(false, |memory| table.len() > memory) {
+            return Ok(false);
+        }
+        let table = values_from_bytes::<G1Affine>(table)?;
+        state
+            .fixed_bases
+            .insert((handle.handle_id, bases_index), (*config, table));
+        Ok(true)
+    }
+
+    fn ntt(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, scalars.len());
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let mut values = values_from_bytes::<Fr>(scalars)?;
+        best_fft_cpu(&mut values, device_ntt_root(log_n), log_n);
+        scalars.copy_from_slice(transmute_values(&values));
+        if let Some(MockDeviceFault::CorruptResult) = fault {
+            scalars[0] ^= 1;
+        }
+
+        Ok(())
+    }
+
+    fn ntt_batch(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+        batch: usize,
+    ) -> Result<(), DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, scalars.len());
+        self.state.lock().unwrap().ntt_batch_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let mut values = values_from_bytes::<Fr>(scalars)?;
+        if values.len() != batch << log_n {
+            return Err(DeviceManagerError::DeviceManagerErrorExecute);
+        }
+        for part in values.chunks_mut(1 << log_n) {
+            best_fft_cpu(part, device_ntt_root(log_n), log_n);
+        }
+        scalars.copy_from_slice(transmute_values(&values));
+        if let Some(MockDeviceFault::CorruptResult) = fault {
+            scalars[0] ^= 1;
+        }
+
+        Ok(())
+    }
+
+    fn supports_coset_extend(&self) -> bool {
+        self.state.lock().unwrap().coset
+    }
+
+    fn coset_extend(
+        &self,
+        handle: &DeviceHandle,
+        coeffs: &[u8],
+        log_n: u32,
+        extended_log_n: u32,
+        zeta: &[u8],
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, coeffs.len() << (extended_log_n - log_n));
+        self.state.lock().unwrap().coset_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let coeffs = values_from_bytes::<Fr>(coeffs)?;
+        let zeta = scalar_from_bytes(zeta)?;
+        let values = coset_extend_cpu(&coeffs, log_n, extended_log_n, zeta);
+        let mut result = transmute_values(&values).to_vec();
+        if let Some(MockDeviceFault::CorruptResult) = fault {
+            result[0] ^= 1;
+        }
+        Ok(result)
+    }
+
+    fn extended_to_coeff(
+        &self,
+        handle: &DeviceHandle,
+        values: &[u8],
+        log_n: u32,
+        extended_log_n: u32,
+        zeta: &[u8],
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, values.len());
+        self.state.lock().unwrap().coset_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let values = values_from_bytes::<Fr>(values)?;
+        let zeta = scalar_from_bytes(zeta)?;
+        let coeffs = extended_to_coeff_cpu(&values, log_n, extended_log_n, zeta);
+        let mut result = transmute_values(&coeffs).to_vec();
+        if let Some(MockDeviceFault::CorruptResult) = fault {
+            result[0] ^= 1;
+        }
+        Ok(result)
+    }
+
+    fn supports_buffers(&self) -> bool {
+        self.state.lock().unwrap().buffers
+    }
+
+    fn alloc_buffer(&self, device_id: usize, bytes: &[u8]) -> Result<usize, DeviceManagerError> {
+        if let Some(MockDeviceFault::Error(error)) = self.begin_call(device_id, bytes.len()) {
+            return Err(error);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let buffer_id = state.next_buffer_id;
+        state.next_buffer_id += 1;
+        state
+            .buffer_data
+            .insert((device_id, buffer_id), bytes.to_vec());
+        Ok(buffer_id)
+    }
+
+    fn read_buffer(
+        &self,
+        device_id: usize,
+        buffer_id: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let mut state = self.state.lock().unwrap();
+        state.buffer_reads += 1;
+        state
+            .buffer_data
+            .get(&(device_id, buffer_id))
+            .cloned()
+            .ok_or(DeviceManagerError::DeviceManagerErrorExecute)
+    }
+
+    fn write_buffer(
+        &self,
+        device_id: usize,
+        buffer_id: usize,
+        bytes: &[u8],
+    ) -> Result<(), DeviceManagerError> {
+        match self
+            .state
+            .lock()
+            .unwrap()
+            .buffer_data
+            .get_mut(&(device_id, buffer_id))
+        {
+            Some(data) if data.len() == bytes.len() => {
+                data.copy_from_slice(bytes);
+                Ok(())
+            }
+            _ => Err(DeviceManagerError::DeviceManagerErrorExecute),
+        }
+    }
+
+    fn free_buffer(&self, device_id: usize, buffer_id: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .buffer_data
+            .remove(&(device_id, buffer_id));
+    }
+
+    fn ntt_buffer(
+        &self,
+        handle: &DeviceHandle,
+        buffer_id: usize,
+        log_n: u32,
+        inverse: bool,
+    ) -> Result<(), DeviceManagerError> {
+        let mut values = self.buffer_values(handle.device_id, buffer_id)?;
+        let mut bytes = transmute_values(&values).to_vec();
+        self.ntt(handle, &mut bytes, log_n)?;
+        values = values_from_bytes::<Fr>(&bytes)?;
+        if inverse {
+            // The inverse NTT is the forward one with the outputs but the
+            // first reversed, divided by the size.
+            values[1..].reverse();
+            let n_inv = Fr::TWO_INV.pow_vartime([log_n as u64]);
+            for value in values.iter_mut() {
+                *value *= n_inv;
+            }
+        }
+
+        self.state.lock().unwrap().buffer_data.insert(
+            (handle.device_id, buffer_id),
+            transmute_values(&values).to_vec(),
+        );
+        Ok(())
+    }
+
+    fn msm_buffer(
+        &self,
+        handle: &DeviceHandle,
+        buffer_id: usize,
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let scalars = self.buffer_values(handle.device_id, buffer_id)?;
+        self.msm(handle, transmute_values(&scalars), bases_index)
+    }
+
+    fn supports_pointwise(&self) -> bool {
+        self.state.lock().unwrap().pointwise
+    }
+
+    fn pointwise(
+        &self,
+        handle: &DeviceHandle,
+        values: &mut [u8],
+        other: &[u8],
+        op: &PointwiseOp<Vec<u8>>,
+    ) -> Result<(), DeviceManagerError> {
+        let fault = self.begin_call(handle.device_id, values.len() + other.len());
+        self.state.lock().unwrap().pointwise_calls += 1;
+        if let Some(MockDeviceFault::Error(error)) = fault {
+            return Err(error);
+        }
+
+        let mut result = values_from_bytes::<Fr>(values)?;
+        let op = op.try_map(|bytes| scalar_from_bytes(bytes))?;
+        pointwise_cpu(&mut result, &values_from_bytes::<Fr>(other)?, &op);
+        values.copy_from_slice(transmute_values(&result));
+        if let Some(MockDeviceFault::CorruptResult) = fault {
+            values[0] ^= 1;
+        }
+        Ok(())
+    }
+
+    fn pointwise_buffer(
+        &self,
+        handle: &DeviceHandle,
+        buffer_id: usize,
+        other_buffer_id: Option<usize>,
+        op: &PointwiseOp<Vec<u8>>,
+    ) -> Result<(), DeviceManagerError> {
+        let mut values =
+            transmute_values(&self.buffer_values(handle.device_id, buffer_id)?).to_vec();
+        let other = match other_buffer_id {
+            Some(other_buffer_id) => {
+                transmute_values(&self.buffer_values(handle.device_id, other_buffer_id)?).to_vec()
+            }
+            None => vec![],
+        };
+        self.pointwise(handle, &mut values, &other, op)?;
+        self.state
+            .lock()
+            .unwrap()
+            .buffer_data
+            .insert((handle.device_id, buffer_id), values);
+        Ok(())
+    }
+
+    fn is_busy(&self, device_id: usize) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .busy_device_ids
+            .contains(&device_id)
+    }
+
+    fn free_memory(&self, _device_id: usize) -> Option<usize> {
+        self.state.lock().unwrap().memory
+    }
+}
@@ -4,18 +4,73 @@ pub use halo2curves::{CurveAffine, CurveExt};
 
 pub use utils::*;
 ///
+pub mod backend;
+///
+pub mod buffer;
+///
+pub mod cancel;
+///
 pub mod common;
 ///
+pub mod coset;
+///
+pub mod cost_model;
+///
+#[cfg(unix)]
+pub mod daemon;
+///
 pub mod device_manager;
 ///
 pub mod device_unit;
 ///
+pub mod fixed_base;
+///
+pub mod four_step;
+///
+pub mod health;
+///
+pub mod metrics;
+///
+pub mod mock;
+///
+pub mod out_of_core;
+///
+pub mod pointwise;
+///
+pub mod queue;
+///
+pub mod shard;
+///
+pub mod stream;
+///
 pub mod utils;
+///
+pub mod verify;
 
+pub use backend::*;
+pub use buffer::*;
+pub use cancel::*;
 pub use common::*;
+pub use coset::*;
+pub use cost_model::*;
+#[cfg(unix)]
+pub use daemon::*;
 pub use device_manager::*;
+pub use fixed_base::*;
+pub use four_step::*;
+pub use health::*;
+pub use metrics::*;
+pub use mock::*;
+pub use out_of_core::*;
+pub use pointwise::*;
+pub use queue::*;
+pub use shard::*;
+pub use stream::*;
 pub use utils::*;
+pub use verify::*;
 
 const NO_AVAILABE_DEVICE: usize = 0x1001;
 const MSM_EXECUTION_RESULT_NUM: usize = 254;
 const BN256_PROJECTIVE_BYTES: usize = 96;
+/// The default timeout of the execute calls.
+const DEVICE_JOB_DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
@@ -0,0 +1,70 @@
+use super::*;
+
+use halo2curves::ff::Field;
+use tracing::{debug, warn};
+
+/// An NTT may use this fraction of the free memory of a device, the rest is
+/// left for its twiddles and scratch space.
+const DEVICE_NTT_MEMORY_FRACTION: f64 = 0.5;
+
+impl DeviceManagerContext {
+    /// Set the memory of every device in bytes, used when the backend does
+    /// not report it. `None` treats such devices as unlimited.
+    pub fn set_device_memory(&mut self, device_memory: Option<usize>) {
+        self.device_memory = device_memory;
+    }
+
+    /// The free memory of the device in bytes as reported by the backend, else
+    /// the one set with `set_device_memory`.
+    pub fn device_free_memory(&self, device_id: usize) -> Option<usize> {
+        self.backend.free_memory(device_id).or(self.device_memory)
+    }
+
+    /// The largest `log_n` of an NTT of `G` that fits into the free memory of
+    /// every one of the devices, or `None` if their memory is unknown.
+    pub fn max_device_ntt_log_n<G>(&self, device_ids: &[usize]) -> Option<u32> {
+        let memory = device_ids
+            .iter()
+            .filter_map(|device_id| self.device_free_memory(*device_id))
+            .min()?;
+        let elements =
+            (memory as f64 * DEVICE_NTT_MEMORY_FRACTION) as usize / std::mem::size_of::<G>().max(1);
+        Some(match elements {
+            0 => 0,
+            elements => usize::BITS - 1 - elements.leading_zeros(),
+        })
+    }
+
+    /// Run an NTT larger than the memory of the devices.
+    ///
+    /// This is the four-step NTT with columns of `2^max_log_n` elements and
+    /// rows of the rest, so the host holds the domain and the devices stream
+    /// through it one column or row at a time. A domain larger than
+    /// `2^(2 * max_log_n)` does not fit the decomposition and returns
+    /// `DeviceManagerErrorOutOfMemory`.
+    pub fn execute_ntt_out_of_core<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        omega: Scalar,
+        log_n: u32,
+        max_log_n: u32,
+        device_ids: &[usize],
+        limits: &DeviceJobLimits,
+    ) -> Result<(), DeviceManagerError> {
+        if max_log_n == 0 || log_n > 2 * max_log_n {
+            warn!(log_n, max_log_n, "NTT does not fit into the device memory");
+            let error = DeviceManagerError::DeviceManagerErrorOutOfMemory;
+            self.metrics.record_error(&error);
+            return Err(error);
+        }
+
+        let log_n1 = max_log_n.min(log_n - 1);
+        debug!(
+            log_n,
+            log_n1,
+            log_n2 = log_n - log_n1,
+            "NTT larger than the device memory, running out of core"
+        );
+        self.run_ntt_four_step::<Scalar, G>(scalars, omega, log_n, log_n1, device_ids, limits)
+    }
+}
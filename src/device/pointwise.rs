@@ -0,0 +1,154 @@
+use super::*;
+
+use crate::arithmetic::{pointwise_cpu, PointwiseOp};
+use halo2curves::ff::Field;
+use std::time::Instant;
+use tracing::info_span;
+
+impl DeviceManagerContext {
+    /// Run the elementwise operation on `values` in place on a device with
+    /// an NTT unit, with `other` for the binary operations, see
+    /// `pointwise_cpu`.
+    ///
+    /// The values go to the device and back, which costs more than the
+    /// operation, so chains of operations are better run on `DeviceBuffer`s
+    /// with `execute_pointwise_buffer`. Without backend support it runs
+    /// `pointwise_cpu`. On error the values are untouched.
+    pub fn execute_pointwise<F: Field>(
+        &mut self,
+        values: &mut [F],
+        other: &[F],
+        op: &PointwiseOp<F>,
+    ) -> Result<(), DeviceManagerError> {
+        self.execute_pointwise_with_options(values, other, op, &DeviceJobOptions::default())
+    }
+
+    /// `execute_pointwise` with a timeout and cancellation token.
+    pub fn execute_pointwise_with_options<F: Field>(
+        &mut self,
+        values: &mut [F],
+        other: &[F],
+        op: &PointwiseOp<F>,
+        options: &DeviceJobOptions,
+    ) -> Result<(), DeviceManagerError> {
+        if op.is_binary() && values.len() != other.len() {
+            return Err(DeviceManagerError::DeviceManagerErrorExecute);
+        }
+        if !self.backend.supports_pointwise() {
+            pointwise_cpu(values, other, op);
+            return Ok(());
+        }
+
+        let limits = self.job_limits(options);
+        let span = info_span!(
+            "device_manager.execute_pointwise",
+            op = ?op.map(|_| ()),
+            n = values.len(),
+            device_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        self.probe_quarantined_devices();
+
+        let other = if op.is_binary() {
+            transmute_values(other).to_vec()
+        } else {
+            vec![]
+        };
+        let op = op.map(|c| transmute_values(&[*c]).to_vec());
+        let n = values.len();
+        let result = self.run_ntt_job(
+            DeviceUnitType::DeviceUnitTypePointwise,
+            n,
+            &span,
+            |context, handle| {
+                let mut bytes = transmute_values(values).to_vec();
+                let (other, op, handle) = (other.clone(), op.clone(), handle.clone());
+                let device_id = handle.device_id;
+                let start = Instant::now();
+                let bytes_len = bytes.len() + other.len();
+                let bytes = context.run_device_job(device_id, &limits, move |backend| {
+                    backend.pointwise(&handle, &mut bytes, &other, &op)?;
+                    Ok(bytes)
+                })?;
+                context
+                    .metrics
+                    .record_compute(DeviceUnitType::DeviceUnitTypePointwise, start.elapsed());
+                context.metrics.record_bytes(
+                    DeviceUnitType::DeviceUnitTypePointwise,
+                    bytes_len,
+                    bytes.len(),
+                );
+                Ok(bytes)
+            },
+        )?;
+        values.copy_from_slice(&values_from_bytes::<F>(&result)?);
+        Ok(())
+    }
+
+    /// Run the elementwise operation on the buffer in place on its device,
+    /// with the buffer `other` on the same device for the binary operations.
+    /// Without backend support the buffer is read back, and written again
+    /// after `pointwise_cpu`.
+    pub fn execute_pointwise_buffer<F: Field>(
+        &mut self,
+        buffer: &DeviceBuffer<F>,
+        other: Option<&DeviceBuffer<F>>,
+        op: &PointwiseOp<F>,
+    ) -> Result<(), DeviceManagerError> {
+        let _span = info_span!(
+            "device_manager.execute_pointwise_buffer",
+            op = ?op.map(|_| ()),
+            device_id = buffer.device_id,
+            n = buffer.len,
+        )
+        .entered();
+        let other = match (op.is_binary(), other) {
+            (true, Some(other)) => {
+                if other.len != buffer.len || other.device_id != buffer.device_id {
+                    return Err(DeviceManagerError::DeviceManagerErrorExecute);
+                }
+                Some(other)
+            }
+            (true, None) => return Err(DeviceManagerError::DeviceManagerErrorExecute),
+            (false, _) => None,
+        };
+        if buffer.is_host() {
+            let mut values = values_from_bytes::<F>(&self.host_buffer(buffer)?)?;
+            let other = match other {
+                Some(other) => values_from_bytes::<F>(&self.host_buffer(other)?)?,
+                None => vec![],
+            };
+            pointwise_cpu(&mut values, &other, op);
+            return self.set_host_buffer(buffer, &values);
+        }
+        if !self.backend.supports_pointwise() {
+            let mut values = self.download_buffer(buffer)?;
+            let other = match other {
+                Some(other) => self.download_buffer(other)?,
+                None => vec![],
+            };
+            pointwise_cpu(&mut values, &other, op);
+            let bytes = transmute_values(&values).to_vec();
+            let (device_id, buffer_id) = (buffer.device_id, buffer.buffer_id);
+            return self.run_buffer_job(
+                device_id,
+                DeviceUnitType::DeviceUnitTypePointwise,
+                move |backend| backend.write_buffer(device_id, buffer_id, &bytes),
+            );
+        }
+        let other_buffer_id = other.map(|other| other.buffer_id);
+        let handle = self
+            .ntt_param_uints
+            .iter()
+            .find(|unit| unit.device_id == buffer.device_id)
+            .map(|unit| unit.handle.clone())
+            .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)?;
+        let op = op.map(|c| transmute_values(&[*c]).to_vec());
+        let buffer_id = buffer.buffer_id;
+        self.run_buffer_job(
+            buffer.device_id,
+            DeviceUnitType::DeviceUnitTypePointwise,
+            move |backend| backend.pointwise_buffer(&handle, buffer_id, other_buffer_id, &op),
+        )
+    }
+}
@@ -0,0 +1,446 @@
+use super::*;
+
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span};
+
+/// The weight of the latest job in the per-element time estimate.
+const DEVICE_JOB_QUEUE_ESTIMATE_ALPHA: f64 = 0.2;
+
+lazy_static! {
+    /// The queue in front of `GLOBAL_DEVICE_MANAGER`.
+    pub static ref GLOBAL_DEVICE_JOB_QUEUE: DeviceJobQueue =
+        DeviceJobQueue::new(DeviceJobQueueConfig::default());
+}
+
+thread_local! {
+    static DEVICE_JOB_CLASS: Cell<(DeviceJobPriority, Option<u64>)> =
+        Cell::new((DeviceJobPriority::Normal, None));
+}
+
+/// Set the priority and tenant of the device jobs of this thread. Without a
+/// tenant, MSM jobs are accounted to their `param_id` and NTT jobs to 0.
+pub fn set_device_job_class(priority: DeviceJobPriority, tenant: Option<u64>) {
+    DEVICE_JOB_CLASS.with(|class| class.set((priority, tenant)));
+}
+
+/// The priority and tenant of the device jobs of this thread.
+pub fn device_job_class() -> (DeviceJobPriority, Option<u64>) {
+    DEVICE_JOB_CLASS.with(|class| class.get())
+}
+
+/// Jobs of a higher priority always run first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceJobPriority {
+    ///
+    Low,
+    ///
+    Normal,
+    ///
+    High,
+}
+
+impl DeviceJobPriority {
+    ///
+    pub fn to_u8(self) -> u8 {
+        match self {
+            DeviceJobPriority::Low => 0,
+            DeviceJobPriority::Normal => 1,
+            DeviceJobPriority::High => 2,
+        }
+    }
+
+    ///
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(DeviceJobPriority::Low),
+            1 => Some(DeviceJobPriority::Normal),
+            2 => Some(DeviceJobPriority::High),
+            _ => None,
+        }
+    }
+}
+
+/// A job waiting for or running on the devices.
+#[derive(Clone, Debug)]
+pub struct DeviceJob {
+    ///
+    pub priority: DeviceJobPriority,
+    /// The circuit or process the job is accounted to for fair sharing.
+    pub tenant: u64,
+    ///
+    pub device_unit_type: DeviceUnitType,
+    /// The number of elements.
+    pub n: usize,
+}
+
+///
+#[derive(Clone, Debug)]
+pub struct DeviceJobQueueConfig {
+    /// Jobs waiting beyond this are rejected with `DeviceManagerErrorQueueFull`.
+    pub max_depth: usize,
+    /// Jobs running at once.
+    pub slots: usize,
+}
+
+impl Default for DeviceJobQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            slots: 1,
+        }
+    }
+}
+
+/// A job in `DeviceJobQueueStatus`.
+#[derive(Clone, Debug)]
+pub struct DeviceJobQueueEntry {
+    ///
+    pub ticket: u64,
+    ///
+    pub job: DeviceJob,
+    /// The place in the run order, 0 for the next job, or for running jobs.
+    pub position: usize,
+    /// Time since the job was queued.
+    pub waited: Duration,
+    /// Estimated time until the job starts, or finishes for running jobs.
+    pub estimated_wait: Duration,
+}
+
+/// A snapshot of the queue.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceJobQueueStatus {
+    ///
+    pub max_depth: usize,
+    ///
+    pub running: Vec<DeviceJobQueueEntry>,
+    /// The waiting jobs in run order.
+    pub waiting: Vec<DeviceJobQueueEntry>,
+}
+
+#[derive(Debug)]
+struct DeviceJobQueueItem {
+    ticket: u64,
+    job: DeviceJob,
+    queued_at: Instant,
+    started_at: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct DeviceJobQueueState {
+    config: DeviceJobQueueConfig,
+    next_ticket: u64,
+    waiting: Vec<DeviceJobQueueItem>,
+    running: Vec<DeviceJobQueueItem>,
+    /// Device seconds used by each tenant.
+    service: HashMap<u64, f64>,
+    /// Moving average of the seconds per element, by unit.
+    secs_per_element: HashMap<&'static str, f64>,
+}
+
+impl DeviceJobQueueState {
+    fn estimate(&self, job: &DeviceJob) -> f64 {
+        self.secs_per_element
+            .get(job.device_unit_type.name())
+            .map_or(0f64, |spe| spe * job.n as f64)
+    }
+
+    fn is_active(&self, tenant: u64) -> bool {
+        self.waiting
+            .iter()
+            .chain(self.running.iter())
+            .any(|item| item.job.tenant == tenant)
+    }
+
+    /// The indices of the waiting jobs in run order.
+    ///
+    /// The highest priority goes first. Within a priority the tenant that has
+    /// used the least device time goes first, counting the estimates of its
+    /// jobs picked before, and each tenant's jobs run in arrival order.
+    fn schedule(&self) -> Vec<usize> {
+        let mut service = self.service.clone();
+        let mut remaining: Vec<usize> = (0..self.waiting.len()).collect();
+        let mut order = Vec::with_capacity(remaining.len());
+
+        while !remaining.is_empty() {
+            let (pos, &next) = remaining
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| {
+                    let (a, b) = (&self.waiting[**a], &self.waiting[**b]);
+                    let service_a = service.get(&a.job.tenant).copied().unwrap_or(0f64);
+                    let service_b = service.get(&b.job.tenant).copied().unwrap_or(0f64);
+                    b.job
+                        .priority
+                        .cmp(&a.job.priority)
+                        .then(service_a.total_cmp(&service_b))
+                        .then(a.ticket.cmp(&b.ticket))
+                })
+                .unwrap();
+            let item = &self.waiting[next];
+            *service.entry(item.job.tenant).or_insert(0f64) += self.estimate(&item.job);
+            order.push(next);
+            remaining.remove(pos);
+        }
+
+        order
+    }
+}
+
+/// A queue in front of the devices with priority classes and fair sharing
+/// of the device time between tenants.
+#[derive(Debug, Default)]
+pub struct DeviceJobQueue {
+    state: Mutex<DeviceJobQueueState>,
+    changed: Condvar,
+}
+
+/// Holds a slot of the queue until dropped.
+#[derive(Debug)]
+pub struct DeviceJobPermit<'a> {
+    queue: &'a DeviceJobQueue,
+    ticket: u64,
+}
+
+impl<'a> Drop for DeviceJobPermit<'a> {
+    fn drop(&mut self) {
+        self.queue.release(self.ticket);
+    }
+}
+
+impl DeviceJobQueue {
+    ///
+    pub fn new(config: DeviceJobQueueConfig) -> Self {
+        Self {
+            state: Mutex::new(DeviceJobQueueState {
+                config,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    ///
+    pub fn set_config(&self, config: DeviceJobQueueConfig) {
+        self.state.lock().unwrap().config = config;
+        self.changed.notify_all();
+    }
+
+    /// Wait until it is the job's turn and a slot is free.
+    pub fn acquire(&self, job: DeviceJob) -> Result<DeviceJobPermit<'_>, DeviceManagerError> {
+        self.acquire_with_options(job, &DeviceJobOptions::default())
+    }
+
+    /// Like `acquire`, but gives up the place in the queue once the timeout
+    /// of the options passes or their token is cancelled.
+    pub fn acquire_with_options(
+        &self,
+        job: DeviceJob,
+        options: &DeviceJobOptions,
+    ) -> Result<DeviceJobPermit<'_>, DeviceManagerError> {
+        let limits = DeviceJobLimits::new(options.timeout, options.cancel.clone());
+        let _span = info_span!(
+            "device_manager.queue",
+            unit = job.device_unit_type.name(),
+            priority = ?job.priority,
+            tenant = job.tenant,
+            n = job.n,
+        )
+        .entered();
+
+        let mut state = self.state.lock().unwrap();
+        if state.waiting.len() >= state.config.max_depth {
+            return Err(DeviceManagerError::DeviceManagerErrorQueueFull);
+        }
+
+        // A tenant coming back from idle starts level with the active ones
+        // instead of catching up on the time it did not use.
+        if !state.is_active(job.tenant) {
+            let floor = state
+                .waiting
+                .iter()
+                .chain(state.running.iter())
+                .filter_map(|item| state.service.get(&item.job.tenant))
+                .copied()
+                .reduce(f64::min);
+            if let Some(floor) = floor {
+                let service = state.service.entry(job.tenant).or_insert(0f64);
+                *service = service.max(floor);
+            }
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push(DeviceJobQueueItem {
+            ticket,
+            job,
+            queued_at: Instant::now(),
+            started_at: None,
+        });
+
+        loop {
+            if state.running.len() < state.config.slots {
+                if let Some(&next) = state.schedule().first() {
+                    if state.waiting[next].ticket == ticket {
+                        let mut item = state.waiting.remove(next);
+                        debug!(
+                            ticket,
+                            waited_secs = item.queued_at.elapsed().as_secs_f64(),
+                            "device job started"
+                        );
+                        item.started_at = Some(Instant::now());
+                        state.running.push(item);
+                        // The next job may fit into another free slot.
+                        self.changed.notify_all();
+                        return Ok(DeviceJobPermit {
+                            queue: self,
+                            ticket,
+                        });
+                    }
+                }
+            }
+
+            if let Err(error) = limits.check() {
+                state.waiting.retain(|item| item.ticket != ticket);
+                debug!(ticket, error = error.kind(), "device job left the queue");
+                // The job may have been next in line.
+                self.changed.notify_all();
+                return Err(error);
+            }
+            state = match limits.wait_interval() {
+                Some(interval) => self.changed.wait_timeout(state, interval).unwrap().0,
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn release(&self, ticket: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pos) = state.running.iter().position(|item| item.ticket == ticket) {
+            let item = state.running.remove(pos);
+            let secs = item
+                .started_at
+                .map_or(0f64, |at| at.elapsed().as_secs_f64());
+            *state.service.entry(item.job.tenant).or_insert(0f64) += secs;
+
+            if item.job.n > 0 {
+                let spe = secs / item.job.n as f64;
+                state
+                    .secs_per_element
+                    .entry(item.job.device_unit_type.name())
+                    .and_modify(|average| {
+                        *average += DEVICE_JOB_QUEUE_ESTIMATE_ALPHA * (spe - *average)
+                    })
+                    .or_insert(spe);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// The running and waiting jobs with their wait estimates.
+    ///
+    /// The estimates assume the jobs ahead take their moving average time per
+    /// element and that no job of a higher priority arrives.
+    pub fn status(&self) -> DeviceJobQueueStatus {
+        let state = self.state.lock().unwrap();
+        let slots = state.config.slots.max(1) as f64;
+
+        let mut busy_secs = 0f64;
+        let running = state
+            .running
+            .iter()
+            .map(|item| {
+                let elapsed = item
+                    .started_at
+                    .map_or(0f64, |at| at.elapsed().as_secs_f64());
+                let remaining = (state.estimate(&item.job) - elapsed).max(0f64);
+                busy_secs += remaining;
+                DeviceJobQueueEntry {
+                    ticket: item.ticket,
+                    job: item.job.clone(),
+                    position: 0,
+                    waited: item.queued_at.elapsed(),
+                    estimated_wait: Duration::from_secs_f64(remaining),
+                }
+            })
+            .collect();
+
+        let waiting = state
+            .schedule()
+            .into_iter()
+            .enumerate()
+            .map(|(position, index)| {
+                let item = &state.waiting[index];
+                let entry = DeviceJobQueueEntry {
+                    ticket: item.ticket,
+                    job: item.job.clone(),
+                    position,
+                    waited: item.queued_at.elapsed(),
+                    estimated_wait: Duration::from_secs_f64(busy_secs / slots),
+                };
+                busy_secs += state.estimate(&item.job);
+                entry
+            })
+            .collect();
+
+        DeviceJobQueueStatus {
+            max_depth: state.config.max_depth,
+            running,
+            waiting,
+        }
+    }
+}
+
+#[test]
+fn test_device_job_queue_schedule() {
+    let job = |priority, tenant| DeviceJob {
+        priority,
+        tenant,
+        device_unit_type: DeviceUnitType::DeviceUnitTypeMSM,
+        n: 1 << 10,
+    };
+
+    let queue = DeviceJobQueue::new(DeviceJobQueueConfig {
+        max_depth: 4,
+        slots: 1,
+    });
+    {
+        let mut state = queue.state.lock().unwrap();
+        state.secs_per_element.insert("msm", 1e-6);
+        // Tenant 1 already used a lot of device time.
+        state.service.insert(1, 10f64);
+        for (ticket, job) in [
+            job(DeviceJobPriority::Normal, 1),
+            job(DeviceJobPriority::Normal, 1),
+            job(DeviceJobPriority::Normal, 2),
+            job(DeviceJobPriority::High, 1),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            state.waiting.push(DeviceJobQueueItem {
+                ticket: ticket as u64,
+                job,
+                queued_at: Instant::now(),
+                started_at: None,
+            });
+        }
+    }
+
+    let status = queue.status();
+    let tickets: Vec<u64> = status.waiting.iter().map(|entry| entry.ticket).collect();
+    assert_eq!(tickets, vec![3, 2, 0, 1]);
+    assert_eq!(status.waiting[0].estimated_wait, Duration::ZERO);
+    assert!(status.waiting[3].estimated_wait > status.waiting[1].estimated_wait);
+
+    assert_eq!(
+        queue
+            .acquire(job(DeviceJobPriority::Low, 3))
+            .unwrap_err()
+            .kind(),
+        "queue_full"
+    );
+}
@@ -0,0 +1,395 @@
+use super::*;
+
+use group::Group;
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, warn};
+
+/// The weight of the latest run in the moving average of the measured MSM
+/// throughput of a device.
+const DEVICE_THROUGHPUT_ALPHA: f64 = 0.2;
+
+/// When one MSM or NTT is split across the devices.
+#[derive(Clone, Debug)]
+pub struct DeviceShardConfig {
+    /// Smaller MSMs run on a single device.
+    pub min_n: usize,
+    /// The smallest part given to a device. Slower devices whose part would
+    /// be smaller are left out.
+    pub min_shard_n: usize,
+    /// Smaller NTTs run on a single device, larger ones with the four-step
+    /// decomposition, see `execute_ntt_four_step`.
+    pub min_ntt_log_n: u32,
+}
+
+impl Default for DeviceShardConfig {
+    fn default() -> Self {
+        Self {
+            min_n: 1 << 18,
+            min_shard_n: 1 << 14,
+            min_ntt_log_n: 20,
+        }
+    }
+}
+
+/// The range of the scalars and bases one device runs of a sharded MSM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceMsmShard {
+    /// The GPU id.
+    pub device_id: usize,
+    ///
+    pub offset: usize,
+    ///
+    pub len: usize,
+}
+
+/// Split `n` elements into consecutive ranges proportional to the weights of
+/// the devices, leaving out the devices whose range would be shorter than
+/// `min_shard_n`. The weights are `(device_id, throughput)` pairs.
+pub fn split_msm_shards(
+    n: usize,
+    weights: &[(usize, f64)],
+    min_shard_n: usize,
+) -> Vec<DeviceMsmShard> {
+    let mut weights: Vec<(usize, f64)> = weights
+        .iter()
+        .filter(|(_, weight)| weight.is_finite() && *weight > 0f64)
+        .copied()
+        .collect();
+    // The fastest devices first, so the slowest is dropped first.
+    weights.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    while weights.len() > 1 {
+        let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+        let slowest = weights[weights.len() - 1].1;
+        if (n as f64 * slowest / total) as usize >= min_shard_n {
+            break;
+        }
+        weights.pop();
+    }
+
+    let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+    let mut lens: Vec<usize> = weights
+        .iter()
+        .map(|(_, weight)| (n as f64 * weight / total) as usize)
+        .collect();
+    // The rounding remainder goes to the fastest device.
+    if let Some(len) = lens.first_mut() {
+        *len += n - lens.iter().sum::<usize>();
+    }
+
+    let mut offset = 0;
+    let mut shards = vec![];
+    for ((device_id, _), len) in weights.iter().zip(lens) {
+        if len == 0 {
+            continue;
+        }
+        shards.push(DeviceMsmShard {
+            device_id: *device_id,
+            offset,
+            len,
+        });
+        offset += len;
+    }
+    shards
+}
+
+impl DeviceManagerContext {
+    /// Enable splitting large MSMs across the devices, `None` to run every MSM
+    /// on a single device.
+    pub fn set_shard_config(&mut self, shard_config: Option<DeviceShardConfig>) {
+        self.shard_config = shard_config;
+    }
+
+    /// Update the moving average MSM throughput of the device with a finished
+    /// job of `n` elements.
+    pub fn record_msm_throughput(&mut self, device_id: usize, n: usize, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if n == 0 || secs <= 0f64 {
+            return;
+        }
+        let throughput = n as f64 / secs;
+        self.msm_throughput
+            .entry(device_id)
+            .and_modify(|average| *average += DEVICE_THROUGHPUT_ALPHA * (throughput - *average))
+            .or_insert(throughput);
+    }
+
+    /// The MSM throughput of the device in elements per second, measured by
+    /// the past jobs or else by `calibrate`.
+    pub fn msm_throughput(&self, device_id: usize) -> Option<f64> {
+        self.msm_throughput.get(&device_id).copied().or_else(|| {
+            let entry = self
+                .cost_model
+                .as_ref()?
+                .get(device_id, DeviceUnitType::DeviceUnitTypeMSM)?;
+            (entry.secs_per_element > 0f64).then(|| 1f64 / entry.secs_per_element)
+        })
+    }
+
+    /// The GPUs holding the param that are healthy and idle.
+    pub fn ready_msm_device_ids(&self, msm_param_id: usize) -> Vec<usize> {
+        // All units of a GPU must be ready, it may be running an NTT.
+        let mut device_ids: Vec<usize> = self
+            .msm_param_uints
+            .iter()
+            .filter(|unit| unit.param_id == msm_param_id)
+            .map(|unit| unit.device_id)
+            .filter(|device_id| {
+                !self.is_device_busy(*device_id)
+                    && self
+                        .devices
+                        .iter()
+                        .filter(|device| device.device_id == *device_id)
+                        .all(|device| device.device_status == DeviceStatusType::DeviceStatusReady)
+            })
+            .collect();
+        device_ids.sort();
+        device_ids.dedup();
+        device_ids
+    }
+
+    /// The parts of an MSM of `n` elements over the ready, idle devices that
+    /// hold the bases of the param, or nothing if it runs on one device.
+    ///
+    /// Devices that were never measured count as fast as the average of the
+    /// measured ones. Backends without `msm_offset` run every MSM on one
+    /// device.
+    pub fn plan_msm_shards(&self, msm_param_id: usize, n: usize) -> Vec<DeviceMsmShard> {
+        let config = match &self.shard_config {
+            Some(config) if n >= config.min_n => config,
+            _ => return vec![],
+        };
+        if !self.backend.supports_msm_offset() {
+            return vec![];
+        }
+
+        let device_ids = self.ready_msm_device_ids(msm_param_id);
+        if device_ids.len() < 2 {
+            return vec![];
+        }
+
+        let measured: Vec<f64> = device_ids
+            .iter()
+            .filter_map(|device_id| self.msm_throughput(*device_id))
+            .collect();
+        let default_weight = if measured.is_empty() {
+            1f64
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+        let weights: Vec<(usize, f64)> = device_ids
+            .iter()
+            .map(|device_id| {
+                let weight = self.msm_throughput(*device_id).unwrap_or(default_weight);
+                (*device_id, weight)
+            })
+            .collect();
+
+        let shards = split_msm_shards(n, &weights, config.min_shard_n);
+        if shards.len() < 2 {
+            return vec![];
+        }
+        shards
+    }
+
+    /// Start one part of a sharded MSM on its device.
+    fn spawn_msm_shard<C: CurveAffine>(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[C::Scalar],
+        bases_index: usize,
+        shard: &DeviceMsmShard,
+    ) -> DeviceJobPending<(C::Curve, Duration)> {
+        let range = &scalars[shard.offset..shard.offset + shard.len];
+        let offset = shard.offset;
+        let handle = DeviceHandle {
+            device_id: shard.device_id,
+            handle_id: handle.handle_id,
+        };
+
+        let bytes = transmute_values(range).to_vec();
+        self.spawn_device_job(shard.device_id, move |backend| {
+            let start = Instant::now();
+            let result = backend.msm_offset(&handle, &bytes, bases_index, offset)?;
+            Ok((curve_from_bytes::<C>(&result)?, start.elapsed()))
+        })
+    }
+
+    /// Run the parts of the MSM concurrently and sum their points on the host.
+    ///
+    /// A failed part is retried on one of the devices that finished theirs.
+    /// The result cannot be attributed to one device, so `last_device_id` is
+    /// cleared.
+    pub fn execute_msm_sharded<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases_index: usize,
+        scalars: &[C::Scalar],
+        shards: &[DeviceMsmShard],
+        limits: &DeviceJobLimits,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let _span = info_span!(
+            "device_manager.execute_msm_sharded",
+            param_id = msm_param_id,
+            bases_index,
+            scalars_num = scalars.len(),
+            shard_num = shards.len(),
+        )
+        .entered();
+        limits.check()?;
+
+        let handle_of = |context: &Self, device_id: usize| {
+            context
+                .msm_param_uints
+                .iter()
+                .find(|unit| unit.param_id == msm_param_id && unit.device_id == device_id)
+                .map(|unit| unit.handle.clone())
+        };
+
+        let start = Instant::now();
+        let mut pending = vec![];
+        for shard in shards.iter() {
+            let handle = handle_of(self, shard.device_id)
+                .ok_or(DeviceManagerError::DeviceManagerErrorParamIdNone)?;
+            self.set_device_status(shard.device_id, DeviceStatusType::DeviceStatusRunning);
+            self.metrics
+                .record_job(DeviceUnitType::DeviceUnitTypeMSM, shard.device_id);
+            debug!(
+                device_id = shard.device_id,
+                offset = shard.offset,
+                len = shard.len,
+                "MSM shard started"
+            );
+            pending.push(self.spawn_msm_shard::<C>(&handle, scalars, bases_index, shard));
+        }
+
+        let mut sum = C::Curve::identity();
+        let mut failed = vec![];
+        let mut finished_device_ids = vec![];
+        let mut limit_error = None;
+        for (shard, pending) in shards.iter().zip(pending) {
+            let result = match limit_error {
+                // Abandon the remaining parts.
+                Some(_) => Err(DeviceManagerError::DeviceManagerErrorCancelled),
+                None => pending.wait(limits),
+            };
+            self.set_device_status(shard.device_id, DeviceStatusType::DeviceStatusReady);
+            match result {
+                Ok((point, elapsed)) => {
+                    sum += point;
+                    self.record_device_success(shard.device_id);
+                    self.record_msm_throughput(shard.device_id, shard.len, elapsed);
+                    finished_device_ids.push(shard.device_id);
+                }
+                Err(error) if limit_error.is_some() => {
+                    debug!(
+                        device_id = shard.device_id,
+                        error = error.kind(),
+                        "MSM shard abandoned"
+                    );
+                }
+                Err(error) => {
+                    if let Some(error) = self.job_limit_error(error.clone(), shard.device_id) {
+                        limit_error = Some(error);
+                        continue;
+                    }
+                    warn!(
+                        device_id = shard.device_id,
+                        error = error.kind(),
+                        "MSM shard failed, retrying on another device"
+                    );
+                    self.record_job_failure(shard.device_id, &error);
+                    failed.push(shard.clone());
+                }
+            }
+        }
+        if let Some(error) = limit_error {
+            return Err(error);
+        }
+
+        for shard in failed {
+            let mut result = Err(DeviceManagerError::DeviceManagerErrorNoAvailableDevice);
+            for device_id in finished_device_ids.clone() {
+                let handle = match handle_of(self, device_id) {
+                    Some(handle) => handle,
+                    None => continue,
+                };
+                let retry = DeviceMsmShard {
+                    device_id,
+                    ..shard.clone()
+                };
+                self.metrics
+                    .record_job(DeviceUnitType::DeviceUnitTypeMSM, device_id);
+                limits.check()?;
+                let pending = self.spawn_msm_shard::<C>(&handle, scalars, bases_index, &retry);
+                result = pending.wait(limits);
+                match &result {
+                    Ok(_) => break,
+                    Err(error) => {
+                        if let Some(error) = self.job_limit_error(error.clone(), device_id) {
+                            return Err(error);
+                        }
+                        self.record_job_failure(device_id, error);
+                        finished_device_ids.retain(|id| *id != device_id);
+                    }
+                }
+            }
+            match result {
+                Ok((point, _)) => sum += point,
+                Err(error) => {
+                    warn!("Execute sharded MSM No available device");
+                    self.metrics.record_error(&error);
+                    return Err(error);
+                }
+            }
+        }
+
+        self.metrics
+            .record_compute(DeviceUnitType::DeviceUnitTypeMSM, start.elapsed());
+        self.metrics.record_bytes(
+            DeviceUnitType::DeviceUnitTypeMSM,
+            std::mem::size_of_val(scalars),
+            std::mem::size_of::<C::Curve>() * shards.len(),
+        );
+        self.last_device_id = None;
+
+        Ok(transmute_values(&[sum]).to_vec())
+    }
+
+    /// Set the status of all units of the GPU.
+    pub(crate) fn set_device_status(&mut self, device_id: usize, device_status: DeviceStatusType) {
+        for device in self.devices.iter_mut() {
+            // Quarantined units stay out until their self-test.
+            if device.device_id == device_id
+                && device.device_status != DeviceStatusType::DeviceStatusError
+            {
+                device.device_status = device_status;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_split_msm_shards() {
+    // Device 1 is three times as fast as device 0, device 2 is far too slow.
+    let shards = split_msm_shards(1 << 16, &[(0, 1f64), (1, 3f64), (2, 0.01f64)], 1 << 10);
+    assert_eq!(
+        shards,
+        vec![
+            DeviceMsmShard {
+                device_id: 1,
+                offset: 0,
+                len: 3 << 14,
+            },
+            DeviceMsmShard {
+                device_id: 0,
+                offset: 3 << 14,
+                len: 1 << 14,
+            },
+        ]
+    );
+
+    let shards = split_msm_shards(1000, &[(0, 1f64), (1, 1f64), (2, 1f64)], 1);
+    assert_eq!(shards.iter().map(|shard| shard.len).sum::<usize>(), 1000);
+    assert_eq!(shards[2].offset + shards[2].len, 1000);
+}
@@ -0,0 +1,282 @@
+use super::*;
+
+use group::Group;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, info_span, warn};
+
+/// The bases of a param may use this fraction of the free memory of a device,
+/// the rest is left for the scalars and buckets of the MSM.
+const DEVICE_MSM_MEMORY_FRACTION: f64 = 0.5;
+
+/// The chunk size of a streamed MSM when the memory of the device is
+/// unknown.
+const DEVICE_MSM_STREAM_DEFAULT_CHUNK: usize = 1 << 20;
+
+/// How a streamed MSM went, see `execute_msm_streaming`.
+#[derive(Clone, Debug)]
+pub struct DeviceMsmStreamReport {
+    ///
+    pub param_id: usize,
+    /// The GPU id.
+    pub device_id: usize,
+    ///
+    pub n: usize,
+    /// The elements of a chunk, two of which are on the device at once.
+    pub chunk_len: usize,
+    ///
+    pub chunk_num: usize,
+    /// The time of all uploads of the bases.
+    pub upload: Duration,
+    /// The time of all MSMs of the chunks.
+    pub compute: Duration,
+    ///
+    pub total: Duration,
+}
+
+impl DeviceMsmStreamReport {
+    /// The fraction of the upload time hidden behind the computation, 1 when
+    /// the uploads overlapped completely.
+    pub fn overlap(&self) -> f64 {
+        let serial = (self.upload + self.compute).as_secs_f64();
+        let hidden = serial - self.total.as_secs_f64();
+        if self.upload.is_zero() {
+            return 1f64;
+        }
+        (hidden / self.upload.as_secs_f64()).clamp(0f64, 1f64)
+    }
+}
+
+/// The handle a streamed MSM writes its chunks of bases to, released when
+/// the MSM and every call using it are done, even abandoned ones.
+struct DeviceStreamHandle {
+    backend: Arc<dyn DeviceBackend>,
+    handle: DeviceHandle,
+}
+
+impl Drop for DeviceStreamHandle {
+    fn drop(&mut self) {
+        self.backend.deinit(&self.handle);
+    }
+}
+
+impl DeviceManagerContext {
+    /// Whether the bases fit into the memory of the device next to an MSM,
+    /// or its memory is unknown.
+    pub fn msm_bases_fit(&self, device_id: usize, bases: Option<&[&[u8]]>) -> bool {
+        let bytes: usize = bases.map_or(0, |bases| bases.iter().map(|b| b.len()).sum());
+        match self.device_free_memory(device_id) {
+            Some(memory) => bytes as f64 <= memory as f64 * DEVICE_MSM_MEMORY_FRACTION,
+            None => true,
+        }
+    }
+
+    /// Whether the bases of the param stay on the host and its MSMs go
+    /// through `execute_msm_streaming`.
+    pub fn is_msm_param_streamed(&self, msm_param_id: usize) -> bool {
+        self.streamed_msm_param_ids.contains(&msm_param_id)
+    }
+
+    /// The elements of a chunk of a streamed MSM, two of which must fit on the
+    /// device with their scalars.
+    fn msm_stream_chunk_len<C: CurveAffine>(&self, device_id: usize) -> usize {
+        let element_bytes = std::mem::size_of::<C>() + std::mem::size_of::<C::Scalar>();
+        match self.device_free_memory(device_id) {
+            Some(memory) => {
+                ((memory as f64 * DEVICE_MSM_MEMORY_FRACTION) as usize / 2 / element_bytes).max(1)
+            }
+            None => DEVICE_MSM_STREAM_DEFAULT_CHUNK,
+        }
+    }
+
+    /// Run an MSM of a param whose bases exceed the device memory.
+    ///
+    /// The bases are uploaded in chunks sized from the free memory of the
+    /// device, alternating between two bases slots of one handle. The next
+    /// chunk is uploaded while the device runs the MSM of the current one, and
+    /// the partial results are summed on the host. The run is logged and kept
+    /// in `last_msm_stream_report`. Backends without `write_bases` return
+    /// `DeviceManagerErrorExecute`.
+    pub fn execute_msm_streaming<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        scalars: &[C::Scalar],
+        bases: &[C],
+        options: &DeviceJobOptions,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        assert_eq!(scalars.len(), bases.len());
+        if !self.backend.supports_write_bases() {
+            let error = DeviceManagerError::DeviceManagerErrorExecute;
+            self.metrics.record_error(&error);
+            return Err(error);
+        }
+        let limits = self.job_limits(options);
+        self.probe_quarantined_devices();
+
+        let device_ids = self.ready_msm_device_ids(msm_param_id);
+        let device_id = match device_ids
+            .iter()
+            .max_by(|a, b| {
+                let a = self.msm_throughput(**a).unwrap_or(0f64);
+                let b = self.msm_throughput(**b).unwrap_or(0f64);
+                a.total_cmp(&b)
+            })
+            .copied()
+        {
+            Some(device_id) => device_id,
+            None => {
+                warn!("Execute streamed MSM No available device");
+                let error = DeviceManagerError::DeviceManagerErrorNoAvailableDevice;
+                self.metrics.record_error(&error);
+                return Err(error);
+            }
+        };
+
+        let chunk_len = self.msm_stream_chunk_len::<C>(device_id);
+        let chunk_num = (scalars.len() + chunk_len - 1) / chunk_len;
+        let _span = info_span!(
+            "device_manager.execute_msm_streaming",
+            param_id = msm_param_id,
+            device_id,
+            scalars_num = scalars.len(),
+            chunk_len,
+            chunk_num,
+        )
+        .entered();
+
+        self.set_device_status(device_id, DeviceStatusType::DeviceStatusRunning);
+        self.metrics
+            .record_job(DeviceUnitType::DeviceUnitTypeMSM, device_id);
+        self.metrics
+            .record_streamed(DeviceUnitType::DeviceUnitTypeMSM);
+        let start = Instant::now();
+        let result = self.run_msm_stream::<C>(device_id, scalars, bases, chunk_len, &limits);
+        self.set_device_status(device_id, DeviceStatusType::DeviceStatusReady);
+
+        let (sum, upload, compute) = match result {
+            Ok(result) => result,
+            Err(error) => {
+                if let Some(error) = self.job_limit_error(error.clone(), device_id) {
+                    return Err(error);
+                }
+                warn!(
+                    device_id,
+                    error = error.kind(),
+                    "Execute streamed MSM failed"
+                );
+                self.record_job_failure(device_id, &error);
+                return Err(error);
+            }
+        };
+
+        let report = DeviceMsmStreamReport {
+            param_id: msm_param_id,
+            device_id,
+            n: scalars.len(),
+            chunk_len,
+            chunk_num,
+            upload,
+            compute,
+            total: start.elapsed(),
+        };
+        info!(
+            param_id = report.param_id,
+            device_id,
+            n = report.n,
+            chunk_len,
+            chunk_num,
+            upload_secs = report.upload.as_secs_f64(),
+            compute_secs = report.compute.as_secs_f64(),
+            total_secs = report.total.as_secs_f64(),
+            overlap = report.overlap(),
+            "MSM bases exceed the device memory, streamed them in chunks"
+        );
+
+        self.record_device_success(device_id);
+        // Like the other calls, with the bases uploads, see `upload` of the
+        // report for them alone.
+        self.metrics
+            .record_compute(DeviceUnitType::DeviceUnitTypeMSM, report.total);
+        self.metrics.record_bytes(
+            DeviceUnitType::DeviceUnitTypeMSM,
+            std::mem::size_of_val(scalars) + std::mem::size_of_val(bases),
+            std::mem::size_of::<C::Curve>() * chunk_num,
+        );
+        self.last_device_id = Some(device_id);
+        self.last_msm_stream_report = Some(report);
+
+        Ok(transmute_values(&[sum]).to_vec())
+    }
+
+    /// The double-buffered pipeline of `execute_msm_streaming`, returning the
+    /// sum and the upload and compute times.
+    fn run_msm_stream<C: CurveAffine>(
+        &mut self,
+        device_id: usize,
+        scalars: &[C::Scalar],
+        bases: &[C],
+        chunk_len: usize,
+        limits: &DeviceJobLimits,
+    ) -> Result<(C::Curve, Duration, Duration), DeviceManagerError> {
+        let owner = self.backend.clone();
+        let handle = self.run_device_job(device_id, limits, move |backend| {
+            let handle = backend.init(
+                device_id,
+                DeviceInitUnitType::DeviceInitUnitTypeMSM,
+                None,
+                None,
+            )?;
+            Ok(Arc::new(DeviceStreamHandle {
+                backend: owner,
+                handle,
+            }))
+        })?;
+
+        // The chunk `i` goes to the bases slot `i % 2`.
+        let spawn_upload = |context: &Self, i: usize, chunk: &[C]| {
+            let handle = handle.clone();
+            let bytes = transmute_values(chunk).to_vec();
+            context.spawn_device_job_on(device_id, DeviceWorkerLane::Copy, move |backend| {
+                let start = Instant::now();
+                backend.write_bases(&handle.handle, i % 2, &bytes)?;
+                Ok(start.elapsed())
+            })
+        };
+
+        let n = scalars.len();
+        let ranges: Vec<(usize, usize)> = (0..n)
+            .step_by(chunk_len)
+            .map(|begin| (begin, (begin + chunk_len).min(n)))
+            .collect();
+
+        let mut sum = C::Curve::identity();
+        let (mut upload, mut compute) = (Duration::ZERO, Duration::ZERO);
+        let mut next_upload = ranges
+            .first()
+            .map(|&(begin, end)| spawn_upload(self, 0, &bases[begin..end]));
+        for (i, &(begin, end)) in ranges.iter().enumerate() {
+            upload += next_upload.take().unwrap().wait(limits)?;
+
+            // Upload the next chunk while the device runs this one. The slot
+            // it overwrites was used by the MSM of the previous chunk, which
+            // has returned.
+            next_upload = ranges
+                .get(i + 1)
+                .map(|&(begin, end)| spawn_upload(self, i + 1, &bases[begin..end]));
+
+            let handle = handle.clone();
+            let bytes = transmute_values(&scalars[begin..end]).to_vec();
+            let pending = self.spawn_device_job(device_id, move |backend| {
+                let start = Instant::now();
+                let result = backend.msm(&handle.handle, &bytes, i % 2)?;
+                Ok((curve_from_bytes::<C>(&result)?, start.elapsed()))
+            });
+            // Calls still running keep the handle until they return.
+            let (point, compute_time) = pending.wait(limits)?;
+            compute += compute_time;
+            sum += point;
+        }
+
+        Ok((sum, upload, compute))
+    }
+}
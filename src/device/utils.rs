@@ -3,7 +3,7 @@ use crate::poly::Polynomial;
 
 use super::*;
 ///
-pub fn transmute_values<'a, U: std::fmt::Debug>(values: &'a [U]) -> &'a [u8] {
+pub fn transmute_values<'a, U>(values: &'a [U]) -> &'a [u8] {
     let ptr = values.as_ptr();
     let len = values.len();
 
@@ -19,8 +19,8 @@ pub fn transmute_values<'a, U: std::fmt::Debug>(values: &'a [U]) -> &'a [u8] {
 }
 
 ///
-pub fn transmute_values_mut<'a, U>(values: &'a [U]) -> &'a mut [u8] {
-    let ptr = values.as_ptr();
+pub fn transmute_values_mut<'a, U>(values: &'a mut [U]) -> &'a mut [u8] {
+    let ptr = values.as_mut_ptr();
     let len = values.len();
 
     assert!(
@@ -32,4 +32,28 @@ pub fn transmute_values_mut<'a, U>(values: &'a [U]) -> &'a mut [u8] {
     let out: &'a mut [u8] = unsafe { std::slice::from_raw_parts_mut(ptr as *mut u8, size) };
 
     out
-}
\ No newline at end of file
+}
+
+/// Read a curve point from the bytes returned by an MSM execution, or an
+/// error if the bytes are not exactly one point.
+pub fn curve_from_bytes<C: CurveAffine>(bytes: &[u8]) -> Result<C::Curve, DeviceManagerError> {
+    if bytes.len() != std::mem::size_of::<C::Curve>() {
+        return Err(DeviceManagerError::DeviceManagerErrorExecute);
+    }
+
+    Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const C::Curve) })
+}
+
+/// Read the values from bytes produced by `transmute_values`, or an error if
+/// the bytes are not a whole number of values.
+pub fn values_from_bytes<U: Copy>(bytes: &[u8]) -> Result<Vec<U>, DeviceManagerError> {
+    let size = std::mem::size_of::<U>();
+    if size == 0 || bytes.len() % size != 0 {
+        return Err(DeviceManagerError::DeviceManagerErrorExecute);
+    }
+
+    Ok(bytes
+        .chunks_exact(size)
+        .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const U) })
+        .collect())
+}
@@ -0,0 +1,253 @@
+use super::*;
+
+use crate::arithmetic::best_multiexp_cpu;
+use group::ff::BatchInvert;
+use halo2curves::ff::Field;
+use rand_core::{OsRng, RngCore};
+use tracing::{info_span, warn};
+
+/// Opt-in probabilistic checks of the device results.
+#[derive(Clone, Debug)]
+pub struct DeviceVerifyConfig {
+    ///
+    pub verify_msm: bool,
+    ///
+    pub verify_ntt: bool,
+    /// The fraction of the MSM terms recomputed on the CPU.
+    pub msm_sample_rate: f64,
+}
+
+impl Default for DeviceVerifyConfig {
+    fn default() -> Self {
+        Self {
+            verify_msm: true,
+            verify_ntt: true,
+            msm_sample_rate: 0.01,
+        }
+    }
+}
+
+/// Check that `output` is the NTT of `input` by `omega`, a primitive `n`-th
+/// root of unity.
+///
+/// For a random `rho`, the random linear combination of the evaluations
+/// `sum_i rho^i * output_i` must equal the input evaluated against
+/// `sum_i (rho * omega^j)^i`, that is `(rho^n - 1) * sum_j input_j / (rho * omega^j - 1)`.
+pub fn verify_ntt_output<Scalar: Field, G: FftGroup<Scalar>>(
+    input: &[G],
+    output: &[G],
+    omega: Scalar,
+    log_n: u32,
+) -> bool {
+    let n = 1usize << log_n;
+    assert_eq!(input.len(), n);
+    assert_eq!(output.len(), n);
+
+    let rho = Scalar::random(OsRng);
+    let numer = rho.pow_vartime(&[n as u64, 0, 0, 0]) - Scalar::ONE;
+
+    let mut denoms = Vec::with_capacity(n);
+    let mut omega_j = Scalar::ONE;
+    for _ in 0..n {
+        denoms.push(rho * omega_j - Scalar::ONE);
+        omega_j *= omega;
+    }
+    denoms.iter_mut().batch_invert();
+
+    let mut lhs = output[n - 1];
+    for value in output.iter().rev().skip(1) {
+        lhs = lhs * rho + *value;
+    }
+
+    let mut rhs = input[0] * denoms[0];
+    for (value, denom) in input.iter().zip(denoms.iter()).skip(1) {
+        rhs = rhs + *value * *denom;
+    }
+    rhs = rhs * numer;
+
+    lhs == rhs
+}
+
+impl DeviceManagerContext {
+    /// Enable the result checks, or disable them with `None`.
+    pub fn set_verify_config(&mut self, verify_config: Option<DeviceVerifyConfig>) {
+        self.verify_config = verify_config;
+    }
+
+    /// Run `execute_msm`, or `execute_msm_streaming` for a streamed param,
+    /// and, if enabled, check the result.
+    ///
+    /// The scalars are split with a random mask `r_i` at a sample of the
+    /// indices, and the devices run the MSMs of `s - r` and of `r` too. Their
+    /// sum must be the result, which catches a fixed offset in the device
+    /// results, and the MSM of `r` must match `sum_i r_i * bases_i` on the CPU.
+    pub fn execute_msm_verified<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases_index: usize,
+        scalars: &[C::Scalar],
+        bases: &[C],
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        let sample_rate = match &self.verify_config {
+            Some(config) if config.verify_msm => config.msm_sample_rate,
+            _ => return self.execute_msm_placed::<C>(msm_param_id, bases_index, scalars, bases),
+        };
+        assert_eq!(scalars.len(), bases.len());
+
+        let msm_result = self.execute_msm_placed::<C>(msm_param_id, bases_index, scalars, bases)?;
+        let device_id = self.last_device_id;
+
+        let _span = info_span!(
+            "device_manager.verify_msm",
+            param_id = msm_param_id,
+            bases_index,
+            scalars_num = scalars.len(),
+            sample_rate
+        )
+        .entered();
+
+        if scalars.is_empty() {
+            return Ok(msm_result);
+        }
+        let sample_num =
+            ((sample_rate.clamp(0f64, 1f64) * scalars.len() as f64).ceil() as usize).max(1);
+        let mut masked = scalars.to_vec();
+        let mut mask = vec![C::Scalar::ZERO; scalars.len()];
+        let mut mask_values = Vec::with_capacity(sample_num);
+        let mut mask_bases = Vec::with_capacity(sample_num);
+        for _ in 0..sample_num {
+            let i = (OsRng.next_u64() % scalars.len() as u64) as usize;
+            let r = C::Scalar::random(OsRng);
+            masked[i] -= r;
+            mask[i] += r;
+            mask_values.push(r);
+            mask_bases.push(bases[i]);
+        }
+
+        let masked_result =
+            self.execute_msm_placed::<C>(msm_param_id, bases_index, &masked, bases)?;
+        let masked_device_id = self.last_device_id;
+        let mask_result = self.execute_msm_placed::<C>(msm_param_id, bases_index, &mask, bases)?;
+        let mask_device_id = self.last_device_id;
+
+        let mask_point = curve_from_bytes::<C>(&mask_result)?;
+        if mask_point != best_multiexp_cpu(&mask_values, &mask_bases)
+            || curve_from_bytes::<C>(&masked_result)? + mask_point
+                != curve_from_bytes::<C>(&msm_result)?
+        {
+            // Any of the devices may be wrong.
+            let mut device_ids = vec![device_id, masked_device_id, mask_device_id];
+            device_ids.sort();
+            device_ids.dedup();
+            for id in device_ids {
+                self.report_verification_failure(id);
+            }
+            return Err(DeviceManagerError::DeviceManagerErrorVerification);
+        }
+
+        Ok(msm_result)
+    }
+
+    /// `execute_msm` on the devices holding the bases, or
+    /// `execute_msm_streaming` if the bases stay on the host.
+    fn execute_msm_placed<C: CurveAffine>(
+        &mut self,
+        msm_param_id: usize,
+        bases_index: usize,
+        scalars: &[C::Scalar],
+        bases: &[C],
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        if self.is_msm_param_streamed(msm_param_id) {
+            return self.execute_msm_streaming::<C>(
+                msm_param_id,
+                scalars,
+                bases,
+                &DeviceJobOptions::default(),
+            );
+        }
+        self.execute_msm::<C>(msm_param_id, bases_index, scalars)
+    }
+
+    /// Run `execute_ntt_multi_device` and, if enabled, check the result with
+    /// `verify_ntt_output`. On error the input is left untouched.
+    pub fn execute_ntt_verified<Scalar: Field, G: FftGroup<Scalar>>(
+        &mut self,
+        scalars: &mut [G],
+        omega: Scalar,
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        match &self.verify_config {
+            Some(config) if config.verify_ntt => {}
+            _ => {
+                return self.execute_ntt_multi_device::<Scalar, G>(
+                    scalars,
+                    omega,
+                    log_n,
+                    &DeviceJobOptions::default(),
+                )
+            }
+        }
+
+        let input = scalars.to_vec();
+        self.execute_ntt_multi_device::<Scalar, G>(
+            scalars,
+            omega,
+            log_n,
+            &DeviceJobOptions::default(),
+        )?;
+        let device_id = self.last_device_id;
+
+        let _span = info_span!("device_manager.verify_ntt", log_n).entered();
+        if !verify_ntt_output(&input, scalars, omega, log_n) {
+            scalars.copy_from_slice(&input);
+            return Err(self.report_verification_failure(device_id));
+        }
+
+        Ok(())
+    }
+
+    fn report_verification_failure(&mut self, device_id: Option<usize>) -> DeviceManagerError {
+        warn!(device_id = ?device_id, "Device result failed verification");
+        let error = DeviceManagerError::DeviceManagerErrorVerification;
+        self.metrics.record_error(&error);
+        if let Some(device_id) = device_id {
+            self.quarantine_device(device_id);
+        }
+        error
+    }
+}
+
+#[test]
+fn test_verify_ntt_output() {
+    use crate::arithmetic::best_fft_cpu;
+    use group::Group;
+    use halo2curves::bn256::{Fr, G1};
+    use halo2curves::ff::PrimeField;
+
+    let log_n = 8;
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in log_n..Fr::S {
+        omega = omega.square();
+    }
+
+    let input = (0..(1 << log_n))
+        .map(|_| Fr::random(OsRng))
+        .collect::<Vec<_>>();
+    let mut output = input.clone();
+    best_fft_cpu(&mut output, omega, log_n);
+    assert!(verify_ntt_output(&input, &output, omega, log_n));
+
+    output[17] += Fr::ONE;
+    assert!(!verify_ntt_output(&input, &output, omega, log_n));
+
+    // Projective points are compared by value too.
+    let input = (0..(1 << log_n))
+        .map(|_| G1::random(OsRng))
+        .collect::<Vec<_>>();
+    let mut output = input.clone();
+    best_fft_cpu(&mut output, omega, log_n);
+    assert!(verify_ntt_output(&input, &output, omega, log_n));
+
+    output[17] += G1::generator();
+    assert!(!verify_ntt_output(&input, &output, omega, log_n));
+}
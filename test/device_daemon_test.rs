@@ -0,0 +1,201 @@
+#![cfg(unix)]
+
+use group::{ff::Field, ff::PrimeField, Curve, Group};
+use halo2_proofs::arithmetic::*;
+use halo2_proofs::device_manager::*;
+use halo2curves::bn256::{Fr, G1Affine, G1};
+use rand_core::OsRng;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LOG_N: u32 = 6;
+
+/// Start a daemon over two mock devices on a fresh socket.
+fn start_daemon(name: &str) -> PathBuf {
+    start_daemon_with(
+        name,
+        MockDeviceBackend::new(2),
+        DeviceJobQueueConfig::default(),
+    )
+}
+
+fn start_daemon_with(
+    name: &str,
+    backend: MockDeviceBackend,
+    queue_config: DeviceJobQueueConfig,
+) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("halo2-device-{}-{}.sock", name, std::process::id()));
+    let device_manager = DeviceManager::with_backend(Arc::new(backend));
+    let daemon = DeviceDaemon::bind(&path, device_manager).unwrap();
+    daemon.set_queue_config(queue_config);
+    std::thread::spawn(move || daemon.serve());
+    path
+}
+
+/// A client device manager, as a prover process would have.
+fn client_device_manager(path: &PathBuf, bases: &[G1Affine], omega: Fr) -> DeviceManager {
+    let mut device_manager = DeviceManager::with_backend(Arc::new(DaemonClientBackend::new(path)));
+    let handle = device_manager.get_handle_mut();
+    handle
+        .init(
+            DeviceInitUnitType::DevicerInitUnitTypeALL,
+            Some(0),
+            Some(&[transmute_values(bases)]),
+            Some(transmute_values(&[omega])),
+        )
+        .unwrap();
+    device_manager
+}
+
+fn omega() -> Fr {
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in LOG_N..Fr::S {
+        omega = omega.square();
+    }
+    omega
+}
+
+#[test]
+fn device_daemon_serves_clients() {
+    let path = start_daemon("serve");
+    let bases: Vec<G1Affine> = (0..1 << LOG_N)
+        .map(|_| G1::random(OsRng).to_affine())
+        .collect();
+
+    // Two provers with the same bases share one registration.
+    let mut clients = [
+        client_device_manager(&path, &bases, omega()),
+        client_device_manager(&path, &bases, omega()),
+    ];
+    let status = DaemonClientBackend::new(&path).status().unwrap();
+    assert_eq!(status.device_num, 2);
+    assert_eq!(status.registration_num, 1);
+    assert_eq!(status.ready_device_ids, vec![0, 1]);
+    assert!(status.quarantined_device_ids.is_empty());
+    assert_eq!(status.queue.max_depth, 64);
+    assert!(status.queue.waiting.is_empty());
+
+    for client in clients.iter_mut() {
+        let handle = client.get_handle_mut();
+
+        let scalars: Vec<Fr> = (0..1 << LOG_N).map(|_| Fr::random(OsRng)).collect();
+        let result = handle.execute_msm::<G1Affine>(0, 0, &scalars).unwrap();
+        let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+        assert_eq!(curve_from_bytes::<G1Affine>(&result).unwrap(), expected);
+
+        let mut values: Vec<Fr> = (0..1 << LOG_N).map(|_| Fr::random(OsRng)).collect();
+        let mut expected = values.clone();
+        best_fft_cpu(&mut expected, omega(), LOG_N);
+        handle.execute_ntt::<Fr, Fr>(&mut values, LOG_N).unwrap();
+        assert_eq!(values, expected);
+    }
+
+    let status = DaemonClientBackend::new(&path).status().unwrap();
+    assert!(status
+        .metrics
+        .contains("halo2_device_jobs_total{unit=\"msm\","));
+}
+
+#[test]
+fn device_daemon_runs_jobs_concurrently() {
+    let latency = Duration::from_millis(500);
+    let path = start_daemon_with(
+        "concurrent",
+        MockDeviceBackend::new(2).with_latency(latency),
+        DeviceJobQueueConfig {
+            max_depth: 64,
+            slots: 2,
+        },
+    );
+    let bases: Vec<G1Affine> = (0..1 << LOG_N)
+        .map(|_| G1::random(OsRng).to_affine())
+        .collect();
+
+    let jobs: Vec<_> = (0..2)
+        .map(|_| {
+            let mut client = client_device_manager(&path, &bases, omega());
+            let bases = bases.clone();
+            std::thread::spawn(move || {
+                let scalars: Vec<Fr> = (0..1 << LOG_N).map(|_| Fr::random(OsRng)).collect();
+                let result = client
+                    .get_handle_mut()
+                    .execute_msm::<G1Affine>(0, 0, &scalars)
+                    .unwrap();
+                let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+                assert_eq!(curve_from_bytes::<G1Affine>(&result).unwrap(), expected);
+            })
+        })
+        .collect();
+
+    // The status does not wait for the running jobs.
+    let client = DaemonClientBackend::new(&path);
+    let start = Instant::now();
+    let mut status = client.status().unwrap();
+    while status.queue.running.len() < 2 && start.elapsed() < latency {
+        status = client.status().unwrap();
+    }
+    assert!(start.elapsed() < latency);
+    assert_eq!(status.queue.running.len(), 2);
+
+    for job in jobs {
+        job.join().unwrap();
+    }
+    let status = client.status().unwrap();
+    assert_eq!(status.registration_num, 1);
+    assert!(status
+        .metrics
+        .contains("halo2_device_jobs_total{unit=\"msm\","));
+}
+
+#[test]
+fn device_daemon_returns_errors() {
+    let path = start_daemon("errors");
+    let bases: Vec<G1Affine> = (0..1 << LOG_N)
+        .map(|_| G1::random(OsRng).to_affine())
+        .collect();
+
+    let client = DaemonClientBackend::new(&path);
+    let handle = client
+        .init(
+            0,
+            DeviceInitUnitType::DeviceInitUnitTypeMSM,
+            Some(&[transmute_values(&bases)]),
+            None,
+        )
+        .unwrap();
+
+    let scalars = vec![Fr::ONE; 1 << LOG_N];
+    let unknown = DeviceHandle {
+        device_id: 0,
+        handle_id: handle.handle_id + 1,
+    };
+    let error = client
+        .msm(&unknown, transmute_values(&scalars), 0)
+        .unwrap_err();
+    assert_eq!(error.kind(), "param_id_none");
+
+    // An NTT needs a registered omega, and fits into its domain.
+    let mut values = transmute_values(&vec![Fr::ONE; 2 << LOG_N]).to_vec();
+    let error = client.ntt(&handle, &mut values, LOG_N + 1).unwrap_err();
+    assert_eq!(error.kind(), "param_id_none");
+    let ntt_handle = client
+        .init(
+            0,
+            DeviceInitUnitType::DeviceInitUnitTypeNTT,
+            None,
+            Some(transmute_values(&[omega()])),
+        )
+        .unwrap();
+    let error = client.ntt(&ntt_handle, &mut values, LOG_N + 1).unwrap_err();
+    assert_eq!(error.kind(), "daemon_io");
+
+    let error = client
+        .init(0, DeviceInitUnitType::DeviceInitUnitTypeNone, None, None)
+        .unwrap_err();
+    assert_eq!(error.kind(), "daemon_io");
+
+    let unreachable = DaemonClientBackend::new(path.with_extension("missing"));
+    assert_eq!(unreachable.status().unwrap_err().kind(), "daemon_io");
+}
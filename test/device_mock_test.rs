@@ -0,0 +1,1057 @@
+use group::{ff::Field, ff::PrimeField, Curve, Group};
+use halo2_proofs::arithmetic::*;
+use halo2_proofs::device_manager::*;
+use halo2curves::bn256::{Fr, G1Affine, G1};
+use rand_core::OsRng;
+use std::sync::Arc;
+use std::time::Duration;
+
+const PARAM_ID: usize = 0;
+const LOG_N: u32 = 6;
+
+fn gen_bases() -> Vec<G1Affine> {
+    (0..1 << LOG_N)
+        .map(|_| G1::random(OsRng).to_affine())
+        .collect()
+}
+
+fn gen_scalars() -> Vec<Fr> {
+    (0..1 << LOG_N).map(|_| Fr::random(OsRng)).collect()
+}
+
+fn omega() -> Fr {
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in LOG_N..Fr::S {
+        omega = omega.square();
+    }
+    omega
+}
+
+/// A device manager over the mock with MSM and NTT set up on every device.
+fn mock_device_manager(backend: &Arc<MockDeviceBackend>, bases: &[G1Affine]) -> DeviceManager {
+    let mut device_manager = DeviceManager::with_backend(backend.clone());
+    let handle = device_manager.get_handle_mut();
+    let bases_bytes = transmute_values(bases);
+    handle
+        .init(
+            DeviceInitUnitType::DevicerInitUnitTypeALL,
+            Some(PARAM_ID),
+            Some(&[bases_bytes]),
+            None,
+        )
+        .unwrap();
+    handle.set_health_config(DeviceHealthConfig {
+        failure_threshold: 2,
+        probe_interval: Duration::from_secs(3600),
+        probe_log_n: LOG_N,
+    });
+    device_manager
+}
+
+fn execute_msm(
+    handle: &mut DeviceManagerContext,
+    scalars: &[Fr],
+) -> Result<G1, DeviceManagerError> {
+    let result = handle.execute_msm::<G1Affine>(PARAM_ID, 0, scalars)?;
+    curve_from_bytes::<G1Affine>(&result)
+}
+
+#[test]
+fn mock_device_results_match_cpu() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let scalars = gen_scalars();
+    let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+
+    let mut values = gen_scalars();
+    let mut expected = values.clone();
+    best_fft_cpu(&mut expected, omega(), LOG_N);
+    handle.execute_ntt::<Fr, Fr>(&mut values, LOG_N).unwrap();
+    assert_eq!(values, expected);
+}
+
+#[test]
+fn mock_device_failure_is_retried_on_another_device() {
+    let bases = gen_bases();
+    let backend = Arc::new(
+        MockDeviceBackend::new(2).fail_on_call(1, DeviceManagerError::DeviceManagerErrorExecute),
+    );
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let scalars = gen_scalars();
+    let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.calls(), vec![0, 1]);
+    assert!(handle.quarantined_device_ids().is_empty());
+
+    let metrics = handle.metrics_snapshot();
+    assert_eq!(metrics.errors.get("execute"), Some(&1));
+}
+
+#[test]
+fn mock_device_is_quarantined_after_repeated_failures() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2).fail_device(0));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let scalars = gen_scalars();
+    for _ in 0..3 {
+        execute_msm(handle, &scalars).unwrap();
+    }
+    assert_eq!(handle.quarantined_device_ids(), vec![0]);
+    // Two failed attempts on device 0, then device 1 only.
+    assert_eq!(backend.calls(), vec![0, 1, 0, 1, 1]);
+}
+
+#[test]
+fn mock_device_all_failing_returns_error() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2).fail_device(0).fail_device(1));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let scalars = gen_scalars();
+    assert_eq!(
+        execute_msm(handle, &scalars).unwrap_err().kind(),
+        "no_available_device"
+    );
+
+    let mut values = gen_scalars();
+    let input = values.clone();
+    assert!(handle.execute_ntt::<Fr, Fr>(&mut values, LOG_N).is_err());
+    assert_eq!(values, input);
+}
+
+#[test]
+fn mock_device_busy_device_is_skipped() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    backend.set_busy(0, true);
+    execute_msm(handle, &gen_scalars()).unwrap();
+    assert_eq!(backend.calls(), vec![1]);
+
+    backend.set_busy(1, true);
+    assert!(execute_msm(handle, &gen_scalars()).is_err());
+}
+
+#[test]
+fn mock_device_corruption_is_caught_by_verification() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1).corrupt_device(0));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_verify_config(Some(DeviceVerifyConfig::default()));
+
+    let mut values = gen_scalars();
+    let input = values.clone();
+    assert_eq!(
+        handle
+            .execute_ntt_verified::<Fr, Fr>(&mut values, omega(), LOG_N)
+            .unwrap_err()
+            .kind(),
+        "verification"
+    );
+    assert_eq!(values, input);
+    assert_eq!(handle.quarantined_device_ids(), vec![0]);
+}
+
+#[test]
+fn mock_device_msm_offset_is_caught_by_verification() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1).with_fault(MockDeviceFaultRule {
+        device_id: Some(0),
+        call: None,
+        fault: MockDeviceFault::OffsetResult,
+    }));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_verify_config(Some(DeviceVerifyConfig::default()));
+
+    // Every result is off by the same point, so a difference of two results
+    // would match.
+    let scalars = gen_scalars();
+    assert_eq!(
+        handle
+            .execute_msm_verified::<G1Affine>(PARAM_ID, 0, &scalars, &bases)
+            .unwrap_err()
+            .kind(),
+        "verification"
+    );
+    assert_eq!(handle.quarantined_device_ids(), vec![0]);
+}
+
+#[test]
+fn mock_device_is_readmitted_after_self_test() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1).fail_device(0));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_health_config(DeviceHealthConfig {
+        failure_threshold: 1,
+        probe_interval: Duration::ZERO,
+        probe_log_n: LOG_N,
+    });
+
+    let scalars = gen_scalars();
+    assert!(execute_msm(handle, &scalars).is_err());
+    assert_eq!(handle.quarantined_device_ids(), vec![0]);
+
+    // Still failing, the self-test keeps it quarantined.
+    assert!(execute_msm(handle, &scalars).is_err());
+    assert_eq!(handle.quarantined_device_ids(), vec![0]);
+
+    backend.clear_faults();
+    let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert!(handle.quarantined_device_ids().is_empty());
+}
+
+#[test]
+fn mock_device_self_test_checks_random_inputs() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2).with_sparse_scalars().with_fault(
+        MockDeviceFaultRule {
+            device_id: Some(1),
+            call: None,
+            fault: MockDeviceFault::OffsetResult,
+        },
+    ));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    // The MSM reaches the device, not the sparse path.
+    let calls = backend.calls().len();
+    assert!(handle.self_test_device(0));
+    assert_eq!(backend.calls().len(), calls + 2);
+    assert_eq!(backend.sparse_calls(), 0);
+
+    // Only the MSM of this device is off, by a fixed point.
+    assert!(!handle.self_test_device(1));
+}
+
+#[test]
+fn mock_device_job_times_out() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    backend.set_latency(Duration::from_millis(500));
+
+    let scalars = gen_scalars();
+    let options = DeviceJobOptions {
+        timeout: Some(Duration::from_millis(50)),
+        cancel: None,
+    };
+    let error = handle
+        .execute_msm_with_options::<G1Affine>(PARAM_ID, 0, &scalars, &options)
+        .unwrap_err();
+    assert_eq!(error.kind(), "timeout");
+    // The abandoned call still occupies its device, which may be hung.
+    assert!(handle.is_device_busy(0));
+    assert!(!handle.is_device_busy(1));
+    assert_eq!(handle.quarantined_device_ids(), vec![0]);
+
+    let mut values = gen_scalars();
+    let input = values.clone();
+    let error = handle
+        .execute_ntt_with_options::<Fr, Fr>(&mut values, LOG_N, &options)
+        .unwrap_err();
+    assert_eq!(error.kind(), "timeout");
+    assert_eq!(values, input);
+
+    let metrics = handle.metrics_snapshot();
+    assert_eq!(metrics.errors.get("timeout"), Some(&2));
+}
+
+#[test]
+fn mock_device_job_is_cancelled() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    backend.set_latency(Duration::from_millis(500));
+
+    let cancel = DeviceCancellationToken::new();
+    let options = DeviceJobOptions {
+        timeout: None,
+        cancel: Some(cancel.clone()),
+    };
+    let canceller = {
+        let cancel = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        })
+    };
+
+    let scalars = gen_scalars();
+    let error = handle
+        .execute_msm_with_options::<G1Affine>(PARAM_ID, 0, &scalars, &options)
+        .unwrap_err();
+    assert_eq!(error.kind(), "cancelled");
+    canceller.join().unwrap();
+    // Cancelling is not a device failure.
+    assert!(handle.quarantined_device_ids().is_empty());
+
+    // A cancelled token fails the job before it reaches a device.
+    let calls = backend.calls().len();
+    let mut values = gen_scalars();
+    assert_eq!(
+        handle
+            .execute_ntt_with_options::<Fr, Fr>(&mut values, LOG_N, &options)
+            .unwrap_err()
+            .kind(),
+        "cancelled"
+    );
+    assert_eq!(backend.calls().len(), calls);
+}
+
+#[test]
+fn mock_device_msm_is_sharded_across_devices() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_shard_config(Some(DeviceShardConfig {
+        min_n: 1 << (LOG_N - 1),
+        min_shard_n: 4,
+        ..Default::default()
+    }));
+
+    // Device 1 was measured three times as fast.
+    handle.msm_throughput.insert(0, 1e6);
+    handle.msm_throughput.insert(1, 3e6);
+    let shards = handle.plan_msm_shards(PARAM_ID, 1 << LOG_N);
+    assert_eq!(
+        shards
+            .iter()
+            .map(|shard| (shard.device_id, shard.len))
+            .collect::<Vec<_>>(),
+        vec![(1, 3 << (LOG_N - 2)), (0, 1 << (LOG_N - 2))]
+    );
+
+    let scalars = gen_scalars();
+    let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    let mut calls = backend.calls();
+    calls.sort();
+    assert_eq!(calls, vec![0, 1]);
+
+    // Below the threshold the MSM runs on one device.
+    let expected: G1 = best_multiexp_cpu(&scalars[..4], &bases[..4]);
+    assert_eq!(execute_msm(handle, &scalars[..4]).unwrap(), expected);
+    assert_eq!(backend.calls().len(), 3);
+}
+
+#[test]
+fn mock_device_failed_msm_shard_is_retried() {
+    let bases = gen_bases();
+    let backend = Arc::new(
+        MockDeviceBackend::new(2).fail_on_call(1, DeviceManagerError::DeviceManagerErrorExecute),
+    );
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_shard_config(Some(DeviceShardConfig {
+        min_n: 1 << (LOG_N - 1),
+        min_shard_n: 4,
+        ..Default::default()
+    }));
+
+    let scalars = gen_scalars();
+    let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.calls().len(), 3);
+    assert!(handle.quarantined_device_ids().is_empty());
+
+    let metrics = handle.metrics_snapshot();
+    assert_eq!(metrics.errors.get("execute"), Some(&1));
+}
+
+#[test]
+fn mock_device_ntt_four_step_matches_cpu() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_shard_config(Some(DeviceShardConfig {
+        min_ntt_log_n: 4,
+        ..Default::default()
+    }));
+
+    // Even and odd sizes split into equal and unequal halves.
+    for log_n in [LOG_N, LOG_N - 1] {
+        let mut omega = Fr::ROOT_OF_UNITY;
+        for _ in log_n..Fr::S {
+            omega = omega.square();
+        }
+        let mut values: Vec<Fr> = (0..1 << log_n).map(|_| Fr::random(OsRng)).collect();
+        let mut expected = values.clone();
+        best_fft_cpu(&mut expected, omega, log_n);
+        handle
+            .execute_ntt_multi_device::<Fr, Fr>(
+                &mut values,
+                omega,
+                log_n,
+                &DeviceJobOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(values, expected);
+    }
+    let mut calls = backend.calls();
+    calls.sort();
+    calls.dedup();
+    assert_eq!(calls, vec![0, 1]);
+
+    // A failed share is retried on the other device.
+    let backend = Arc::new(
+        MockDeviceBackend::new(2).fail_on_call(1, DeviceManagerError::DeviceManagerErrorExecute),
+    );
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_shard_config(Some(DeviceShardConfig {
+        min_ntt_log_n: 4,
+        ..Default::default()
+    }));
+    let mut values = gen_scalars();
+    let mut expected = values.clone();
+    best_fft_cpu(&mut expected, omega(), LOG_N);
+    handle
+        .execute_ntt_multi_device::<Fr, Fr>(
+            &mut values,
+            omega(),
+            LOG_N,
+            &DeviceJobOptions::default(),
+        )
+        .unwrap();
+    assert_eq!(values, expected);
+    assert_eq!(handle.metrics_snapshot().errors.get("execute"), Some(&1));
+}
+
+/// The mock with the default implementations of the optional calls.
+#[derive(Debug)]
+struct DefaultMockBackend(MockDeviceBackend);
+
+impl DeviceBackend for DefaultMockBackend {
+    fn device_number(&self) -> Result<usize, DeviceManagerError> {
+        self.0.device_number()
+    }
+
+    fn init(
+        &self,
+        device_id: usize,
+        init_device_unit_type: DeviceInitUnitType,
+        bases: Option<&[&[u8]]>,
+        omega: Option<&[u8]>,
+    ) -> Result<DeviceHandle, DeviceManagerError> {
+        self.0.init(device_id, init_device_unit_type, bases, omega)
+    }
+
+    fn deinit(&self, handle: &DeviceHandle) {
+        self.0.deinit(handle)
+    }
+
+    fn msm(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &[u8],
+        bases_index: usize,
+    ) -> Result<Vec<u8>, DeviceManagerError> {
+        self.0.msm(handle, scalars, bases_index)
+    }
+
+    fn ntt(
+        &self,
+        handle: &DeviceHandle,
+        scalars: &mut [u8],
+        log_n: u32,
+    ) -> Result<(), DeviceManagerError> {
+        self.0.ntt(handle, scalars, log_n)
+    }
+}
+
+#[test]
+fn default_ntt_batch_checks_the_length() {
+    let backend = DefaultMockBackend(MockDeviceBackend::new(1));
+    let handle = backend
+        .init(0, DeviceInitUnitType::DeviceInitUnitTypeNTT, None, None)
+        .unwrap();
+
+    let log_n = 3;
+    let values: Vec<Fr> = (0..2 << log_n).map(|_| Fr::random(OsRng)).collect();
+    let mut expected = values.clone();
+    for part in expected.chunks_mut(1 << log_n) {
+        best_fft_cpu(part, device_ntt_root(log_n), log_n);
+    }
+    let mut bytes = transmute_values(&values).to_vec();
+    backend.ntt_batch(&handle, &mut bytes, log_n, 2).unwrap();
+    assert_eq!(values_from_bytes::<Fr>(&bytes).unwrap(), expected);
+
+    // The parts are cut by their size, not by the length over the batch.
+    for (log_n, batch) in [(log_n, 1), (log_n, 4), (log_n - 1, 2)] {
+        assert_eq!(
+            backend
+                .ntt_batch(&handle, &mut bytes, log_n, batch)
+                .unwrap_err()
+                .kind(),
+            "execute"
+        );
+    }
+    assert_eq!(values_from_bytes::<Fr>(&bytes).unwrap(), expected);
+}
+
+#[test]
+fn mock_device_ntt_inverse_matches_cpu() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_shard_config(Some(DeviceShardConfig {
+        min_ntt_log_n: 4,
+        ..Default::default()
+    }));
+
+    // Four-step on both devices, and on one device.
+    for log_n in [LOG_N, 3] {
+        let mut omega_inv = Fr::ROOT_OF_UNITY_INV;
+        for _ in log_n..Fr::S {
+            omega_inv = omega_inv.square();
+        }
+        let mut values: Vec<Fr> = (0..1 << log_n).map(|_| Fr::random(OsRng)).collect();
+        let mut expected = values.clone();
+        best_fft_cpu(&mut expected, omega_inv, log_n);
+        handle
+            .execute_ntt_multi_device::<Fr, Fr>(
+                &mut values,
+                omega_inv,
+                log_n,
+                &DeviceJobOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(values, expected);
+    }
+    // One launch per device and step of the four-step NTT.
+    assert_eq!(backend.ntt_batch_calls(), 4);
+
+    // The devices have no other roots.
+    let mut values = gen_scalars();
+    let input = values.clone();
+    assert_eq!(
+        handle
+            .execute_ntt_multi_device::<Fr, Fr>(
+                &mut values,
+                omega().square(),
+                LOG_N,
+                &DeviceJobOptions::default(),
+            )
+            .unwrap_err()
+            .kind(),
+        "execute"
+    );
+    assert_eq!(values, input);
+}
+
+#[test]
+fn mock_device_ntt_larger_than_memory_runs_out_of_core() {
+    let bases = gen_bases();
+    // Half of the memory is left to the device, so NTTs up to 2^4 fit.
+    let backend = Arc::new(MockDeviceBackend::new(1).with_memory(1 << 10));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    assert_eq!(handle.max_device_ntt_log_n::<Fr>(&[0]), Some(4));
+
+    let mut values = gen_scalars();
+    let input = values.clone();
+    assert_eq!(
+        handle
+            .execute_ntt::<Fr, Fr>(&mut values, LOG_N)
+            .unwrap_err()
+            .kind(),
+        "out_of_memory"
+    );
+    assert_eq!(values, input);
+    // Running out of memory is no fault of the device.
+    assert!(handle.quarantined_device_ids().is_empty());
+
+    let mut expected = values.clone();
+    best_fft_cpu(&mut expected, omega(), LOG_N);
+    handle
+        .execute_ntt_multi_device::<Fr, Fr>(
+            &mut values,
+            omega(),
+            LOG_N,
+            &DeviceJobOptions::default(),
+        )
+        .unwrap();
+    assert_eq!(values, expected);
+
+    // Beyond 2^8 the columns and rows no longer fit.
+    let log_n = 9;
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in log_n..Fr::S {
+        omega = omega.square();
+    }
+    let mut values: Vec<Fr> = (0..1 << log_n).map(|_| Fr::random(OsRng)).collect();
+    assert_eq!(
+        handle
+            .execute_ntt_multi_device::<Fr, Fr>(
+                &mut values,
+                omega,
+                log_n,
+                &DeviceJobOptions::default()
+            )
+            .unwrap_err()
+            .kind(),
+        "out_of_memory"
+    );
+}
+
+#[test]
+fn mock_device_memory_is_set_when_not_reported() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    let bases_bytes = transmute_values(&bases[..]);
+    assert_eq!(handle.max_device_ntt_log_n::<Fr>(&[0]), None);
+    assert!(handle.msm_bases_fit(0, Some(&[bases_bytes])));
+
+    handle.set_device_memory(Some(1 << 10));
+    assert_eq!(handle.device_free_memory(0), Some(1 << 10));
+    assert_eq!(handle.max_device_ntt_log_n::<Fr>(&[0]), Some(4));
+    assert!(!handle.msm_bases_fit(0, Some(&[bases_bytes])));
+
+    // What the backend reports takes precedence.
+    let backend = Arc::new(MockDeviceBackend::new(1).with_memory(1 << 12));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    handle.set_device_memory(Some(1 << 10));
+    assert_eq!(handle.max_device_ntt_log_n::<Fr>(&[0]), Some(6));
+}
+
+#[test]
+fn mock_device_msm_larger_than_memory_is_streamed() {
+    let bases = gen_bases();
+    // The bases take all of the memory, so they are uploaded in chunks.
+    let backend =
+        Arc::new(MockDeviceBackend::new(1).with_memory(std::mem::size_of_val(&bases[..])));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    assert!(handle.is_msm_param_streamed(PARAM_ID));
+
+    let scalars = gen_scalars();
+    assert_eq!(
+        execute_msm(handle, &scalars).unwrap_err().kind(),
+        "out_of_memory"
+    );
+
+    let deinit_calls = backend.deinit_calls();
+    let result = handle
+        .execute_msm_streaming::<G1Affine>(PARAM_ID, &scalars, &bases, &DeviceJobOptions::default())
+        .unwrap();
+    let expected: G1 = best_multiexp_cpu(&scalars, &bases);
+    assert_eq!(curve_from_bytes::<G1Affine>(&result).unwrap(), expected);
+
+    let report = handle.last_msm_stream_report.clone().unwrap();
+    assert_eq!(report.n, scalars.len());
+    assert!(report.chunk_num > 1);
+    assert_eq!(handle.metrics_snapshot().streamed.get("msm"), Some(&1));
+    assert!(handle.quarantined_device_ids().is_empty());
+    // One handle takes every chunk and is released afterwards.
+    assert_eq!(backend.bases_writes(), report.chunk_num);
+    assert_eq!(backend.deinit_calls(), deinit_calls + 1);
+
+    // Verified MSMs of the param are streamed too.
+    handle.set_verify_config(Some(DeviceVerifyConfig::default()));
+    let result = handle
+        .execute_msm_verified::<G1Affine>(PARAM_ID, 0, &scalars, &bases)
+        .unwrap();
+    assert_eq!(curve_from_bytes::<G1Affine>(&result).unwrap(), expected);
+    assert_eq!(handle.metrics_snapshot().streamed.get("msm"), Some(&4));
+
+    // The bases of an MSM unit belong to a param.
+    assert_eq!(
+        handle
+            .init(
+                DeviceInitUnitType::DeviceInitUnitTypeMSM,
+                None,
+                Some(&[transmute_values(&bases[..])]),
+                None,
+            )
+            .unwrap_err()
+            .kind(),
+        "param_id_none"
+    );
+}
+
+#[test]
+fn mock_device_msm_takes_signed_digits() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1).with_signed_digits(5));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let mut scalars = gen_scalars();
+    scalars[0] = -Fr::ONE;
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.signed_digit_calls(), 1);
+}
+
+#[test]
+fn mock_device_msm_uses_fixed_base_table() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let config = FixedBaseConfig {
+        window: 4,
+        tables: 8,
+    };
+    handle.set_fixed_base_config(PARAM_ID, Some(config));
+    handle
+        .init_fixed_base::<G1Affine>(PARAM_ID, &[&bases])
+        .unwrap();
+    let table = handle.fixed_base_table(PARAM_ID, 0).unwrap();
+    assert_eq!(table.device_ids, vec![0, 1]);
+    let points = table.points::<G1Affine>().unwrap();
+    assert_eq!(points.len(), config.table_len(bases.len()));
+
+    let scalars = gen_scalars();
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(fixed_base_multiexp(&scalars, &points, &config), expected);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.fixed_base_calls(), 1);
+
+    handle.deinit().unwrap();
+    assert!(handle.fixed_base_table(PARAM_ID, 0).is_none());
+}
+
+#[test]
+fn mock_device_msm_takes_glv_halves() {
+    let bases = gen_bases();
+    // GLV goes before signed digits.
+    let backend = Arc::new(MockDeviceBackend::new(1).with_glv().with_signed_digits(5));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let mut scalars = gen_scalars();
+    scalars[0] = -Fr::ONE;
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.glv_calls(), 1);
+    assert_eq!(backend.signed_digit_calls(), 0);
+}
+
+#[test]
+fn mock_device_msm_takes_small_scalars() {
+    let bases = gen_bases();
+    // Small scalars go before GLV.
+    let backend = Arc::new(MockDeviceBackend::new(1).with_small_scalars().with_glv());
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let mut scalars: Vec<Fr> = (0..bases.len() as u64)
+        .map(|i| Fr::from(u64::MAX - i * i))
+        .collect();
+    scalars[1] = Fr::ONE;
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.small_calls(), 1);
+    assert_eq!(backend.glv_calls(), 0);
+
+    scalars[0] = -Fr::ONE;
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.small_calls(), 1);
+    assert_eq!(backend.glv_calls(), 1);
+}
+
+#[test]
+fn mock_device_msm_takes_sparse_scalars() {
+    let bases = gen_bases();
+    // Sparse scalars go before small ones.
+    let backend = Arc::new(
+        MockDeviceBackend::new(1)
+            .with_sparse_scalars()
+            .with_small_scalars(),
+    );
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let mut scalars: Vec<Fr> = (0..bases.len())
+        .map(|i| match i % 8 {
+            0 => Fr::random(OsRng),
+            _ => Fr::ZERO,
+        })
+        .collect();
+    scalars[3] = Fr::ONE;
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.sparse_calls(), 1);
+
+    // Denser scalars go as they are.
+    let scalars = gen_scalars();
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.sparse_calls(), 1);
+    assert_eq!(backend.small_calls(), 0);
+}
+
+#[test]
+fn mock_device_msm_skips_zero_scalars() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+    let calls = backend.calls().len();
+
+    let mut scalars = vec![Fr::ZERO; bases.len()];
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), G1::identity());
+    assert_eq!(backend.calls().len(), calls);
+    assert_eq!(handle.last_device_id, None);
+
+    scalars[3] = Fr::random(OsRng);
+    let expected: G1 = small_multiexp(&scalars, &bases);
+    assert_eq!(execute_msm(handle, &scalars).unwrap(), expected);
+    assert_eq!(backend.calls().len(), calls + 1);
+}
+
+#[test]
+fn curve_from_bytes_rejects_bad_lengths() {
+    let point = G1::generator();
+    let bytes = transmute_values(&[point]).to_vec();
+    assert_eq!(curve_from_bytes::<G1Affine>(&bytes).unwrap(), point);
+    assert_eq!(
+        curve_from_bytes::<G1Affine>(&bytes[1..])
+            .unwrap_err()
+            .kind(),
+        "execute"
+    );
+    assert!(curve_from_bytes::<G1Affine>(&[]).is_err());
+}
+
+#[test]
+fn mock_device_coset_extend_round_trips() {
+    let bases = gen_bases();
+    let log_n = LOG_N - 2;
+    let zeta = Fr::from(7);
+    let poly: Vec<Fr> = (0..1 << log_n).map(|_| Fr::random(OsRng)).collect();
+    let expected = coset_extend_cpu(&poly, log_n, LOG_N, zeta);
+    for (i, value) in expected.iter().enumerate() {
+        let point = zeta * omega().pow_vartime([i as u64]);
+        assert_eq!(*value, eval_polynomial(&poly, point));
+    }
+
+    // Fused on the device, or composed of `execute_ntt` and host passes.
+    for fused in [true, false] {
+        let backend = MockDeviceBackend::new(1);
+        let backend = Arc::new(if fused {
+            backend.with_coset_extend()
+        } else {
+            backend
+        });
+        let mut device_manager = mock_device_manager(&backend, &bases);
+        let handle = device_manager.get_handle_mut();
+
+        let values = handle
+            .execute_coset_extend::<Fr, Fr>(&poly, log_n, LOG_N, zeta)
+            .unwrap();
+        assert_eq!(values, expected);
+        let coeffs = handle
+            .execute_extended_to_coeff::<Fr, Fr>(&values, log_n, LOG_N, zeta)
+            .unwrap();
+        assert_eq!(coeffs, poly);
+        assert_eq!(backend.coset_calls(), if fused { 2 } else { 0 });
+    }
+}
+
+#[test]
+fn mock_device_buffer_commits_after_intt() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(2).with_buffers());
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let evals = gen_scalars();
+    let mut coeffs = evals.clone();
+    best_fft_cpu(&mut coeffs, omega().invert().unwrap(), LOG_N);
+    let n_inv = Fr::from(1 << LOG_N).invert().unwrap();
+    coeffs.iter_mut().for_each(|coeff| *coeff *= n_inv);
+
+    // The coefficients are committed to without leaving the device.
+    let buffer = handle.upload_buffer(&evals).unwrap();
+    assert_eq!(handle.buffer_bytes(buffer.device_id), 32 << LOG_N);
+    handle
+        .execute_intt_buffer::<Fr, Fr>(&buffer, LOG_N)
+        .unwrap();
+    let commitment = handle
+        .execute_msm_buffer::<G1Affine>(PARAM_ID, 0, &buffer)
+        .unwrap();
+    let expected: G1 = small_multiexp(&coeffs, &bases);
+    assert_eq!(curve_from_bytes::<G1Affine>(&commitment).unwrap(), expected);
+    assert_eq!(backend.buffer_reads(), 0);
+
+    handle.execute_ntt_buffer::<Fr, Fr>(&buffer, LOG_N).unwrap();
+    assert_eq!(handle.download_buffer(&buffer).unwrap(), evals);
+    assert_eq!(backend.buffer_reads(), 1);
+
+    // The next buffer goes to the device with fewer buffer bytes.
+    let other = handle.upload_buffer(&coeffs).unwrap();
+    assert_ne!(other.device_id, buffer.device_id);
+    handle.free_buffer(buffer);
+    assert_eq!(backend.buffer_count(), 1);
+    let other_device_id = other.device_id;
+    drop(other);
+    // The drop frees it on the worker of the device, before this call.
+    let limits = handle.job_limits(&DeviceJobOptions::default());
+    handle
+        .run_device_job(other_device_id, &limits, |_| Ok(()))
+        .unwrap();
+    assert_eq!(backend.buffer_count(), 0);
+
+    // A quarantined device keeps its buffers until they are read back.
+    let buffer = handle.upload_buffer(&evals).unwrap();
+    handle.quarantine_device(buffer.device_id);
+    assert!(handle.execute_ntt_buffer::<Fr, Fr>(&buffer, LOG_N).is_err());
+    assert_eq!(handle.download_buffer(&buffer).unwrap(), evals);
+    handle.deinit().unwrap();
+    assert_eq!(backend.buffer_count(), 0);
+    drop(buffer);
+}
+
+#[test]
+fn mock_device_buffer_falls_back_to_the_host() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1));
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let evals = gen_scalars();
+    let mut coeffs = evals.clone();
+    best_fft_cpu(&mut coeffs, omega().invert().unwrap(), LOG_N);
+    let n_inv = Fr::from(1 << LOG_N).invert().unwrap();
+    coeffs.iter_mut().for_each(|coeff| *coeff *= n_inv);
+
+    let buffer = handle.upload_buffer(&evals).unwrap();
+    assert!(buffer.is_host());
+    handle
+        .execute_intt_buffer::<Fr, Fr>(&buffer, LOG_N)
+        .unwrap();
+    assert_eq!(handle.download_buffer(&buffer).unwrap(), coeffs);
+    let commitment = handle
+        .execute_msm_buffer::<G1Affine>(PARAM_ID, 0, &buffer)
+        .unwrap();
+    let expected: G1 = small_multiexp(&coeffs, &bases);
+    assert_eq!(curve_from_bytes::<G1Affine>(&commitment).unwrap(), expected);
+    handle.execute_ntt_buffer::<Fr, Fr>(&buffer, LOG_N).unwrap();
+    assert_eq!(handle.download_buffer(&buffer).unwrap(), evals);
+
+    assert_eq!(handle.buffer_bytes(DEVICE_BUFFER_HOST_ID), 32 << LOG_N);
+
+    // The ids are not reused after a drop, even by a clone of the context.
+    let other = handle.upload_buffer(&coeffs).unwrap();
+    let other_buffer_id = other.buffer_id;
+    drop(other);
+    let third = handle.clone().upload_buffer(&coeffs).unwrap();
+    assert!(third.buffer_id > other_buffer_id);
+    assert_ne!(third.buffer_id, buffer.buffer_id);
+    drop(third);
+
+    drop(buffer);
+    assert_eq!(handle.buffer_bytes(DEVICE_BUFFER_HOST_ID), 0);
+}
+
+#[test]
+fn mock_device_pointwise_matches_cpu() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1).with_pointwise().with_buffers());
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let (a, b) = (gen_scalars(), gen_scalars());
+    let (c, ratio) = (Fr::random(OsRng), Fr::random(OsRng));
+    let ops = [
+        PointwiseOp::Add,
+        PointwiseOp::Sub,
+        PointwiseOp::Mul,
+        PointwiseOp::Scale(c),
+        PointwiseOp::AddConstant(c),
+        PointwiseOp::SubConstant(c),
+        PointwiseOp::MulPowers { start: c, ratio },
+    ];
+    for op in ops.iter() {
+        let mut expected = a.clone();
+        pointwise_cpu(&mut expected, &b, op);
+        let mut values = a.clone();
+        handle.execute_pointwise(&mut values, &b, op).unwrap();
+        assert_eq!(values, expected, "{:?}", op);
+    }
+    assert_eq!(backend.pointwise_calls(), ops.len());
+
+    // A chain of operations on buffers only copies the result back.
+    let mut expected = a.clone();
+    pointwise_cpu(&mut expected, &b, &PointwiseOp::Mul);
+    pointwise_cpu(&mut expected, &[], &PointwiseOp::SubConstant(c));
+    pointwise_cpu(
+        &mut expected,
+        &[],
+        &PointwiseOp::MulPowers { start: c, ratio },
+    );
+    let buffer = handle.upload_buffer(&a).unwrap();
+    let other = handle.upload_buffer(&b).unwrap();
+    handle
+        .execute_pointwise_buffer(&buffer, Some(&other), &PointwiseOp::Mul)
+        .unwrap();
+    handle
+        .execute_pointwise_buffer(&buffer, None, &PointwiseOp::SubConstant(c))
+        .unwrap();
+    handle
+        .execute_pointwise_buffer(&buffer, None, &PointwiseOp::MulPowers { start: c, ratio })
+        .unwrap();
+    assert_eq!(handle.download_buffer(&buffer).unwrap(), expected);
+    assert_eq!(backend.buffer_reads(), 1);
+}
+
+#[test]
+fn mock_device_pointwise_falls_back_to_the_cpu() {
+    let bases = gen_bases();
+    let backend = Arc::new(MockDeviceBackend::new(1).with_buffers());
+    let mut device_manager = mock_device_manager(&backend, &bases);
+    let handle = device_manager.get_handle_mut();
+
+    let (a, b) = (gen_scalars(), gen_scalars());
+    let mut expected = a.clone();
+    pointwise_cpu(&mut expected, &b, &PointwiseOp::Add);
+    let mut values = a.clone();
+    handle
+        .execute_pointwise(&mut values, &b, &PointwiseOp::Add)
+        .unwrap();
+    assert_eq!(values, expected);
+
+    // The buffer is read back and written again.
+    let buffer = handle.upload_buffer(&a).unwrap();
+    let other = handle.upload_buffer_to(buffer.device_id, &b).unwrap();
+    handle
+        .execute_pointwise_buffer(&buffer, Some(&other), &PointwiseOp::Add)
+        .unwrap();
+    assert_eq!(handle.download_buffer(&buffer).unwrap(), expected);
+    assert_eq!(backend.pointwise_calls(), 0);
+
+    assert!(handle
+        .execute_pointwise_buffer(&buffer, None, &PointwiseOp::Add)
+        .is_err());
+    assert!(handle
+        .execute_pointwise(&mut values, &b[1..], &PointwiseOp::Add)
+        .is_err());
+    assert_eq!(values, expected);
+}
@@ -0,0 +1,411 @@
+//! Differential tests of every available MSM and NTT backend against
+//! `best_multiexp_cpu` and `best_fft_cpu`.
+//!
+//! A new backend gets conformance coverage by implementing `MsmBackend` or
+//! `NttBackend` and adding itself to `msm_backends`/`ntt_backends`.
+
+use group::{
+    ff::{Field, PrimeField},
+    Curve, Group,
+};
+use halo2_proofs::arithmetic::*;
+use halo2_proofs::device_manager::*;
+use halo2curves::bn256::{self, G1Affine};
+use halo2curves::pasta::{EpAffine, EqAffine, Fp, Fq};
+use halo2curves::secp256k1::Secp256k1Affine;
+use proptest::prelude::*;
+use rand_core::RngCore;
+use std::sync::Arc;
+
+const MAX_LOG_N: u32 = 8;
+const MAX_MSM_SIZE: usize = 300;
+
+/// A backend computing MSMs over the curve.
+trait MsmBackend<C: CurveAffine> {
+    fn name(&self) -> &'static str;
+    fn msm(&self, scalars: &[C::Scalar], bases: &[C]) -> C::Curve;
+}
+
+/// A backend computing NTTs over the field.
+trait NttBackend<F: Field> {
+    fn name(&self) -> &'static str;
+    fn ntt(&self, values: &mut [F], omega: F, log_n: u32);
+}
+
+struct SmallMultiexp;
+
+impl<C: CurveAffine> MsmBackend<C> for SmallMultiexp {
+    fn name(&self) -> &'static str {
+        "small_multiexp"
+    }
+
+    fn msm(&self, scalars: &[C::Scalar], bases: &[C]) -> C::Curve {
+        small_multiexp(scalars, bases)
+    }
+}
+
+struct BestMultiexp;
+
+impl<C: CurveAffine> MsmBackend<C> for BestMultiexp {
+    fn name(&self) -> &'static str {
+        "best_multiexp"
+    }
+
+    fn msm(&self, scalars: &[C::Scalar], bases: &[C]) -> C::Curve {
+        best_multiexp(scalars, bases)
+    }
+}
+
+struct BestFft;
+
+impl<F: Field> NttBackend<F> for BestFft {
+    fn name(&self) -> &'static str {
+        "best_fft"
+    }
+
+    fn ntt(&self, values: &mut [F], omega: F, log_n: u32) {
+        best_fft(values, omega, log_n)
+    }
+}
+
+/// A device manager over `backend`, with the bases set up for MSM.
+///
+/// Devices only take the standard root of unity for the NTT, so this backend
+/// must only be checked with it.
+struct DeviceManagerBackend {
+    name: &'static str,
+    backend: Arc<dyn DeviceBackend>,
+}
+
+impl DeviceManagerBackend {
+    fn mock() -> Self {
+        Self {
+            name: "mock_device",
+            backend: Arc::new(MockDeviceBackend::new(2)),
+        }
+    }
+
+    #[cfg(any(feature = "msm_cuda", feature = "fft_cuda"))]
+    fn panda() -> Self {
+        Self {
+            name: "panda",
+            backend: Arc::new(PandaBackend::new()),
+        }
+    }
+}
+
+impl MsmBackend<G1Affine> for DeviceManagerBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn msm(&self, scalars: &[bn256::Fr], bases: &[G1Affine]) -> bn256::G1 {
+        // Devices take no empty MSMs, like `best_multiexp_gpu`.
+        if scalars.is_empty() {
+            return bn256::G1::identity();
+        }
+
+        let mut device_manager = DeviceManager::with_backend(self.backend.clone());
+        let handle = device_manager.get_handle_mut();
+        handle
+            .init(
+                DeviceInitUnitType::DeviceInitUnitTypeMSM,
+                Some(0),
+                Some(&[transmute_values(bases)]),
+                None,
+            )
+            .unwrap();
+        let result = handle.execute_msm::<G1Affine>(0, 0, scalars).unwrap();
+        handle.deinit().unwrap();
+
+        curve_from_bytes::<G1Affine>(&result).unwrap()
+    }
+}
+
+impl NttBackend<bn256::Fr> for DeviceManagerBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn ntt(&self, values: &mut [bn256::Fr], omega: bn256::Fr, log_n: u32) {
+        let mut device_manager = DeviceManager::with_backend(self.backend.clone());
+        let handle = device_manager.get_handle_mut();
+        handle
+            .init(
+                DeviceInitUnitType::DeviceInitUnitTypeNTT,
+                None,
+                None,
+                Some(transmute_values(&[omega])),
+            )
+            .unwrap();
+        handle
+            .execute_ntt::<bn256::Fr, bn256::Fr>(values, log_n)
+            .unwrap();
+        handle.deinit().unwrap();
+    }
+}
+
+fn msm_backends<C: CurveAffine>() -> Vec<Box<dyn MsmBackend<C>>> {
+    vec![Box::new(SmallMultiexp), Box::new(BestMultiexp)]
+}
+
+fn bn256_msm_backends() -> Vec<Box<dyn MsmBackend<G1Affine>>> {
+    let mut backends = msm_backends::<G1Affine>();
+    backends.push(Box::new(DeviceManagerBackend::mock()));
+    #[cfg(any(feature = "msm_cuda"))]
+    backends.push(Box::new(DeviceManagerBackend::panda()));
+    backends
+}
+
+fn ntt_backends<F: Field>() -> Vec<Box<dyn NttBackend<F>>> {
+    vec![Box::new(BestFft)]
+}
+
+fn bn256_ntt_backends() -> Vec<Box<dyn NttBackend<bn256::Fr>>> {
+    let mut backends = ntt_backends::<bn256::Fr>();
+    backends.push(Box::new(DeviceManagerBackend::mock()));
+    #[cfg(any(feature = "fft_cuda"))]
+    backends.push(Box::new(DeviceManagerBackend::panda()));
+    backends
+}
+
+/// A small deterministic rng, so that proptest can replay and shrink failures.
+struct XorShiftRng(u64);
+
+impl RngCore for XorShiftRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+fn rng(seed: u64) -> XorShiftRng {
+    // Xorshift is stuck at zero.
+    XorShiftRng(seed | 1)
+}
+
+/// The shape of the inputs, to hit the edge cases more often than random
+/// values would.
+#[derive(Clone, Copy, Debug)]
+enum Input {
+    Random,
+    Zero,
+    /// `p - 1`, the largest scalar.
+    MinusOne,
+    /// Random with some zero, one and `p - 1` entries.
+    Mixed,
+}
+
+fn input_strategy() -> impl Strategy<Value = Input> {
+    prop_oneof![
+        Just(Input::Random),
+        Just(Input::Zero),
+        Just(Input::MinusOne),
+        Just(Input::Mixed),
+    ]
+}
+
+fn gen_values<F: Field>(rng: &mut XorShiftRng, n: usize, input: Input) -> Vec<F> {
+    (0..n)
+        .map(|_| match input {
+            Input::Random => F::random(&mut *rng),
+            Input::Zero => F::ZERO,
+            Input::MinusOne => -F::ONE,
+            Input::Mixed => match rng.next_u32() % 4 {
+                0 => F::ZERO,
+                1 => F::ONE,
+                2 => -F::ONE,
+                _ => F::random(&mut *rng),
+            },
+        })
+        .collect()
+}
+
+fn gen_bases<C: CurveAffine>(rng: &mut XorShiftRng, n: usize, identity: bool) -> Vec<C> {
+    (0..n)
+        .map(|_| {
+            if identity || rng.next_u32() % 8 == 0 {
+                C::identity()
+            } else {
+                C::Curve::random(&mut *rng).to_affine()
+            }
+        })
+        .collect()
+}
+
+/// The standard `2^log_n`-th root of unity, as used by the evaluation domains.
+fn root_of_unity<F: PrimeField>(log_n: u32) -> F {
+    assert!(log_n <= F::S);
+    let mut omega = F::ROOT_OF_UNITY;
+    for _ in log_n..F::S {
+        omega = omega.square();
+    }
+    omega
+}
+
+/// Check every backend against `best_multiexp_cpu`.
+fn check_msm<C: CurveAffine>(
+    backends: &[Box<dyn MsmBackend<C>>],
+    seed: u64,
+    n: usize,
+    input: Input,
+    identity_bases: bool,
+) -> Result<(), TestCaseError> {
+    let mut rng = rng(seed);
+    let scalars = gen_values::<C::Scalar>(&mut rng, n, input);
+    let bases = gen_bases::<C>(&mut rng, n, identity_bases);
+    let expected = best_multiexp_cpu(&scalars, &bases);
+
+    for backend in backends {
+        prop_assert_eq!(
+            backend.msm(&scalars, &bases),
+            expected,
+            "{} msm of {} {:?} scalars",
+            backend.name(),
+            n,
+            input
+        );
+    }
+    Ok(())
+}
+
+/// Check every backend against `best_fft_cpu` with the standard root of unity.
+fn check_ntt<F: PrimeField>(
+    backends: &[Box<dyn NttBackend<F>>],
+    seed: u64,
+    log_n: u32,
+    input: Input,
+) -> Result<(), TestCaseError> {
+    let mut rng = rng(seed);
+    let values = gen_values::<F>(&mut rng, 1 << log_n, input);
+    let omega = root_of_unity::<F>(log_n);
+    let mut expected = values.clone();
+    best_fft_cpu(&mut expected, omega, log_n);
+
+    for backend in backends {
+        let mut result = values.clone();
+        backend.ntt(&mut result, omega, log_n);
+        prop_assert_eq!(
+            result,
+            expected.clone(),
+            "{} ntt of 2^{} {:?} values",
+            backend.name(),
+            log_n,
+            input
+        );
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn differential_msm_bn256(
+        seed in any::<u64>(),
+        n in 0..MAX_MSM_SIZE,
+        input in input_strategy(),
+        identity_bases in any::<bool>(),
+    ) {
+        check_msm(&bn256_msm_backends(), seed, n, input, identity_bases)?;
+    }
+
+    #[test]
+    fn differential_msm_pallas(
+        seed in any::<u64>(),
+        n in 0..MAX_MSM_SIZE,
+        input in input_strategy(),
+        identity_bases in any::<bool>(),
+    ) {
+        check_msm(&msm_backends::<EpAffine>(), seed, n, input, identity_bases)?;
+    }
+
+    #[test]
+    fn differential_msm_vesta(
+        seed in any::<u64>(),
+        n in 0..MAX_MSM_SIZE,
+        input in input_strategy(),
+        identity_bases in any::<bool>(),
+    ) {
+        check_msm(&msm_backends::<EqAffine>(), seed, n, input, identity_bases)?;
+    }
+
+    #[test]
+    fn differential_msm_secp256k1(
+        seed in any::<u64>(),
+        n in 0..MAX_MSM_SIZE,
+        input in input_strategy(),
+        identity_bases in any::<bool>(),
+    ) {
+        check_msm(&msm_backends::<Secp256k1Affine>(), seed, n, input, identity_bases)?;
+    }
+
+    #[test]
+    fn differential_ntt_bn256(
+        seed in any::<u64>(),
+        log_n in 0..=MAX_LOG_N,
+        input in input_strategy(),
+    ) {
+        check_ntt(&bn256_ntt_backends(), seed, log_n, input)?;
+    }
+
+    #[test]
+    fn differential_ntt_pasta_fp(
+        seed in any::<u64>(),
+        log_n in 0..=MAX_LOG_N,
+        input in input_strategy(),
+    ) {
+        check_ntt(&ntt_backends::<Fp>(), seed, log_n, input)?;
+    }
+
+    #[test]
+    fn differential_ntt_pasta_fq(
+        seed in any::<u64>(),
+        log_n in 0..=MAX_LOG_N,
+        input in input_strategy(),
+    ) {
+        check_ntt(&ntt_backends::<Fq>(), seed, log_n, input)?;
+    }
+}
+
+/// The edge cases are also checked exhaustively, not only when proptest
+/// happens to draw them.
+#[test]
+fn differential_msm_edge_cases() {
+    for n in [0, 1, 2, 3, 64] {
+        for input in [Input::Zero, Input::MinusOne, Input::Mixed] {
+            for identity_bases in [false, true] {
+                check_msm(&bn256_msm_backends(), 7, n, input, identity_bases).unwrap();
+                check_msm(&msm_backends::<EpAffine>(), 7, n, input, identity_bases).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn differential_ntt_edge_cases() {
+    for log_n in [0, 1, 2] {
+        for input in [Input::Zero, Input::MinusOne, Input::Mixed] {
+            check_ntt(&bn256_ntt_backends(), 7, log_n, input).unwrap();
+            check_ntt(&ntt_backends::<Fp>(), 7, log_n, input).unwrap();
+        }
+    }
+}